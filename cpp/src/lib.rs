@@ -103,6 +103,9 @@
 //! module into the parsing process. Please note that the latter is only supported
 //! in its simplest form: straight-forward `feature = "..."` without any
 //! additional conditions, `cfg!` macros are also not supported at the moment.
+//! When a module (inline or in its own file) is excluded this way, all the `cpp!`
+//! macros it contains are skipped: neither their C++ code nor their `rust!` callbacks
+//! are compiled.
 //!
 //! Since the C++ code is included within a rust file, the C++ code must obey both
 //! the Rust and the C++ lexing rules. For example, Rust supports nested block comments
@@ -114,6 +117,8 @@
 
 #![no_std]
 
+extern crate alloc;
+
 #[macro_use]
 #[allow(unused_imports)]
 extern crate cpp_macros;
@@ -146,18 +151,45 @@ macro_rules! __cpp_internal {
         #[allow(clippy::forget_copy, clippy::forget_ref)]
         #[doc(hidden)]
         $($a)* unsafe extern "C" fn $i($($an : *const $at),*) {
+            #[allow(unused)]
+            #[derive($crate::__cpp_internal_rust_callback)]
+            enum RustCallbackInput {
+                Input = (stringify!(rust!($i [$($an : $at as $ac),*] {})), 0).1
+            }
             $(let $an : $at = unsafe { $an.read() };)*
             (|| { $($body)* })();
             $(::core::mem::forget($an);)*
 
         }
     };
+    // A reference is returned as a pointer, see RustInvocation::return_reference. The
+    // `&&` token is split to be matched as two references.
+    (@expand_rust_macro [$($a:tt)*] $i:ident [$($args:tt)*] -> && $($rest:tt)*) => {
+        $crate::__cpp_internal!{ @expand_rust_macro [$($a)*] $i [$($args)*] -> & & $($rest)* }
+    };
+    (@expand_rust_macro [$($a:tt)*] $i:ident [$($args:tt)*] -> & $lt:lifetime mut $rt:ty as $rc:tt {$($body:tt)*}) => {
+        $crate::__cpp_internal!{ @expand_rust_reference [$($a)*] $i [$($args)*] [& $lt mut $rt] *mut $rt as $rc {$($body)*} }
+    };
+    (@expand_rust_macro [$($a:tt)*] $i:ident [$($args:tt)*] -> & $lt:lifetime $rt:ty as $rc:tt {$($body:tt)*}) => {
+        $crate::__cpp_internal!{ @expand_rust_reference [$($a)*] $i [$($args)*] [& $lt $rt] *const $rt as $rc {$($body)*} }
+    };
+    (@expand_rust_macro [$($a:tt)*] $i:ident [$($args:tt)*] -> &mut $rt:ty as $rc:tt {$($body:tt)*}) => {
+        $crate::__cpp_internal!{ @expand_rust_reference [$($a)*] $i [$($args)*] [&mut $rt] *mut $rt as $rc {$($body)*} }
+    };
+    (@expand_rust_macro [$($a:tt)*] $i:ident [$($args:tt)*] -> & $rt:ty as $rc:tt {$($body:tt)*}) => {
+        $crate::__cpp_internal!{ @expand_rust_reference [$($a)*] $i [$($args)*] [& $rt] *const $rt as $rc {$($body)*} }
+    };
     (@expand_rust_macro [$($a:tt)*] $i:ident [$($an:ident : $at:ty as $ac:tt),*] -> $rt:ty as $rc:tt {$($body:tt)*}) => {
         #[allow(non_snake_case)]
         #[allow(unused_unsafe)]
         #[allow(clippy::forget_copy, clippy::forget_ref)]
         #[doc(hidden)]
         $($a)* unsafe extern "C" fn $i($($an : *const $at, )* rt : *mut $rt) -> *mut $rt {
+            #[allow(unused)]
+            #[derive($crate::__cpp_internal_rust_callback)]
+            enum RustCallbackInput {
+                Input = (stringify!(rust!($i [$($an : $at as $ac),*] -> $rt as $rc {})), 0).1
+            }
 
             $(let $an : $at = unsafe { $an.read() };)*
             {
@@ -170,6 +202,30 @@ macro_rules! __cpp_internal {
         }
     };
 
+    // The body is a block rather than a closure, which could not return a reborrow of a
+    // `&mut` argument
+    (@expand_rust_reference [$($a:tt)*] $i:ident [$($an:ident : $at:ty as $ac:tt),*] [$($rt:tt)*] $ptr:ty as $rc:tt {$($body:tt)*}) => {
+        #[allow(non_snake_case)]
+        #[allow(unused_unsafe)]
+        #[allow(clippy::forget_copy, clippy::forget_ref)]
+        #[doc(hidden)]
+        $($a)* unsafe extern "C" fn $i($($an : *const $at),*) -> $ptr {
+            #[allow(unused)]
+            #[derive($crate::__cpp_internal_rust_callback)]
+            enum RustCallbackInput {
+                Input = (stringify!(rust!($i [$($an : $at as $ac),*] -> $($rt)* as $rc {})), 0).1
+            }
+
+            $(let $an : $at = unsafe { $an.read() };)*
+            let result: $ptr = {
+                let r: $($rt)* = { $($body)* };
+                r
+            };
+            $(::core::mem::forget($an);)*
+            result
+        }
+    };
+
     (@expand_rust_macro $($invalid:tt)*) => {
         compile_error!(concat!( "Cannot parse rust! macro: ", stringify!([ $($invalid)* ]) ))
     };
@@ -191,6 +247,8 @@ macro_rules! __cpp_internal {
 /// The second variant is used to embed C++ code within Rust code. A list of
 /// variable names which should be captured are taken as the first argument,
 /// with their corresponding C++ type. The body is compiled as a C++ function.
+/// The build script warns about the captures which are not used in the body,
+/// unless their name starts with `_`.
 ///
 /// This variant of the macro may only be invoked in expression context, and
 /// requires an `unsafe` block, as it is performing FFI.
@@ -214,6 +272,372 @@ macro_rules! __cpp_internal {
 /// });
 /// ```
 ///
+/// ## Capturing arrays
+///
+/// Captured variables are passed to C++ by reference, so the C++ type may also be
+/// written with a trailing `&`. An array, including an array of `cpp_class!` types,
+/// can be captured as a `std::array`. To capture a slice, convert it to a
+/// [`Slice`], which is captured as a `rustcpp::Slice<T>` (or
+/// `rustcpp::Slice<const T>` if the C++ code does not modify it). It carries the
+/// length of the slice, and can be iterated over:
+///
+/// ```ignore
+/// let array: [A; 3] = [A::new(1, 2), A::new(3, 4), A::new(5, 6)];
+/// let slice = cpp::Slice::from(&array[1..]);
+/// let sum = unsafe {
+///     cpp!([array as "std::array<A, 3>&", slice as "rustcpp::Slice<const A>"] -> i32 as "int" {
+///         int sum = array[0].multiply();
+///         for (const A &a : slice)
+///             sum += a.multiply();
+///         return sum + slice.at(1).multiply();
+///     })
+/// };
+/// ```
+///
+/// The `at(i)` accessor of `rustcpp::Slice` asserts that the index is smaller than
+/// the length of the Rust slice, unless `NDEBUG` is defined, while `operator[]`
+/// is not checked.
+///
+/// With C++20, a [`Slice`] can also be captured as a `std::span<T>` (or
+/// `std::span<const T>`). The layout of `std::span` is not specified, so the span
+/// is constructed by the generated function from the pointer and the length.
+///
+/// The elements are still owned by Rust: the C++ code must not destroy them or move
+/// from them.
+///
+/// A capture is the name of a variable, which is also the name of the C++
+/// parameter. To pass a pointer to a field or to an element of an array, bind a
+/// reference to it to a variable first: the borrow lasts for the whole call.
+///
+/// ```ignore
+/// let element = &mut data.matrix[2];
+/// unsafe { cpp!([element as "float*"] { *element *= 10; }) };
+/// ```
+///
+/// A `static` can be captured by its name too. As it is passed by reference, the
+/// C++ code gets the address of the static itself, without a copy, even for a large
+/// array. A `&'static` reference to it can also be captured as a pointer:
+///
+/// ```ignore
+/// static TABLE: [u8; 4096] = [0; 4096];
+/// let first = unsafe {
+///     cpp!([TABLE as "std::array<uint8_t, 4096>"] -> *const u8 as "const uint8_t*" {
+///         return TABLE.data();
+///     })
+/// };
+/// assert_eq!(first, TABLE.as_ptr());
+/// ```
+///
+/// ## Output buffers
+///
+/// A `&mut [u8]` captured as a `rustcpp::MutBuf` is passed as its pointer, `data`,
+/// and its length, `len`. Its `copy_from(src, n)` method copies at most `len` bytes
+/// and returns the number of bytes copied, so the C++ code cannot write past the end
+/// of the buffer:
+///
+/// ```ignore
+/// let mut buf = [0u8; 16];
+/// let buf_len = &mut buf[..];
+/// let written = unsafe {
+///     cpp!([buf_len as "rustcpp::MutBuf"] -> usize as "size_t" {
+///         const char message[] = "hello";
+///         return buf_len.copy_from(message, sizeof(message) - 1);
+///     })
+/// };
+/// assert_eq!(&buf[..written], b"hello");
+/// ```
+///
+/// A `mut` array of bytes or `Vec<u8>` can also be captured as a `rustcpp::MutBuf`.
+///
+/// ## Appending to vectors
+///
+/// To let the C++ code add elements to a `Vec<T>`, convert a mutable reference to
+/// it to a [`VecMut`], and capture it as a mutable `rustcpp::Vec<T>`. Its
+/// `push_back` and `reserve` methods call back into Rust to grow the vector:
+///
+/// ```ignore
+/// let mut values: Vec<i32> = Vec::new();
+/// {
+///     let mut vec = cpp::VecMut::from(&mut values);
+///     unsafe {
+///         cpp!([mut vec as "rustcpp::Vec<int32_t>"] {
+///             vec.reserve(10);
+///             for (int32_t i = 0; i < 10; ++i)
+///                 vec.push_back(i * i);
+///         })
+///     };
+/// }
+/// assert_eq!(values[3], 9);
+/// ```
+///
+/// Like with a `std::vector`, growing the vector may reallocate its elements, which
+/// invalidates the pointers and references to them. The elements are moved by Rust,
+/// which copies their bytes: the C++ type of the elements must allow that, like the
+/// primitive types and the `#[repr(C)]` structs. The length of the `Vec` is updated
+/// when the `VecMut` is dropped, so the new elements are only visible afterwards.
+///
+/// ## Initializing Rust memory
+///
+/// To let the C++ code initialize memory owned by Rust, capture a
+/// `&mut MaybeUninit<T>` as a pointer `T*`. The size of the pointer is checked, and
+/// the memory may be uninitialized since it is not read by Rust before the call. A
+/// `MaybeUninit<T>` has the same layout as `T`, so it can also be captured as a
+/// mutable `T`.
+///
+/// ```ignore
+/// let mut buffer = MaybeUninit::<[u8; 16]>::uninit();
+/// let out = &mut buffer;
+/// unsafe { cpp!([out as "uint8_t*"] { std::memset(out, 1, 16); }) };
+/// let buffer = unsafe { buffer.assume_init() };
+/// ```
+///
+/// ## Tuples
+///
+/// The layout of Rust tuples is unspecified, so capturing a tuple such as
+/// `(i32, f64)` fails to compile (with an error about type annotations for
+/// `__NotTuple`), unless the type of the capture is a generic parameter. Use a `#[repr(C)]` struct or tuple struct instead, with
+/// a named C++ struct declared with the same fields in the same order:
+///
+/// ```ignore
+/// #[repr(C)]
+/// struct Pair(i32, f64);
+///
+/// cpp!{{ struct Pair { int32_t a; double b; }; }}
+///
+/// let p = Pair(3, 0.5);
+/// let r = unsafe { cpp!([p as "Pair"] -> f64 as "double" { return p.a * p.b; }) };
+/// ```
+///
+/// ## Generic functions
+///
+/// The C++ code is generated by the build script, before the generic functions are
+/// monomorphized, so a capture cannot have the type of a generic parameter (`T`,
+/// `impl Trait`, or `self` in a generic `impl`): this is reported as an error at the
+/// capture. Convert the value to a concrete type before capturing it, or capture a
+/// reference or a pointer to it, whose size doesn't depend on `T`:
+///
+/// ```ignore
+/// fn print<T: Into<i32>>(x: T) {
+///     let x: i32 = x.into();
+///     unsafe { cpp!([x as "int32_t"] { std::cout << x << std::endl; }) }
+/// }
+/// ```
+///
+/// ## Returning references
+///
+/// When a closure captures `self` and returns a reference with an elided
+/// lifetime, the lifetime of the reference is tied to a borrow of `*self`, as
+/// for the return value of a method. So an accessor can't return a reference
+/// which outlives the object:
+///
+/// ```ignore
+/// impl B {
+///     fn a(&mut self) -> &mut i32 {
+///         unsafe { cpp!([self as "B*"] -> &mut i32 as "int*" { return &self->a; }) }
+///     }
+/// }
+/// ```
+///
+/// In other cases, the lifetime of the returned reference is unbounded, and
+/// it is up to the caller to make sure that it does not outlive the data.
+///
+/// A reference `&T` or `&mut T` is returned as a C++ pointer `T*`, which must
+/// not be null, and must point to a valid object for the whole lifetime of the
+/// reference. In debug builds, a null pointer panics, as a null reference is
+/// undefined behavior. Use a raw pointer as the return type when the C++ code
+/// may return null.
+///
+/// ## Returning enums
+///
+/// A Rust enum returned by transmuting the value written by the C++ code is
+/// undefined behavior if that value is not one of its discriminants. An enum with
+/// `#[derive(cpp::CppEnum)]` and a `#[repr(C)]` or `#[repr(<integer>)]` attribute is
+/// checked instead: the returned integer is converted to the variant with that
+/// discriminant. An unknown value gives the variant marked `#[cpp_enum(fallback)]`,
+/// or panics if there is none. The enums are recognized by the name of the type
+/// in the return type of the closure.
+///
+/// ```ignore
+/// #[derive(cpp::CppEnum)]
+/// #[repr(u8)]
+/// enum Color {
+///     Red = 1,
+///     Green = 2,
+///     #[cpp_enum(fallback)]
+///     Unknown = 255,
+/// }
+///
+/// let color = unsafe { cpp!([] -> Color as "uint8_t" { return 2; }) };
+/// ```
+///
+/// A Rust enum can be captured as a C++ enum, such as an `enum class Color : uint8_t`,
+/// if its `#[repr]` has the size of the underlying type of the C++ enum. Otherwise,
+/// the capture fails to compile with an error explaining the mismatch.
+///
+/// ## Wide strings
+///
+/// The size of `wchar_t` depends on the target: it has 16 bits on Windows, where
+/// wide strings are UTF-16, and usually 32 bits elsewhere (UTF-32). A pointer or a
+/// [`Slice`] captured as a pointer to `wchar_t` or a `rustcpp::Slice<wchar_t>` must
+/// have elements of that size: `u16` on Windows, `u32` or `char` elsewhere.
+/// Otherwise, the call panics with an explanation instead of reading past the
+/// end of the string. Elements other than `u16`, `i16`, `u32`, `i32` and `char`
+/// fail to compile.
+///
+/// ```ignore
+/// cpp!{{ #include <cwchar> }}
+///
+/// let name: Vec<u16> = "name\0".encode_utf16().collect();
+/// let p = name.as_ptr();
+/// let len = unsafe { cpp!([p as "const wchar_t*"] -> usize as "size_t" { return wcslen(p); }) };
+/// ```
+///
+/// ## Pointer-width integers
+///
+/// A `usize` should be captured or returned as `size_t` or `uintptr_t`, and an
+/// `isize` as `ptrdiff_t` or `intptr_t`. The build script checks that these C++
+/// types have the width of a pointer on the target. Capturing or returning a
+/// `usize` or an `isize` as a C++ type of a different size (such as `int`) is a compile
+/// error.
+///
+/// ## Characters
+///
+/// A `char` should be captured or returned as `char32_t`. Since not all the
+/// values of a `char32_t` are valid `char`s, when the C++ code of a closure
+/// writes to a mutable `char` capture, the value is checked after the call: an
+/// invalid value (such as a surrogate) is replaced by
+/// `char::REPLACEMENT_CHARACTER`, and the closure panics. A returned `char32_t`
+/// is not checked, so it must be a valid Unicode scalar value. A mutable
+/// `char32_t` capture must be a `char`, a `u32` or an `i32`.
+///
+/// ## Half-precision floats
+///
+/// An `f16` (which requires a Rust compiler where it is available) should be
+/// captured or returned as `_Float16`, `__fp16` or `std::float16_t`, which is also
+/// checked to be 16 bits wide. These C++ types are not available everywhere:
+/// `_Float16` is supported by recent GCC and Clang on x86 and ARM, `__fp16` is
+/// an ARM extension of GCC and Clang, and `std::float16_t` requires C++23 and
+/// the `<stdfloat>` header. MSVC supports none of them.
+///
+/// ## Optional values and errors
+///
+/// A closure can return a `std::optional<T>` as an `Option<T>`, and a
+/// `std::expected<T, E>` as a `Result<T, E>`. Only the contained value (or
+/// error) is moved to Rust, so it is its size which must match the Rust type.
+/// The C++ code must be compiled with a standard that provides these types
+/// (C++17 for `std::optional`, C++23 for `std::expected`) and include the
+/// corresponding header.
+///
+/// ```ignore
+/// let half = unsafe {
+///     cpp!([x as "int"] -> Option<i32> as "std::optional<int>" {
+///         if (x % 2 != 0)
+///             return std::nullopt;
+///         return x / 2;
+///     })
+/// };
+/// ```
+///
+/// ## Returning string views
+///
+/// A closure can return characters owned by the C++ code without copying them, as
+/// a `rustcpp::StrView`. It is constructed from a pointer and a length, or from any
+/// string with `data()` and `size()`, such as a `std::string` or a
+/// `std::string_view`. It is returned to Rust as a `&[u8]`, or as a [`StrView`].
+///
+/// ```ignore
+/// let name = unsafe {
+///     cpp!([person as "const Person*"] -> &[u8] as "rustcpp::StrView" {
+///         return person->name;
+///     })
+/// };
+/// ```
+///
+/// The returned slice borrows the C++ string, but its lifetime is not bounded (or
+/// tied to `self`, see [Returning references](#returning-references)): the string
+/// must not be modified or destroyed while the slice is used. In particular, a view
+/// of a temporary string, such as a `std::string` returned by value, is dangling.
+///
+/// ## Durations
+///
+/// A `std::time::Duration` is converted to a [`Duration`], which is captured as a
+/// `rustcpp::Duration`. It holds the seconds as an `int64_t` and the nanoseconds
+/// as an `uint32_t`, as the layout of the `std::chrono` types is not specified. It
+/// converts implicitly from and to a `std::chrono::duration` with an integer count,
+/// truncated to its period, and saturated to `INT64_MAX` when it overflows (after
+/// about 106 days in picoseconds). A closure returning a `rustcpp::Duration` can be given
+/// the Rust type `std::time::Duration` (it panics if the C++ duration is negative),
+/// or `cpp::Duration`.
+///
+/// ```ignore
+/// let timeout = cpp::Duration::from(Duration::from_millis(1500));
+/// let elapsed = unsafe {
+///     cpp!([timeout as "rustcpp::Duration"] -> Duration as "rustcpp::Duration" {
+///         std::chrono::milliseconds ms = timeout;
+///         return wait_for(ms);
+///     })
+/// };
+/// ```
+///
+/// ## bfloat16 numbers
+///
+/// A [`Bf16`] is captured or returned as a `rustcpp::Bf16`, which holds the 16
+/// high bits of a `float` in an `uint16_t`, and converts implicitly from and to
+/// `float` (rounding to the nearest number, ties to even). So it also converts to
+/// the `__bf16` type of the compilers which have one, such as GCC 13 and Clang 17
+/// on x86-64 and AArch64, which define `__BFLT16_MANT_DIG__`.
+///
+/// ```ignore
+/// let weights = [cpp::Bf16::from(0.5), cpp::Bf16::from(1.5)];
+/// let sum = unsafe {
+///     cpp!([weights as "std::array<rustcpp::Bf16, 2>"] -> cpp::Bf16 as "rustcpp::Bf16" {
+///         return weights[0] + weights[1];
+///     })
+/// };
+/// assert_eq!(f32::from(sum), 2.0);
+/// ```
+///
+/// A crate with its own bfloat16 type, such as a `#[repr(transparent)]` newtype
+/// over `u16` holding the same representation, can capture it as a
+/// `rustcpp::Bf16` too, or as an `__bf16` where the compiler supports it, as only
+/// the size and alignment of the types are checked.
+///
+/// ## Headers of a closure
+///
+/// A header which is only needed by one closure can be included with the
+/// `cpp_include` attribute, instead of in a `cpp!{{ }}` snippet. The
+/// `#include` directive is written just before the code of the closure, after
+/// all the snippets.
+///
+/// ```ignore
+/// let r = unsafe {
+///     cpp!(#[cpp_include("<cmath>")] [x as "double"] -> f64 as "double" {
+///         return std::sqrt(x);
+///     })
+/// };
+/// ```
+///
+/// The generated C++ code is a single translation unit, so the header is still
+/// visible to the code of the closures which follow.
+///
+/// ## Exceptions
+///
+/// A C++ exception must not unwind into the Rust code. By default, the build
+/// script catches the exceptions which escape the body of a closure and calls
+/// `std::terminate`, which aborts the program (see
+/// `cpp_build::Config::catch_exceptions`).
+///
+/// ## noexcept closures
+///
+/// A closure which never throws can be marked `noexcept`, after the return type
+/// if there is one. The generated C++ function is then declared `noexcept`,
+/// which lets the compiler optimize it. If the body throws anyway, the C++
+/// runtime calls `std::terminate`, so the exception never reaches Rust.
+///
+/// ```ignore
+/// let r = unsafe { cpp!([x as "int"] -> i32 as "int" noexcept { return x * 2; }) };
+/// ```
+///
 /// ## rust! pseudo-macro
 ///
 /// The `cpp!` macro can contain, in the C++ code, a `rust!` sub-macro, which allows
@@ -246,21 +670,232 @@ macro_rules! __cpp_internal {
 ///      $(-> $ret_rust_type:ty as $rust_c_type:tt)* {$($body:tt)*})
 /// ```
 /// `uniq_ident` is a unique identifier which will be used to name the `extern` function
+///
+/// As for the captures of `cpp!`, the size and alignment of each argument and return
+/// type are compared between C++ and Rust, and a mismatch such as `x : i64 as "int"`
+/// is a compilation error. A C++ reference argument is checked against a pointer.
+///
+/// The arguments are owned by the C++ code: the callback gets a bitwise copy of
+/// each of them, which is not dropped, so moving an argument out of the body is
+/// a compilation error.
+///
+/// ### Returning references
+///
+/// A callback can return a reference into one of its arguments, such as an element
+/// of a container. It is returned as a pointer (and not written to a temporary), so
+/// its C++ type must be a pointer:
+///
+/// ```ignore
+/// cpp!{{
+///     const Item *find(const Items &items, int id) {
+///         return rust!(FindItem [items : &Items as "const Items&", id : i32 as "int"]
+///             -> &Item as "const Item*" {
+///             items.find(id)
+///         });
+///     }
+/// }}
+/// ```
+///
+/// The reference must point to data which outlives the call, such as data owned by
+/// the C++ code: the C++ code must not use the pointer after the referenced object
+/// is modified or destroyed. The body of such a callback is evaluated as a block, so
+/// it can't contain a `return` statement.
+///
+/// ### Out-parameters
+///
+/// A callback returns a single value, but it can produce more through C++
+/// references, taken as `&mut T` arguments:
+///
+/// ```ignore
+/// cpp!{{
+///     bool parse(const char *text, int &value, int &consumed) {
+///         return rust!(ParseInt [text : *const c_char as "const char*",
+///                                value : &mut i32 as "int&",
+///                                consumed : &mut i32 as "int&"] -> bool as "bool" {
+///             parse_int(unsafe { CStr::from_ptr(text) }, value, consumed)
+///         });
+///     }
+/// }}
+/// ```
+///
+/// The references are valid for the duration of the call only, so they must not
+/// be stored. As for any `&mut`, the C++ code must not access the referenced
+/// objects during the call, and two out-parameters must not refer to the same
+/// object. The referenced objects must be initialized, as the Rust code may
+/// read them, and an assignment such as `*value = x` drops the previous value.
+///
+/// ### Several return values
+///
+/// A closure can return several values through mutable captures, declared and
+/// returned as a tuple by the [`cpp_out!`] macro.
+///
+/// ## Sharing an Arc with C++
+///
+/// A capture prefixed with `arc` must be an `Arc<T>` (or a reference to one). It
+/// is cloned, and the C++ code receives the pointer returned by `Arc::into_raw`,
+/// as a `const void*` or a pointer to the C++ type corresponding to `T`. The C++
+/// code then owns that reference to the object, and can keep it as long as it
+/// needs. It is released by giving the pointer back to a `rust!` callback which
+/// calls `Arc::from_raw`:
+///
+/// ```ignore
+/// cpp!{{
+///     const void *shared_config = nullptr;
+///     void release_config() {
+///         rust!(ReleaseConfig [shared_config : *const Config as "const void*"] {
+///             drop(unsafe { std::sync::Arc::from_raw(shared_config) });
+///         });
+///     }
+/// }}
+///
+/// let config = std::sync::Arc::new(Config::default());
+/// unsafe { cpp!([arc config as "const void*"] { shared_config = config; }) };
+/// ```
+///
+/// ## Moving a Box to C++
+///
+/// A capture prefixed with `move` must be an `Option<Box<T>>`, which is consumed.
+/// The C++ code receives a pointer to the C++ type corresponding to `T`: the
+/// pointer returned by `Box::into_raw`, or a null pointer for `None`. The C++ code
+/// then owns the box, but as it was allocated by Rust, it must not `delete` it.
+/// The ownership is given back to Rust by returning the pointer from a closure,
+/// or passing it to a `rust!` callback, as an `Option<Box<T>>`, which has the
+/// same representation as a pointer:
+///
+/// ```ignore
+/// cpp!{{ Node *pending = nullptr; }}
+///
+/// let node = Some(Box::new(Node::default()));
+/// unsafe { cpp!([move node as "Node*"] { pending = node; }) };
+/// let node = unsafe {
+///     cpp!([] -> Option<Box<Node>> as "Node*" { return std::exchange(pending, nullptr); })
+/// };
+/// ```
+///
+/// ## Capturing Rust closures
+///
+/// A `&mut dyn FnMut` can be captured as a `rustcpp::FnMut` with the corresponding
+/// C++ function type, which the C++ code can call, or store in a `std::function`.
+/// The closure can only be called during the call to the `cpp!` closure, and it
+/// must not be called recursively. It takes up to four arguments, which are
+/// passed by value with the C calling convention, so the argument and return
+/// types must be primitive types, pointers, or `#[repr(C)]` structs. A panic in
+/// the closure aborts the process.
+///
+/// ```ignore
+/// let mut total = 0;
+/// let f: &mut dyn FnMut(i32, i32) -> i32 = &mut |a, b| {
+///     total += a * b;
+///     total
+/// };
+/// unsafe {
+///     cpp!([f as "rustcpp::FnMut<int32_t(int32_t, int32_t)>"] {
+///         std::function<int32_t(int32_t, int32_t)> callback = f;
+///         for (int i = 0; i < 3; ++i)
+///             callback(i, 2);
+///     })
+/// };
+/// assert_eq!(total, 6);
+/// ```
+///
+/// ## Passing pointers by value
+///
+/// A capture such as `[ptr as "void*"]`, where `ptr` is a `*mut T`, already gives
+/// the value of the pointer to the C++ code: like any capture, the parameter is a
+/// reference to the Rust variable. Its address is what is passed to the generated
+/// function, and `&ptr` in C++ is the address of the Rust variable.
+///
+/// A capture prefixed with `copy` is passed by value instead: the C++ parameter is
+/// a copy of the pointer, which the C++ code may modify, for instance to iterate,
+/// without changing the Rust variable. The C++ type must be a pointer, and the
+/// Rust type must have the same size, like a raw pointer, a reference or an
+/// `Option<&T>`:
+///
+/// ```ignore
+/// let data: *const u8 = bytes.as_ptr();
+/// let zeros = unsafe {
+///     cpp!([copy data as "const uint8_t*", len as "size_t"] -> usize as "size_t" {
+///         size_t zeros = 0;
+///         for (const uint8_t *end = data + len; data != end; ++data)
+///             zeros += *data == 0;
+///         return zeros;
+///     })
+/// };
+/// ```
+///
+/// The pointee of the C++ type does not have to match the Rust type: a type-erased
+/// `*mut ()` or `*const c_void` can be captured as a pointer to the actual C++
+/// type, such as `[copy handle as "Widget*"]`, without a cast in the body.
+///
+/// ## rust_const! pseudo-macro
+///
+/// Captures are runtime values, so they can't be used where C++ expects a
+/// constant expression, such as the size of an array. Instead, `rust_const!(NAME)`
+/// is replaced by the value of the Rust constant `NAME` when the C++ code is
+/// generated by the build script:
+///
+/// ```ignore
+/// const BUFFER_SIZE: usize = 4 * 1024;
+///
+/// let len = unsafe {
+///     cpp!([] -> usize as "size_t" {
+///         char buffer[rust_const!(BUFFER_SIZE)];
+///         return sizeof(buffer);
+///     })
+/// };
+/// ```
+///
+/// Since the build script only parses the source code, this is limited to the
+/// `const` items of the crate whose value is an integer expression made of
+/// literals, other such constants, casts, and arithmetic operators. The constant
+/// is looked up by name only: it can be defined in any module of the crate, but
+/// its name must be unique. Associated constants, `const fn` calls, and constants
+/// from other crates are not supported.
+///
+/// A constant can also be captured with `const`, which declares a `constexpr`
+/// variable of the given C++ type with its value at the beginning of the body,
+/// rather than passing it at runtime. The same limitations apply: only the
+/// integer constants of the crate are supported, and not const generic
+/// parameters.
+///
+/// ```ignore
+/// let len = unsafe {
+///     cpp!([const BUFFER_SIZE as "size_t"] -> usize as "size_t" {
+///         char buffer[BUFFER_SIZE];
+///         return sizeof(buffer);
+///     })
+/// };
+/// ```
+///
+/// ## Imported symbols
+///
+/// When a function called from the C++ code is defined in another library, the
+/// linker may drop the object which defines it before it sees the reference.
+/// Marking its declaration with `rustcpp_import` in a `cpp!{{ }}` snippet makes
+/// the build script pass an argument to the final link which forces the symbol
+/// to be resolved (`-u` or `/INCLUDE`). The function must be `extern "C"`, since
+/// its name is used as the symbol name.
+///
+/// ```ignore
+/// cpp!{{
+///     extern "C" rustcpp_import void vendor_init();
+/// }}
+/// ```
 #[macro_export]
 macro_rules! cpp {
     // raw text inclusion
     ({$($body:tt)*}) => { $crate::__cpp_internal!{ @find_rust_macro [#[no_mangle] pub] $($body)*} };
 
     // inline closure
-    ([$($captures:tt)*] $($rest:tt)*) => {
+    ($(#[$($attrs:tt)*])* [$($captures:tt)*] $($rest:tt)*) => {
         {
             $crate::__cpp_internal!{ @find_rust_macro [] $($rest)*}
             #[allow(unused)]
             #[derive($crate::__cpp_internal_closure)]
             enum CppClosureInput {
-                Input = (stringify!([$($captures)*] $($rest)*), 0).1
+                Input = (stringify!($(#[$($attrs)*])* [$($captures)*] $($rest)*), 0).1
             }
-            __cpp_closure_impl![$($captures)*]
+            __cpp_closure_impl![$crate; $($captures)*]
         }
     };
 
@@ -268,6 +903,456 @@ macro_rules! cpp {
     (unsafe $($tail:tt)*) => { unsafe { cpp!($($tail)*) } };
 }
 
+/// Declares output variables, initialized with their `Default` value, calls a
+/// `cpp!` closure which writes to them, and returns their values as a tuple (or
+/// the value itself if there is a single output).
+///
+/// The build script reads the `cpp!` macros in the source, so the closure is
+/// written as a regular `cpp!` closure which captures the outputs as mutable
+/// variables, and returns nothing.
+///
+/// ```ignore
+/// let (min, max) = unsafe {
+///     cpp_out!(min: i32, max: i32 => cpp!([values as "rustcpp::Slice<const int32_t>",
+///                                          mut min as "int32_t", mut max as "int32_t"] {
+///         min = max = values[0];
+///         for (int32_t v : values) {
+///             min = std::min(min, v);
+///             max = std::max(max, v);
+///         }
+///     }))
+/// };
+/// ```
+#[macro_export]
+macro_rules! cpp_out {
+    ($($name:ident : $ty:ty),+ => $closure:expr) => {{
+        $(let mut $name: $ty = ::core::default::Default::default();)+
+        let () = $closure;
+        ($($name),+)
+    }};
+}
+
+/// Used by `cpp!` to reject the captures of tuples, whose layout is unspecified:
+/// `__NotTuple::__check_not_tuple(&value)` is ambiguous, and fails to compile, if
+/// `value` is a tuple of 1 to 12 elements, for which both impls apply.
+#[doc(hidden)]
+pub trait __NotTuple<Marker> {
+    fn __check_not_tuple(&self) {}
+}
+
+impl<T: ?Sized> __NotTuple<()> for T {}
+
+#[doc(hidden)]
+pub struct __IsTuple;
+
+macro_rules! tuple_impls {
+    ($(($($name:ident),+))*) => {
+        $(impl<$($name),+> __NotTuple<__IsTuple> for ($($name,)+) {})*
+    };
+}
+
+tuple_impls! {
+    (A)
+    (A, B)
+    (A, B, C)
+    (A, B, C, D)
+    (A, B, C, D, E)
+    (A, B, C, D, E, F)
+    (A, B, C, D, E, F, G)
+    (A, B, C, D, E, F, G, H)
+    (A, B, C, D, E, F, G, H, I)
+    (A, B, C, D, E, F, G, H, I, J)
+    (A, B, C, D, E, F, G, H, I, J, K)
+    (A, B, C, D, E, F, G, H, I, J, K, L)
+}
+
+/// Used by `cpp!` for the captures of C++ enums: fails to compile if the size of
+/// `T` is not `N`, the size of the underlying type of the C++ enum.
+#[doc(hidden)]
+pub fn __check_enum_size<T, const N: usize>(_: &T) {
+    struct Check<T, const N: usize>(core::marker::PhantomData<T>);
+    impl<T, const N: usize> Check<T, N> {
+        const SIZE_MATCHES: () = assert!(
+            core::mem::size_of::<T>() == N,
+            "the underlying type of the C++ enum captured by cpp! does not have the size of \
+             the Rust type: use the #[repr] of the same integer type on the Rust enum"
+        );
+    }
+    #[allow(clippy::let_unit_value)]
+    let () = Check::<T, N>::SIZE_MATCHES;
+}
+
+/// Used by `cpp!` after the call for the mutable captures of type `char32_t`,
+/// which must be a `char`, `u32` or `i32`: if the C++ code wrote an invalid
+/// Unicode scalar value to a Rust `char`, it is replaced by
+/// `char::REPLACEMENT_CHARACTER` before panicking.
+#[doc(hidden)]
+pub trait __CppChar32 {
+    fn __validate_char(&mut self, name: &str);
+}
+
+impl __CppChar32 for char {
+    fn __validate_char(&mut self, name: &str) {
+        let ptr = self as *mut char as *mut u32;
+        // Safety: char has the size and alignment of a u32
+        let bits = unsafe { ptr.read() };
+        if core::char::from_u32(bits).is_none() {
+            unsafe { ptr.write(core::char::REPLACEMENT_CHARACTER as u32) };
+            panic!("the C++ code wrote an invalid char (0x{:x}) to `{}`", bits, name);
+        }
+    }
+}
+
+impl __CppChar32 for u32 {
+    fn __validate_char(&mut self, _: &str) {}
+}
+
+impl __CppChar32 for i32 {
+    fn __validate_char(&mut self, _: &str) {}
+}
+
+/// The Rust types of the elements of a wide string: `u16`, `i16`, `u32`, `i32`
+/// and `char`
+#[doc(hidden)]
+pub trait __WideCharElement: Sized {}
+
+impl __WideCharElement for u16 {}
+impl __WideCharElement for i16 {}
+impl __WideCharElement for u32 {}
+impl __WideCharElement for i32 {}
+impl __WideCharElement for char {}
+
+/// Used by `cpp!` for the captures of pointers to `wchar_t`, which must be a
+/// pointer, a reference, a slice or a `Slice` of wide string elements.
+#[doc(hidden)]
+pub trait __WideChar {
+    /// The size of the elements, which must be the size of `wchar_t`
+    const ELEMENT_SIZE: usize;
+}
+
+macro_rules! wide_char_impls {
+    ($($ty:ty),*) => {
+        $(impl<'a, E: __WideCharElement> __WideChar for $ty {
+            const ELEMENT_SIZE: usize = core::mem::size_of::<E>();
+        })*
+    };
+}
+
+wide_char_impls!(
+    *const E,
+    *mut E,
+    *const [E],
+    *mut [E],
+    &'a E,
+    &'a mut E,
+    &'a [E],
+    &'a mut [E],
+    Slice<'a, E>
+);
+
+/// Used by `cpp!` to get the element size of the capture `value`
+#[doc(hidden)]
+pub fn __wide_char_size<T: __WideChar>(_: &T) -> usize {
+    T::ELEMENT_SIZE
+}
+
+/// A field-less enum which can be returned by a `cpp!` closure: the value returned
+/// by the C++ code is checked to be one of its discriminants instead of being
+/// transmuted. Implement it with `#[derive(cpp::CppEnum)]`, on an enum with a
+/// `#[repr(C)]` or `#[repr(<integer>)]` attribute.
+/// See [`cpp!`](macro.cpp.html#returning-enums).
+pub trait CppEnum: Sized {
+    /// The integer type of the `#[repr]` attribute (`c_int` for `#[repr(C)]`)
+    type Repr: Copy + core::fmt::Debug;
+    /// The variant marked `#[cpp_enum(fallback)]`, returned for the values which
+    /// are not a discriminant. Without it, such a value panics.
+    const FALLBACK: Option<Self>;
+    /// The variant whose discriminant is `repr`, if any
+    fn from_repr(repr: Self::Repr) -> Option<Self>;
+}
+
+/// Used by `cpp!` to convert the value returned for an enum deriving `CppEnum`
+#[doc(hidden)]
+pub fn __checked_enum<E: CppEnum>(repr: E::Repr) -> E {
+    E::from_repr(repr).or(E::FALLBACK).unwrap_or_else(|| {
+        panic!(
+            "the C++ code returned {:?}, which is not a discriminant of `{}`",
+            repr,
+            core::any::type_name::<E>()
+        )
+    })
+}
+
+/// A slice which can be captured by a `cpp!` closure as a `rustcpp::Slice<T>`,
+/// with its length. See [`cpp!`](macro.cpp.html#capturing-arrays).
+#[repr(C)]
+#[derive(Debug)]
+pub struct Slice<'a, T> {
+    ptr: *mut T,
+    len: usize,
+    _marker: core::marker::PhantomData<&'a mut [T]>,
+}
+
+impl<'a, T> From<&'a mut [T]> for Slice<'a, T> {
+    fn from(slice: &'a mut [T]) -> Self {
+        Slice { ptr: slice.as_mut_ptr(), len: slice.len(), _marker: core::marker::PhantomData }
+    }
+}
+
+/// The C++ code must not modify the elements: capture it as a `rustcpp::Slice<const T>`
+impl<'a, T> From<&'a [T]> for Slice<'a, T> {
+    fn from(slice: &'a [T]) -> Self {
+        Slice {
+            ptr: slice.as_ptr() as *mut T,
+            len: slice.len(),
+            _marker: core::marker::PhantomData,
+        }
+    }
+}
+
+/// Used by `cpp!` to pass a `&mut [u8]` captured as a `rustcpp::MutBuf`
+#[doc(hidden)]
+#[repr(C)]
+pub struct __MutBuf<'a> {
+    data: *mut u8,
+    len: usize,
+    _marker: core::marker::PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> From<&'a mut [u8]> for __MutBuf<'a> {
+    fn from(buf: &'a mut [u8]) -> Self {
+        __MutBuf { data: buf.as_mut_ptr(), len: buf.len(), _marker: core::marker::PhantomData }
+    }
+}
+
+/// Used by `cpp!` to pass a `&mut dyn FnMut` captured as a `rustcpp::FnMut`: the
+/// reference, opaque to the C++ code, and the function which calls it
+#[doc(hidden)]
+#[repr(C)]
+pub struct __FnMut<'a, F: ?Sized> {
+    closure: *mut F,
+    call: *const (),
+    _marker: core::marker::PhantomData<&'a mut F>,
+}
+
+impl<'a, F: ?Sized + __DynFnMut> From<&'a mut F> for __FnMut<'a, F> {
+    fn from(closure: &'a mut F) -> Self {
+        __FnMut { closure, call: F::__call(), _marker: core::marker::PhantomData }
+    }
+}
+
+/// Implemented by the `dyn FnMut` types which can be captured as a `rustcpp::FnMut`
+#[doc(hidden)]
+pub trait __DynFnMut {
+    /// The function called by the C++ code with the address of the reference to the
+    /// closure, followed by the arguments
+    fn __call() -> *const ();
+}
+
+macro_rules! dyn_fn_mut {
+    ($($arg:ident),*) => {
+        impl<'f, R, $($arg),*> __DynFnMut for dyn FnMut($($arg),*) -> R + 'f {
+            fn __call() -> *const () {
+                #[allow(non_snake_case)]
+                unsafe extern "C" fn call<R, $($arg),*>(
+                    closure: *const *mut dyn FnMut($($arg),*) -> R,
+                    $($arg: $arg),*
+                ) -> R {
+                    (**closure)($($arg),*)
+                }
+                call::<R, $($arg),*> as *const ()
+            }
+        }
+    };
+}
+
+dyn_fn_mut!();
+dyn_fn_mut!(A);
+dyn_fn_mut!(A, B);
+dyn_fn_mut!(A, B, C);
+dyn_fn_mut!(A, B, C, D);
+
+/// A vector which can be captured by a `cpp!` closure as a `rustcpp::Vec<T>`, to
+/// which the C++ code can add elements. See
+/// [`cpp!`](macro.cpp.html#appending-to-vectors).
+#[repr(C)]
+#[derive(Debug)]
+pub struct VecMut<'a, T> {
+    vec: *mut alloc::vec::Vec<T>,
+    // Written by the C++ code
+    ptr: *mut T,
+    len: usize,
+    capacity: usize,
+    reserve: unsafe extern "C" fn(*mut alloc::vec::Vec<T>, usize, usize, *mut usize) -> *mut T,
+    _marker: core::marker::PhantomData<&'a mut alloc::vec::Vec<T>>,
+}
+
+/// Called by `rustcpp::Vec::reserve`: sets the length of the vector to the number of
+/// elements added by the C++ code, so they are moved if the vector grows.
+unsafe extern "C" fn reserve_vec<T>(
+    vec: *mut alloc::vec::Vec<T>,
+    len: usize,
+    additional: usize,
+    capacity: *mut usize,
+) -> *mut T {
+    let vec = &mut *vec;
+    vec.set_len(len);
+    vec.reserve(additional);
+    *capacity = vec.capacity();
+    vec.as_mut_ptr()
+}
+
+impl<'a, T> From<&'a mut alloc::vec::Vec<T>> for VecMut<'a, T> {
+    fn from(vec: &'a mut alloc::vec::Vec<T>) -> Self {
+        VecMut {
+            ptr: vec.as_mut_ptr(),
+            len: vec.len(),
+            capacity: vec.capacity(),
+            vec,
+            reserve: reserve_vec::<T>,
+            _marker: core::marker::PhantomData,
+        }
+    }
+}
+
+/// The elements added by the C++ code are only part of the vector from then on
+impl<'a, T> Drop for VecMut<'a, T> {
+    fn drop(&mut self) {
+        unsafe { (*self.vec).set_len(self.len) }
+    }
+}
+
+/// Characters owned by the C++ code, returned by a `cpp!` closure as a
+/// `rustcpp::StrView` without a copy. See [`cpp!`](macro.cpp.html#returning-string-views).
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct StrView {
+    ptr: *const u8,
+    len: usize,
+}
+
+impl StrView {
+    /// The pointer to the first character
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr
+    }
+
+    /// The number of characters
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether there is no character
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The characters, as a slice with an unbounded lifetime.
+    ///
+    /// # Safety
+    ///
+    /// The string which the C++ code returned a view of must not be modified or
+    /// destroyed while the slice is used.
+    pub unsafe fn as_bytes<'a>(&self) -> &'a [u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        core::slice::from_raw_parts(self.ptr, self.len)
+    }
+}
+
+/// A `std::time::Duration`, passed to C++ as a `rustcpp::Duration`, which converts
+/// from and to a `std::chrono::duration`. See [`cpp!`](macro.cpp.html#durations).
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Duration {
+    secs: i64,
+    nanos: u32,
+}
+
+/// The seconds which do not fit in an `i64` are saturated
+impl From<core::time::Duration> for Duration {
+    fn from(duration: core::time::Duration) -> Self {
+        let secs = duration.as_secs().min(i64::MAX as u64) as i64;
+        Duration { secs, nanos: duration.subsec_nanos() }
+    }
+}
+
+impl Duration {
+    /// The `std::time::Duration`, or `None` if the C++ duration was negative
+    pub fn to_duration(self) -> Option<core::time::Duration> {
+        if self.secs < 0 || self.nanos >= 1_000_000_000 {
+            return None;
+        }
+        Some(core::time::Duration::new(self.secs as u64, self.nanos))
+    }
+}
+
+/// Used by `cpp!` to convert a returned `rustcpp::Duration` to the Rust return type
+#[doc(hidden)]
+pub trait __FromCppDuration {
+    fn __from_cpp_duration(duration: Duration) -> Self;
+}
+
+impl __FromCppDuration for Duration {
+    fn __from_cpp_duration(duration: Duration) -> Self {
+        duration
+    }
+}
+
+impl __FromCppDuration for core::time::Duration {
+    fn __from_cpp_duration(duration: Duration) -> Self {
+        match duration.to_duration() {
+            Some(duration) => duration,
+            None => panic!("the C++ code returned a negative duration"),
+        }
+    }
+}
+
+/// A bfloat16 number, passed to C++ as a `rustcpp::Bf16`, which converts from and
+/// to `float`. See [`cpp!`](macro.cpp.html#bfloat16-numbers).
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Bf16(u16);
+
+impl Bf16 {
+    /// The number whose representation is `bits`
+    pub const fn from_bits(bits: u16) -> Self {
+        Bf16(bits)
+    }
+
+    /// The representation of the number: the 16 high bits of an `f32`
+    pub const fn to_bits(self) -> u16 {
+        self.0
+    }
+
+    /// The nearest number (ties to even) to `f`, like the C++ conversion
+    pub fn from_f32(f: f32) -> Self {
+        let u = f.to_bits();
+        if f.is_nan() {
+            return Bf16((u >> 16) as u16 | 0x40);
+        }
+        Bf16((u.wrapping_add(0x7fff + ((u >> 16) & 1)) >> 16) as u16)
+    }
+
+    /// The value of the number, which is exact
+    pub fn to_f32(self) -> f32 {
+        f32::from_bits((self.0 as u32) << 16)
+    }
+}
+
+impl From<f32> for Bf16 {
+    fn from(f: f32) -> Self {
+        Bf16::from_f32(f)
+    }
+}
+
+impl From<Bf16> for f32 {
+    fn from(b: Bf16) -> Self {
+        b.to_f32()
+    }
+}
+
 #[doc(hidden)]
 pub trait CppTrait {
     type BaseType;
@@ -316,6 +1401,59 @@ pub trait CppTrait {
 /// * The trait `Ord` can also be specified when the semantics of the `operator<` corresponds
 ///   to a total order
 ///
+/// ## Constructing from Rust values
+///
+/// The `#[rustcpp::from(...)]` attribute implements `From` by calling the C++
+/// constructor which takes arguments of the given C++ types. With
+/// `("const char*", "size_t")`, it implements `From<&str>`, passing the pointer
+/// to the UTF-8 bytes, which are not null terminated, and their length. With a
+/// single C++ type corresponding to a Rust primitive (such as `"int"` or
+/// `"double"`), it implements `From` for that primitive. The attribute can be
+/// repeated.
+///
+/// ```ignore
+/// cpp_class!(#[rustcpp::from("const char*", "size_t")] unsafe struct Name as "Name");
+/// let name = Name::from("hello");
+/// ```
+///
+/// ## Field accessors
+///
+/// The public data members of the C++ class can be listed in braces after its
+/// type, with their Rust and C++ types. Each field `x` gets a getter `x(&self)`,
+/// which copy-constructs the value, and a setter `set_x(&mut self, value)`, which
+/// copy-assigns it. The methods have the visibility given to the field.
+///
+/// ```ignore
+/// cpp_class!(pub unsafe struct Point as "Point" { pub x: i32 as "int", y: f64 as "double" });
+/// let mut p = Point::default();
+/// p.set_x(3);
+/// assert_eq!(p.x(), 3);
+/// ```
+///
+/// As for the captures of `cpp!`, the Rust type must have the layout of the C++ type.
+///
+/// ## Manual destruction
+///
+/// The `#[rustcpp::no_drop]` attribute prevents the implementation of `Drop`,
+/// even if the C++ type has a non-trivial destructor. This is useful when the
+/// ownership of the object is transferred to C++ code which destroys it.
+///
+/// ```ignore
+/// cpp_class!(#[rustcpp::no_drop] pub unsafe struct Handle as "Handle");
+/// impl Handle {
+///     fn destroy(mut self) {
+///         let ptr = &mut self as *mut Handle;
+///         unsafe { cpp!([ptr as "Handle*"] { ptr->~Handle(); }) }
+///     }
+/// }
+/// ```
+///
+/// The destructor is then never called from Rust: a value which is not destroyed
+/// by other means leaks the resources it owns. Calling the destructor twice, or
+/// using the value after it was destroyed, is undefined behavior. A `Clone`
+/// implementation calling the copy constructor is still provided, and each clone
+/// must be destroyed as well.
+///
 /// ## Safety Warning
 ///
 /// Use of this macro is highly unsafe. Only certain C++ classes can be bound
@@ -353,6 +1491,15 @@ macro_rules! cpp_class {
     ($(#[$($attrs:tt)*])* pub($($pub:tt)*) unsafe struct $name:ident as $type:expr) => {
         $crate::__cpp_class_internal!{@parse [ $(#[$($attrs)*])* ] [pub($($pub)*)] [unsafe struct $name as $type] }
     };
+    ($(#[$($attrs:tt)*])* unsafe struct $name:ident as $type:literal { $($fields:tt)* }) => {
+        $crate::__cpp_class_internal!{@parse [ $(#[$($attrs)*])* ] [] [unsafe struct $name as $type] { $($fields)* } }
+    };
+    ($(#[$($attrs:tt)*])* pub unsafe struct $name:ident as $type:literal { $($fields:tt)* }) => {
+        $crate::__cpp_class_internal!{@parse [ $(#[$($attrs)*])* ] [pub] [unsafe struct $name as $type] { $($fields)* } }
+    };
+    ($(#[$($attrs:tt)*])* pub($($pub:tt)*) unsafe struct $name:ident as $type:literal { $($fields:tt)* }) => {
+        $crate::__cpp_class_internal!{@parse [ $(#[$($attrs)*])* ] [pub($($pub)*)] [unsafe struct $name as $type] { $($fields)* } }
+    };
 }
 
 /// Implementation details for cpp_class!
@@ -369,10 +1516,24 @@ macro_rules! __cpp_class_internal {
             }
         ]}
     };
+    (@parse [$($attrs:tt)*] [$($vis:tt)*] [unsafe struct $name:ident as $type:expr] { $($fields:tt)* }) => {
+        $crate::__cpp_class_internal!{@parse_attributes [ $($attrs)* ] [] [
+            #[derive($crate::__cpp_internal_class)]
+            #[repr(C)]
+            $($vis)* struct $name {
+                _opaque : [<$name as $crate::CppTrait>::BaseType ; <$name as $crate::CppTrait>::ARRAY_SIZE
+                    + (stringify!($($attrs)* $($vis)* unsafe struct $name as $type { $($fields)* }), 0).1]
+            }
+        ]}
+    };
 
     (@parse_attributes [] [$($attributes:tt)*] [$($result:tt)*]) => ( $($attributes)* $($result)* );
     (@parse_attributes [#[derive($($der:ident),*)] $($tail:tt)* ] [$($attributes:tt)*] [$($result:tt)*] )
         => ($crate::__cpp_class_internal!{@parse_derive [$($der),*] @parse_attributes [$($tail)*] [ $($attributes)* ] [ $($result)* ] } );
+    (@parse_attributes [ #[rustcpp::no_drop] $($tail:tt)* ] [$($attributes:tt)*] [$($result:tt)*])
+        => ($crate::__cpp_class_internal!{@parse_attributes [$($tail)*] [$($attributes)*] [ $($result)* ] } );
+    (@parse_attributes [ #[rustcpp::from($($args:tt)*)] $($tail:tt)* ] [$($attributes:tt)*] [$($result:tt)*])
+        => ($crate::__cpp_class_internal!{@parse_attributes [$($tail)*] [$($attributes)*] [ $($result)* ] } );
     (@parse_attributes [ #[$m:meta] $($tail:tt)* ] [$($attributes:tt)*] [$($result:tt)*])
         => ($crate::__cpp_class_internal!{@parse_attributes [$($tail)*] [$($attributes)* #[$m] ] [ $($result)* ] } );
 
@@ -392,3 +1553,39 @@ macro_rules! __cpp_class_internal {
     (@parse_derive [$i:ident $(,$tail:ident)*] @parse_attributes [$($attr:tt)*] [$($attributes:tt)*] [$($result:tt)*] )
         => ( $crate::__cpp_class_internal!{@parse_derive [$($tail),*] @parse_attributes [$($attr)*] [$($attributes)* #[derive($i)] ] [ $($result)* ] } );
 }
+
+/// The compiler which built the C++ code of the crate, as returned by
+/// [`build_info!`].
+#[derive(Debug, Clone, Copy)]
+pub struct BuildInfo {
+    /// The path of the compiler
+    pub compiler: &'static str,
+    /// The first line printed by the compiler for `--version` (or by `cl.exe`
+    /// without arguments), or an empty string if it could not be run
+    pub version: &'static str,
+    /// The flags given to the compiler, including the defines and include directories
+    pub flags: &'static [&'static str],
+}
+
+/// Returns the [`BuildInfo`] of the C++ code of the crate, recorded by
+/// `cpp_build` when it built the library, to help diagnose problems which
+/// depend on the toolchain.
+///
+/// ```ignore
+/// let info = cpp::build_info!();
+/// println!("built with {} ({}) {}", info.compiler, info.version, info.flags.join(" "));
+/// ```
+///
+/// When several libraries are built with `cpp_build::Config::lib_name`, the name
+/// of the library can be given: `cpp::build_info!("my_lib")`.
+#[macro_export]
+macro_rules! build_info {
+    () => {
+        $crate::build_info!("rust_cpp_generated")
+    };
+    ($lib_name:literal) => {{
+        let (compiler, version, flags) =
+            include!(concat!(env!("OUT_DIR"), "/", $lib_name, "_build_info.rs"));
+        $crate::BuildInfo { compiler, version, flags }
+    }};
+}
@@ -10,15 +10,61 @@ mod strnom;
 
 use cpp_common::*;
 use lazy_static::lazy_static;
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::DefaultHasher;
 use std::collections::hash_map::{Entry, HashMap};
+use std::collections::HashSet;
 use std::env;
-use std::fs::{create_dir, remove_dir_all, File};
-use std::io::prelude::*;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::{copy, create_dir_all, remove_dir_all, remove_file, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, prelude::*};
 use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
 
 mod parser;
 
+/// The callback of [`Config::on_compiler_output`]. It is `Send`, so that the
+/// `Config` holding it can be moved to another thread.
+type OutputHandler = Box<dyn FnMut(&str) + Send>;
+
+// Checks at compile time that Config stays Send
+const _: fn() = || {
+    fn is_send<T: Send>() {}
+    is_send::<Config>();
+};
+
+thread_local! {
+    // The callback of Config::on_compiler_output, while Config::build runs
+    static OUTPUT_HANDLER: RefCell<Option<OutputHandler>> = RefCell::new(None);
+    // Config::cargo_warnings, while Config::build runs
+    static CARGO_WARNINGS: Cell<bool> = const { Cell::new(true) };
+}
+
+/// Pass each line of `a` to the callback of [`Config::on_compiler_output`].
+/// Returns false if there is none.
+fn call_output_handler(a: &str) -> bool {
+    OUTPUT_HANDLER.with(|handler| match &mut *handler.borrow_mut() {
+        Some(handler) => {
+            for line in a.lines() {
+                handler(line);
+            }
+            true
+        }
+        None => false,
+    })
+}
+
 fn warnln_impl(a: &str) {
+    if call_output_handler(a) {
+        return;
+    }
+    if !CARGO_WARNINGS.with(Cell::get) {
+        let _ = writeln!(std::io::stderr(), "{}", a);
+        return;
+    }
     for s in a.lines() {
         println!("cargo:warning={}", s);
     }
@@ -30,12 +76,37 @@ macro_rules! warnln {
     }
 }
 
+/// Passes the messages of `cc`, such as the diagnostics of the compiler, to
+/// `warnln!`. The error of a failed command is returned by `cc` instead.
+struct CcOutput;
+
+impl cc::BuildMessageLogger for CcOutput {
+    fn log(&self, kind: cc::BuildMessageKind, msg: cc::BuildMessage<'_>, _: &dyn std::any::Any) {
+        if !matches!(kind, cc::BuildMessageKind::CommandFailed { .. }) {
+            warnln!("{}", msg);
+        }
+    }
+}
+
+fn errln_impl(a: &str) {
+    if !call_output_handler(a) {
+        let _ = writeln!(std::io::stderr(), "{}", a);
+    }
+}
+
+// Like warnln!, for the errors which are printed to stderr
+macro_rules! errln {
+    ($($all:tt)*) => {
+        $crate::errln_impl(&format!($($all)*));
+    }
+}
+
 // Like the write! macro, but add the #line directive (pointing to this file).
 // Note: the string literal must be on on the same line of the macro
 macro_rules! write_add_line {
     ($o:expr, $($e:tt)*) => {
         (|| {
-            writeln!($o, "#line {} \"{}\"", line!(), file!().replace('\\', "\\\\"))?;
+            writeln!($o, "#line {} \"{}\"", line!(), $crate::escape_cpp_string(file!()))?;
             write!($o, $($e)*)
         })()
     };
@@ -44,9 +115,22 @@ macro_rules! write_add_line {
 const INTERNAL_CPP_STRUCTS: &str = r#"
 /* THIS FILE IS GENERATED BY rust-cpp. DO NOT EDIT */
 
+// MSVC only sets __cplusplus with /Zc:__cplusplus
+#if (defined(_MSVC_LANG) ? _MSVC_LANG : __cplusplus) < 201103L
+#error "rust-cpp requires C++11 or later: pass a -std flag with cpp_build::Config::flag"
+#endif
+
 #include "stdint.h" // For {u}intN_t
+#include <cstddef> // For size_t and ptrdiff_t
 #include <new> // For placement new
+#ifndef RUST_CPP_FREESTANDING
 #include <cstdlib> // For abort
+#include <cassert> // For Slice::at
+#define RUST_CPP_ABORT() std::abort()
+#else
+// Config::freestanding: there may be no C runtime providing abort
+#define RUST_CPP_ABORT() __builtin_trap()
+#endif
 #include <type_traits>
 #include <utility>
 
@@ -83,18 +167,178 @@ template<typename T> struct argument_helper<T&> {
     using type = argument_helper<T&> const&;
 };
 
+// The type which is read by Rust for an argument of a rust! callback
+template<typename T> struct callback_type { using type = T; };
+template<typename T> struct callback_type<T&> { using type = T*; };
+
 template<typename T>
 typename std::enable_if<std::is_copy_constructible<T>::value>::type copy_helper(const void *src, void *dest)
 { new (dest) T (*static_cast<T const*>(src)); }
 template<typename T>
 typename std::enable_if<!std::is_copy_constructible<T>::value>::type copy_helper(const void *, void *)
-{ std::abort(); }
+{ RUST_CPP_ABORT(); }
 template<typename T>
 typename std::enable_if<std::is_default_constructible<T>::value>::type default_helper(void *dest)
 { new (dest) T(); }
 template<typename T>
 typename std::enable_if<!std::is_default_constructible<T>::value>::type default_helper(void *)
-{ std::abort(); }
+{ RUST_CPP_ABORT(); }
+
+// The pointer to the elements of a Rust slice and its length, see cpp::Slice
+template<typename T> struct Slice {
+    T *ptr;
+    size_t len;
+    T *begin() const { return ptr; }
+    T *end() const { return ptr + len; }
+    size_t size() const { return len; }
+    T &operator[](size_t i) const { return ptr[i]; }
+    T &at(size_t i) const {
+#ifndef RUST_CPP_FREESTANDING
+        assert(i < len && "rustcpp::Slice::at: index out of bounds");
+#else
+        if (i >= len)
+            RUST_CPP_ABORT();
+#endif
+        return ptr[i];
+    }
+};
+
+// A Rust byte buffer to which the C++ code writes, captured from a &mut [u8]. copy_from never
+// writes more than len bytes.
+struct MutBuf {
+    uint8_t *data;
+    size_t len;
+    uint8_t *begin() const { return data; }
+    uint8_t *end() const { return data + len; }
+    size_t size() const { return len; }
+    // Copies the first n bytes of src, or as many as fit, and returns the number copied
+    size_t copy_from(const void *src, size_t n) const {
+        if (n > len)
+            n = len;
+        const uint8_t *bytes = static_cast<const uint8_t *>(src);
+        for (size_t i = 0; i < n; ++i)
+            data[i] = bytes[i];
+        return n;
+    }
+};
+
+// A Rust closure, captured from a &mut dyn FnMut. It can be called, copied and stored in a
+// std::function, but only during the call to the cpp! closure which captures it. The
+// arguments and the return value are passed by value to Rust, so their types must be
+// primitive types, pointers or structs with a #[repr(C)] counterpart.
+template<typename F> struct FnMut;
+template<typename R, typename... Args> struct FnMut<R(Args...)> {
+    void *closure[2]; // The &mut dyn FnMut, opaque to C++
+    R (*call)(void *const *closure, Args... args);
+    R operator()(Args... args) const { return call(closure, args...); }
+};
+
+// A Rust vector to which the C++ code can add elements, see cpp::VecMut. The elements are
+// moved by copying their bytes when the Rust vector grows, and its length is set when the
+// cpp::VecMut is dropped.
+template<typename T> struct Vec {
+    typedef T value_type;
+    void *vec;
+    T *ptr;
+    size_t len;
+    size_t capacity;
+    T *(*reserve_fn)(void *vec, size_t len, size_t additional, size_t *capacity);
+    T *begin() const { return ptr; }
+    T *end() const { return ptr + len; }
+    size_t size() const { return len; }
+    T &operator[](size_t i) const { return ptr[i]; }
+    void reserve(size_t additional) {
+        if (capacity - len < additional)
+            ptr = reserve_fn(vec, len, additional, &capacity);
+    }
+    void push_back(const T &value) { reserve(1); ::new (ptr + len) T(value); ++len; }
+    void push_back(T &&value) { reserve(1); ::new (ptr + len) T(std::move(value)); ++len; }
+};
+
+// The pointer to characters owned by the C++ code and their count, returned to Rust without a
+// copy, see cpp::StrView. It is constructed from any string with data() and size(), such as a
+// std::string or a std::string_view, which must outlive it.
+struct StrView {
+    const char *ptr;
+    size_t len;
+    StrView(const char *ptr, size_t len) : ptr(ptr), len(len) {}
+    template<typename S, typename = decltype(std::declval<const S&>().data() + std::declval<const S&>().size())>
+    StrView(const S &s) : ptr(s.data()), len(s.size()) {}
+    const char *data() const { return ptr; }
+    size_t size() const { return len; }
+};
+
+// A std::time::Duration, see cpp::Duration. It is converted from and to any duration with an
+// integer count of a std::ratio period, such as std::chrono::nanoseconds, without including
+// <chrono>. A negative duration is converted to secs = -1, which Rust rejects.
+// The ratio between the period and a nanosecond is reduced before multiplying, so that periods
+// finer than a nanosecond, such as std::pico, don't overflow.
+struct Duration {
+    int64_t secs;
+    uint32_t nanos;
+    Duration(int64_t secs, uint32_t nanos) : secs(secs), nanos(nanos) {}
+    template<typename D, typename P = typename D::period>
+    Duration(const D &d) : secs(-1), nanos(0) {
+        int64_t count = static_cast<int64_t>(d.count());
+        if (count < 0)
+            return;
+        const int64_t g = gcd(P::den, 1000000000);
+        int64_t rest = count % P::den * P::num;
+        secs = count / P::den * P::num + rest / P::den;
+        nanos = static_cast<uint32_t>(rest % P::den * (1000000000 / g) / (P::den / g));
+    }
+    // Truncated to the period of D, and saturated if the count doesn't fit in an int64_t
+    template<typename D, typename P = typename D::period>
+    operator D() const {
+        typedef typename D::rep Rep;
+        const int64_t max = static_cast<int64_t>(~uint64_t(0) >> 1);
+        const int64_t g = gcd(P::den, 1000000000);
+        int64_t frac = int64_t(nanos) * (P::den / g) / (P::num * (1000000000 / g));
+        int64_t part = secs % P::num * P::den / P::num + frac;
+        if (secs / P::num > (max - part) / P::den)
+            return D(static_cast<Rep>(max));
+        return D(static_cast<Rep>(secs / P::num * P::den + part));
+    }
+    static constexpr int64_t gcd(int64_t a, int64_t b) { return b == 0 ? a : gcd(b, a % b); }
+};
+
+// A bfloat16 number, see cpp::Bf16: the 16 high bits of a float. It converts implicitly from
+// and to float, and so to the __bf16 type of the compilers which have one.
+struct Bf16 {
+    uint16_t bits;
+    Bf16() : bits(0) {}
+    Bf16(float f) {
+        uint32_t u = float_bits(f);
+        if ((u & 0x7fffffff) > 0x7f800000)
+            bits = static_cast<uint16_t>((u >> 16) | 0x40); // Keep a NaN quiet
+        else // Round to nearest, ties to even
+            bits = static_cast<uint16_t>((u + 0x7fff + ((u >> 16) & 1)) >> 16);
+    }
+    operator float() const {
+        uint32_t u = static_cast<uint32_t>(bits) << 16;
+        float f;
+        copy_bytes(&f, &u);
+        return f;
+    }
+private:
+    static uint32_t float_bits(float f) {
+        uint32_t u;
+        copy_bytes(&u, &f);
+        return u;
+    }
+    static void copy_bytes(void *dest, const void *src) {
+        for (size_t i = 0; i < 4; ++i)
+            static_cast<unsigned char *>(dest)[i] = static_cast<const unsigned char *>(src)[i];
+    }
+};
+
+// Marks the declaration of a function defined elsewhere which must be kept by the linker,
+// see the cpp! documentation
+#define rustcpp_import
+
+// Used to report a clear error when a captured or returned type is only forward declared
+template<typename T, typename = void> struct is_complete : std::false_type {};
+template<typename T> struct is_complete<T, decltype(void(sizeof(T)))> : std::true_type {};
 
 template<typename T> int compare_helper(const T &a, const T&b, int cmp) {
     switch (cmp) {
@@ -110,20 +354,62 @@ template<typename T> int compare_helper(const T &a, const T&b, int cmp) {
         case -1: return a <= b;
         case 1: return a >= b;
     }
-    std::abort();
+    RUST_CPP_ABORT();
 }
 }
 
+// Annotation of the symbols which must be found by the Rust code, see Config::hidden_visibility
+#ifndef RUST_CPP_EXPORT
+#define RUST_CPP_EXPORT
+#endif
+
+// Put around the code of the closures by Config::catch_exceptions
+#if defined(__cpp_exceptions) || defined(__EXCEPTIONS) || defined(_CPPUNWIND)
+#include <exception>
+#define RUST_CPP_TRY try {
+#define RUST_CPP_CATCH } catch (...) { std::terminate(); }
+#else
+#define RUST_CPP_TRY {
+#define RUST_CPP_CATCH }
+#endif
+
 #define RUST_CPP_CLASS_HELPER(HASH, ...) \
     extern "C" { \
-    void __cpp_destructor_##HASH(void *ptr) { typedef __VA_ARGS__ T; static_cast<T*>(ptr)->~T(); } \
-    void __cpp_copy_##HASH(const void *src, void *dest) { rustcpp::copy_helper<__VA_ARGS__>(src, dest); } \
-    void __cpp_default_##HASH(void *dest) { rustcpp::default_helper<__VA_ARGS__>(dest); } \
+    RUST_CPP_EXPORT void __cpp_destructor_##HASH(void *ptr) { typedef __VA_ARGS__ T; static_cast<T*>(ptr)->~T(); } \
+    RUST_CPP_EXPORT void __cpp_copy_##HASH(const void *src, void *dest) { rustcpp::copy_helper<__VA_ARGS__>(src, dest); } \
+    RUST_CPP_EXPORT void __cpp_default_##HASH(void *dest) { rustcpp::default_helper<__VA_ARGS__>(dest); } \
     }
 "#;
 
+// The length of the include directories and definitions above which they are
+// written to a response file. Windows limits a command line to 32k characters,
+// and to 8k when the compiler is a batch script wrapper.
+const RESPONSE_FILE_THRESHOLD: usize = 8000;
+
+// Written before INTERNAL_CPP_STRUCTS when Config::hidden_visibility is set
+const HIDDEN_VISIBILITY_EXPORT: &str = r#"
+#if defined(__GNUC__) || defined(__clang__)
+#define RUST_CPP_EXPORT __attribute__((visibility("default")))
+#endif
+"#;
+
+// The files written with Config::cpp20_modules, next to cpp_closures.cpp
+const MODULE_PRELUDE: &str = "cpp_prelude.h";
+const MODULE_INTERFACE: &str = "cpp_snippets.cppm";
+
+// The files written with Config::emit_amalgamation
+const AMALGAMATION_HEADER: &str = "rust_cpp_generated.h";
+const AMALGAMATION_SOURCE: &str = "rust_cpp_generated.cpp";
+
+// When this environment variable is `1`, the generated code is printed to the build log
+const DUMP_ENV: &str = "RUST_CPP_DUMP";
+
+// Written before INTERNAL_CPP_STRUCTS when Config::freestanding is set
+const FREESTANDING_DEFINE: &str = r#"
+#define RUST_CPP_FREESTANDING
+"#;
+
 lazy_static! {
-    static ref CPP_DIR: PathBuf = OUT_DIR.join("rust_cpp");
     static ref CARGO_MANIFEST_DIR: PathBuf = PathBuf::from(env::var("CARGO_MANIFEST_DIR").expect(
         r#"
 -- rust-cpp fatal error --
@@ -131,85 +417,486 @@ lazy_static! {
 The CARGO_MANIFEST_DIR environment variable was not set.
 NOTE: rust-cpp's build function must be run in a build script."#
     ));
+    static ref TARGET_POINTER_WIDTH: Option<u32> =
+        env::var("CARGO_CFG_TARGET_POINTER_WIDTH").ok().and_then(|x| x.parse().ok());
+    // The names of the libraries built so far by this build script
+    static ref BUILT_LIBRARIES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+}
+
+/// Quote an argument of a command line which is displayed, if it contains
+/// spaces (as in `C:\Users\Foo Bar\`), quotes, or other characters which have a
+/// meaning for the shell (as in the definition `-DSQUARE(x)=((x) * (x))`)
+fn quote_command_arg(arg: &str) -> String {
+    let special = |c: char| c.is_whitespace() || "\"'()<>|&;$`*?[]{}#~!".contains(c);
+    if arg.is_empty() || arg.contains(special) {
+        let escaped = arg.replace('"', "\\\"").replace('$', "\\$").replace('`', "\\`");
+        format!("\"{}\"", escaped)
+    } else {
+        arg.to_owned()
+    }
+}
+
+/// The include directories without the duplicates, in the order of their first
+/// occurrence since it decides which header is found when several directories have
+/// one with the same name. The paths are compared once canonicalized, but are passed
+/// as written (MSVC does not accept the `\\?\` prefix of canonical Windows paths).
+fn unique_includes(includes: &[PathBuf]) -> Vec<&PathBuf> {
+    let mut seen = HashSet::new();
+    includes
+        .iter()
+        .filter(|dir| seen.insert(dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf())))
+        .collect()
+}
+
+/// Quote an argument written in a response file. GCC and Clang treat the
+/// backslashes as escapes, while MSVC uses the rules of its command line.
+fn quote_response_file_arg(arg: &str, msvc: bool) -> String {
+    if !msvc {
+        let mut result = String::new();
+        for c in arg.chars() {
+            if c == '\\' || c == '"' || c == '\'' || c.is_whitespace() {
+                result.push('\\');
+            }
+            result.push(c);
+        }
+        return result;
+    }
+    if !arg.contains(|c: char| c.is_whitespace() || c == '"') {
+        return arg.to_owned();
+    }
+    // The backslashes are only special before a quote
+    let mut result = String::from("\"");
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            '"' => {
+                result.push_str(&"\\".repeat(backslashes * 2 + 1));
+                backslashes = 0;
+            }
+            _ => {
+                result.push_str(&"\\".repeat(backslashes));
+                backslashes = 0;
+            }
+        }
+        if c != '\\' {
+            result.push(c);
+        }
+    }
+    result.push_str(&"\\".repeat(backslashes * 2));
+    result.push('"');
+    result
+}
+
+/// Escape a string so it can be put within a C++ string literal
+fn escape_cpp_string(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Generate static assertions checking that the C++ types which correspond to
+/// a Rust primitive (see `cpp_common::PRIMITIVE_TYPES`) have the right width on
+/// the target. If the width is unknown, the assertion always fails when `strict`
+/// is set (see [`Config::strict_types`]).
+fn primitive_type_checks(closure: &Closure, strict: bool) -> String {
+    let ClosureSig { captures, cpp, .. } = &closure.sig;
+    let mut result = String::new();
+    let types = captures.iter().map(|c| (&c.cpp, "captured")).chain(Some((cpp, "returned")));
+    for (cpp, what) in types {
+        let rust = match rust_primitive_for(cpp) {
+            Some(x) => x,
+            None => continue,
+        };
+        match primitive_width(rust, *TARGET_POINTER_WIDTH) {
+            Some(width) => {
+                let message = format!(
+                    "rust-cpp: the C++ type `{}` {} at {} is not {} bits wide on the target, so \
+                     it does not match the Rust type `{}`",
+                    cpp, what, closure.location, width, rust
+                );
+                result.push_str(&format!(
+                    "static_assert(sizeof({}) * 8 == {}, \"{}\");\n",
+                    cpp,
+                    width,
+                    escape_cpp_string(&message)
+                ));
+            }
+            None if strict => {
+                let message = format!(
+                    "rust-cpp: the width of the C++ type `{}` {} at {} can't be checked against \
+                     the Rust type `{}`, as the pointer width of the target is unknown \
+                     (Config::strict_types)",
+                    cpp, what, closure.location, rust
+                );
+                result.push_str(&format!(
+                    "static_assert(sizeof({}) == 0, \"{}\");\n",
+                    cpp,
+                    escape_cpp_string(&message)
+                ));
+            }
+            None => {}
+        }
+    }
+    result
+}
+
+/// Generate the static assertions of [`Config::strict_types`], checking that the
+/// captured and returned C++ types are standard-layout: otherwise, only their size
+/// and alignment can be checked. The types of `cpp_class!` macros, and the types
+/// which contain them, are opaque to Rust, so they are not checked.
+fn strict_type_checks(closure: &Closure, classes: &[Class]) -> String {
+    let ClosureSig { captures, cpp, .. } = &closure.sig;
+    let wrapper = closure.sig.return_wrapper();
+    // The value of a std::optional or a std::expected is passed to Rust on its own
+    let returned = match &wrapper {
+        Some(ReturnWrapper::Optional { cpp, .. }) | Some(ReturnWrapper::Expected { cpp, .. }) => {
+            cpp
+        }
+        None => cpp,
+    };
+    let captured = captures
+        .iter()
+        .filter(|c| !c.constant)
+        .map(|c| (&c.cpp, format!("capture site of `{}`", c.name)));
+    let returned = Some((returned, "return site".to_owned())).filter(|_| returned != "void");
+    let mut checks = String::new();
+    for (cpp, site) in captured.chain(returned) {
+        if classes.iter().any(|class| cpp.contains(class.cpp.as_str())) {
+            continue;
+        }
+        let message = format!(
+            "rust-cpp: C++ type `{}` at the {} ({}) is not standard-layout, so its layout can't \
+             be checked against the Rust type (Config::strict_types)",
+            cpp, site, closure.location
+        );
+        checks.push_str(&format!(
+            "{}static_assert(std::is_standard_layout<std::remove_reference<{}>::type>::value, \"{}\");\n",
+            closure.line,
+            cpp,
+            escape_cpp_string(&message)
+        ));
+    }
+    checks
+}
+
+/// Generate static assertions checking that the captured and returned C++
+/// types are complete, since their size must be known. They are put after a
+/// `#line` directive pointing to the macro so the error has the Rust location.
+fn incomplete_type_checks(closure: &Closure) -> String {
+    let ClosureSig { captures, cpp, .. } = &closure.sig;
+    let mut checks = String::new();
+    let captured = captures.iter().map(|c| (&c.cpp, format!("capture site of `{}`", c.name)));
+    let returned = Some((cpp, "return site".to_owned())).filter(|_| cpp != "void");
+    for (cpp, site) in captured.chain(returned) {
+        let message = format!(
+            "rust-cpp: C++ type `{}` is incomplete at the {} ({})",
+            cpp, site, closure.location
+        );
+        checks.push_str(&format!(
+            "{}static_assert(rustcpp::is_complete<{}>::value, \"{}\");\n",
+            closure.line,
+            cpp,
+            escape_cpp_string(&message)
+        ));
+    }
+    checks
+}
+
+/// Explain the compile error of a C++ type of a closure which is not declared, if
+/// `stderr` reports one of the identifiers of such a type as undeclared: the
+/// captured and returned types must be declared at global scope, since they are
+/// used outside of the body of the closure.
+fn undeclared_type_hint(closures: &[Closure], stderr: &str) -> Option<String> {
+    const UNDECLARED_MESSAGES: &[&str] = &[
+        "was not declared",
+        "does not name a type",
+        "has not been declared",
+        "undeclared identifier",
+        "unknown type name",
+    ];
+    let errors = stderr
+        .lines()
+        .filter(|line| UNDECLARED_MESSAGES.iter().any(|m| line.contains(m)))
+        .collect::<Vec<_>>();
+    // GCC quotes the identifiers with typographic quotes in a UTF-8 locale
+    let is_undeclared = |ident: &str| {
+        errors.iter().any(|line| {
+            ["'", "\u{2018}"].iter().any(|open| {
+                ["'", "\u{2019}"]
+                    .iter()
+                    .any(|close| line.contains(&format!("{}{}{}", open, ident, close)))
+            })
+        })
+    };
+    let mut sites = Vec::new();
+    for closure in closures {
+        let ClosureSig { captures, cpp, .. } = &closure.sig;
+        let captured = captures.iter().map(|c| (&c.cpp, format!("the capture `{}`", c.name)));
+        let returned = Some((cpp, "the return value".to_owned()));
+        for (cpp, site) in captured.chain(returned) {
+            let mut idents = cpp.split(|c: char| !(c.is_alphanumeric() || c == '_'));
+            if idents.any(|i| !i.is_empty() && is_undeclared(i)) {
+                sites.push(format!(
+                    "NOTE: The C++ type `{}` of {} at {} is not declared.",
+                    cpp, site, closure.location
+                ));
+            }
+        }
+    }
+    if sites.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "{}\nThe C++ types of the captures and of the return value of a cpp! closure must be \
+         declared at global scope before it, for instance in a cpp!{{{{ }}}} snippet. The types \
+         declared in the body of a closure are only visible within it, such as in the types of a \
+         rust! callback.",
+        sites.join("\n")
+    ))
 }
 
-fn gen_cpp_lib(visitor: &parser::Parser) -> PathBuf {
-    let result_path = CPP_DIR.join("cpp_closures.cpp");
-    let mut output = File::create(&result_path).expect("Unable to generate temporary C++ file");
+/// The linker argument which forces `symbol` to be resolved by the final link
+fn undefined_symbol_link_arg(symbol: &str) -> String {
+    let target_env = env::var("CARGO_CFG_TARGET_ENV").unwrap_or_default();
+    let target_vendor = env::var("CARGO_CFG_TARGET_VENDOR").unwrap_or_default();
+    if target_env == "msvc" {
+        format!("/INCLUDE:{}", symbol)
+    } else if target_vendor == "apple" {
+        format!("-Wl,-u,_{}", symbol)
+    } else {
+        format!("-Wl,-u,{}", symbol)
+    }
+}
+
+/// The path of the static library `lib_name` built in `OUT_DIR`
+fn lib_path(lib_name: &str) -> PathBuf {
+    if env::var("CARGO_CFG_TARGET_ENV").unwrap_or_default() == "msvc" {
+        OUT_DIR.join(msvc_lib_file_name(lib_name))
+    } else {
+        OUT_DIR.join(lib_file_name(lib_name))
+    }
+}
+
+/// The `rustcpp::Slice` passed for a capture of type `cpp`, if it is a
+/// `std::span`. The layout of a `std::span` is not specified, so the span is
+/// constructed in the generated function from the pointer and the length.
+fn span_capture_slice(cpp: &str) -> Option<String> {
+    if cpp.trim_start().starts_with("std::span<") {
+        Some(format!("rustcpp::Slice<{}::element_type>", cpp))
+    } else {
+        None
+    }
+}
 
-    write!(output, "{}", INTERNAL_CPP_STRUCTS).unwrap();
+/// The directory holding the intermediate files of the library `lib_name`, in
+/// `OUT_DIR` unless [`Config::intermediate_dir`] is set
+fn cpp_dir(intermediate_dir: Option<&Path>, lib_name: &str) -> PathBuf {
+    let base = intermediate_dir.unwrap_or(&OUT_DIR);
+    if lib_name == DEFAULT_LIB_NAME {
+        base.join("rust_cpp")
+    } else {
+        base.join(format!("rust_cpp_{}", lib_name))
+    }
+}
+
+fn gen_cpp_lib(visitor: &parser::Parser, cpp_dir: &Path, config: &Config) -> io::Result<PathBuf> {
+    let result_path = cpp_dir.join("cpp_closures.cpp");
+    let mut output =
+        File::create(&result_path).map_err(io_context("Unable to generate temporary C++ file"))?;
+
+    // The order is: the prepended snippets, the internal structures, the callbacks array, the
+    // cpp!{{ }} snippets in the order they are parsed, the snippet files, then the code of each
+    // closure and class, the metadata and finally the appended code.
+    write!(output, "{}", config.source_definitions())?;
+    write!(output, "{}", config.prepended_snippets)?;
+    if config.hidden_visibility {
+        write!(output, "{}", HIDDEN_VISIBILITY_EXPORT)?;
+    }
+    if config.freestanding {
+        write!(output, "{}", FREESTANDING_DEFINE)?;
+    }
+    write!(output, "{}", INTERNAL_CPP_STRUCTS)?;
+    write_metadata_types(&mut output)?;
+    let prelude_end = output.stream_position()? as usize;
 
     if visitor.callbacks_count > 0 {
         #[rustfmt::skip]
         write_add_line!(output, r#"
 extern "C" {{
-    void (*rust_cpp_callbacks{file_hash}[{callbacks_count}])() = {{}};
+    RUST_CPP_EXPORT void (*rust_cpp_callbacks{lib_hash}[{callbacks_count}])() = {{}};
 }}
         "#,
-            file_hash = *FILE_HASH,
+            lib_hash = visitor.lib_hash,
             callbacks_count = visitor.callbacks_count
-        ).unwrap();
+        )?;
     }
 
-    write!(output, "{}\n\n", &visitor.snippets).unwrap();
+    for snippet in &visitor.snippets {
+        write!(output, "\n{}", snippet)?;
+    }
+    write!(output, "\n\n")?;
+
+    for path in &config.snippet_files {
+        println!("cargo:rerun-if-changed={}", path.display());
+        let mut content = String::new();
+        File::open(path).and_then(|mut f| f.read_to_string(&mut content)).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to read the snippet file {}: {}", path.display(), e),
+            )
+        })?;
+        write!(
+            output,
+            "#line 1 \"{}\"\n{}\n\n",
+            escape_cpp_string(&path.to_string_lossy()),
+            content
+        )?;
+    }
+    let snippets_end = output.stream_position()? as usize;
 
-    let mut hashmap = HashMap::new();
+    let mut hashmap = HashMap::<u128, &Closure>::new();
 
     let mut sizealign = vec![];
-    for Closure { body_str, sig, callback_offset, .. } in &visitor.closures {
+    for closure in &visitor.closures {
+        let Closure { body_str, sig, callback_offset, location, .. } = closure;
         let ClosureSig { captures, cpp, .. } = sig;
 
         let hash = sig.name_hash();
         let name = sig.extern_name();
+        let metadata_hash = metadata_hash(hash);
 
         match hashmap.entry(hash) {
             Entry::Occupied(e) => {
-                if *e.get() != sig {
+                let first = *e.get();
+                if first.sig != *sig {
                     // Let the compiler do a compilation error. FIXME: report a better error
                     warnln!("Hash collision detected.");
                 } else {
+                    if config.warn_duplicate_closures {
+                        warnln!(
+                            r#"-- rust-cpp warning --
+The cpp! closures at {} and {} are identical and share the same generated function `{}`.
+NOTE: `static` variables declared in their body are shared."#,
+                            first.location,
+                            location,
+                            name
+                        );
+                    }
                     continue;
                 }
             }
             Entry::Vacant(e) => {
-                e.insert(sig);
+                e.insert(closure);
             }
         }
 
+        for include in &sig.includes {
+            writeln!(output, "#include {}", include)?;
+        }
+        write!(output, "{}", incomplete_type_checks(closure))?;
+        write!(output, "{}", primitive_type_checks(closure, config.strict_types))?;
+        if config.strict_types {
+            write!(output, "{}", strict_type_checks(closure, &visitor.classes))?;
+        }
+
         let is_void = cpp == "void";
+        let noexcept = if sig.noexcept { " noexcept" } else { "" };
+        let (try_, catch) = if config.catch_exceptions && !sig.noexcept {
+            ("RUST_CPP_TRY", "RUST_CPP_CATCH")
+        } else {
+            ("", "")
+        };
+        let wrapper = sig.return_wrapper();
+        // The type of the value which is passed to Rust
+        let ret_cpp = match &wrapper {
+            Some(ReturnWrapper::Optional { cpp, .. })
+            | Some(ReturnWrapper::Expected { cpp, .. }) => cpp,
+            None => cpp,
+        };
 
         // Generate the sizes array with the sizes of each of the argument types
         if is_void {
             sizealign.push(format!(
-                "{{{hash}ull, 0, 1, {callback_offset}ull << 32}}",
-                hash = hash,
+                "{{{hash}, 0, 1, {callback_offset}ull << 32}}",
+                hash = metadata_hash,
                 callback_offset = callback_offset
             ));
         } else {
+            // The last segment of the path of an enum deriving CppEnum
+            let checked_enum = match &sig.ret {
+                Some(syn::Type::Path(p)) if wrapper.is_none() => matches!(
+                    p.path.segments.last(),
+                    Some(s) if visitor.checked_enums.iter().any(|e| s.ident == e)
+                ),
+                _ => false,
+            };
+            let checked_enum = if checked_enum {
+                format!(" | (1ull << {})", flags::IS_CHECKED_ENUM)
+            } else {
+                String::new()
+            };
+            sizealign.push(format!("{{
+                {hash},
+                sizeof({type}),
+                rustcpp::AlignOf<{type}>::value,
+                rustcpp::Flags<{type}>::value{checked_enum} | {callback_offset}ull << 32
+            }}", hash=metadata_hash, type=ret_cpp, checked_enum=checked_enum, callback_offset = callback_offset));
+        }
+        for Capture { cpp, name, .. } in captures {
+            let mut generic = if closure.generic_captures.contains(&name.to_string()) {
+                format!(" | (1ull << {})", flags::IS_GENERIC_CAPTURE)
+            } else {
+                String::new()
+            };
+            if is_wide_char_pointer(cpp) {
+                generic.push_str(" | (uint64_t)sizeof(wchar_t) << 32");
+            }
+            let cpp = span_capture_slice(cpp).unwrap_or_else(|| cpp.clone());
             sizealign.push(format!("{{
-                {hash}ull,
+                {hash},
                 sizeof({type}),
                 rustcpp::AlignOf<{type}>::value,
-                rustcpp::Flags<{type}>::value | {callback_offset}ull << 32
-            }}", hash=hash, type=cpp, callback_offset = callback_offset));
+                rustcpp::Flags<{type}>::value{generic}
+            }}", hash=metadata_hash, type=cpp, generic=generic));
         }
-        for Capture { cpp, .. } in captures {
+        // The error of std::expected comes after the captures
+        if let Some(ReturnWrapper::Expected { cpp_error, .. }) = &wrapper {
             sizealign.push(format!("{{
-                {hash}ull,
+                {hash},
                 sizeof({type}),
                 rustcpp::AlignOf<{type}>::value,
                 rustcpp::Flags<{type}>::value
-            }}", hash=hash, type=cpp));
+            }}", hash=metadata_hash, type=cpp_error));
         }
 
-        // Generate the parameters and function declaration
+        // Generate the parameters and function declaration. A std::span is passed as a
+        // rustcpp::Slice named `__cpp_span_{name}`, from which it is constructed.
+        let mut prelude = config.closure_prelude.clone();
         let params = captures
             .iter()
-            .map(|&Capture { mutable, ref name, ref cpp }| {
-                if mutable {
-                    format!("{} & {}", cpp, name)
-                } else {
-                    format!("{} const& {}", cpp, name)
+            .filter(|c| !c.constant)
+            .map(|&Capture { mutable, by_value, ref name, ref cpp, .. }| {
+                let constness = if mutable { "" } else { " const" };
+                if by_value {
+                    // Passed like the pointers to the other captures
+                    prelude.push_str(&format!(
+                        "\nstatic_assert(std::is_pointer<{cpp}>::value, \"the `copy` capture `{name}` \
+                         must have a pointer type\");",
+                        cpp = cpp,
+                        name = name
+                    ));
+                    return format!("{} {}", cpp, name);
+                }
+                match span_capture_slice(cpp) {
+                    Some(slice) => {
+                        prelude.push_str(&format!(
+                            "\n{cpp}{constness} {name}(__cpp_span_{name}.ptr, __cpp_span_{name}.len);",
+                            cpp = cpp,
+                            constness = constness,
+                            name = name
+                        ));
+                        format!("{}{}& __cpp_span_{}", slice, constness, name)
+                    }
+                    None => format!("{}{}& {}", cpp, constness, name),
                 }
             })
             .collect::<Vec<_>>()
@@ -219,40 +906,125 @@ extern "C" {{
             #[rustfmt::skip]
             write_add_line!(output, r#"
 extern "C" {{
-void {name}({params}) {{
+RUST_CPP_EXPORT void {name}({params}){noexcept} {{
+{prelude}
+{try_}
 {body}
+{catch}
 }}
 }}
 "#,
                 name = &name,
+                prelude = prelude,
+                noexcept = noexcept,
+                try_ = try_,
+                catch = catch,
                 params = params,
                 body = body_str
-            ).unwrap();
+            )?;
         } else {
             let comma = if params.is_empty() { "" } else { "," };
             let args = captures
                 .iter()
-                .map(|Capture { name, .. }| name.to_string())
+                .filter(|c| !c.constant)
+                .map(|Capture { name, cpp, .. }| match span_capture_slice(cpp) {
+                    Some(_) => format!("__cpp_span_{}", name),
+                    None => name.to_string(),
+                })
                 .collect::<Vec<_>>()
                 .join(", ");
             #[rustfmt::skip]
             write_add_line!(output, r#"
-static inline {ty} {name}_impl({params}) {{
+static inline {ty} {name}_impl({params}){noexcept} {{
+{prelude}
 {body}
 }}
-extern "C" {{
-void {name}({params}{comma} void* __result) {{
-    ::new(__result) ({ty})({name}_impl({args}));
-}}
-}}
 "#,
                 name = &name,
+                prelude = prelude,
+                noexcept = noexcept,
                 params = params,
-                comma = comma,
                 ty = cpp,
-                args = args,
                 body = body_str
-            ).unwrap();
+            )?;
+            match &wrapper {
+                None => {
+                    #[rustfmt::skip]
+                    write_add_line!(output, r#"
+extern "C" {{
+RUST_CPP_EXPORT void {name}({params}{comma} void* __result){noexcept} {{
+{try_}
+    ::new(__result) ({ty})({name}_impl({args}));
+{catch}
+}}
+}}
+"#,
+                        name = &name,
+                        noexcept = noexcept,
+                        try_ = try_,
+                        catch = catch,
+                        params = params,
+                        comma = comma,
+                        ty = cpp,
+                        args = args
+                    )?;
+                }
+                // Only the value is moved into the result when there is one
+                Some(ReturnWrapper::Optional { cpp: value, .. }) => {
+                    #[rustfmt::skip]
+                    write_add_line!(output, r#"
+extern "C" {{
+RUST_CPP_EXPORT bool {name}({params}{comma} void* __result){noexcept} {{
+{try_}
+    {ty} __r({name}_impl({args}));
+    if (!__r) return false;
+    ::new(__result) ({value})(std::move(*__r));
+    return true;
+{catch}
+}}
+}}
+"#,
+                        name = &name,
+                        noexcept = noexcept,
+                        try_ = try_,
+                        catch = catch,
+                        params = params,
+                        comma = comma,
+                        ty = cpp,
+                        value = value,
+                        args = args
+                    )?;
+                }
+                Some(ReturnWrapper::Expected { cpp: value, cpp_error, .. }) => {
+                    #[rustfmt::skip]
+                    write_add_line!(output, r#"
+extern "C" {{
+RUST_CPP_EXPORT bool {name}({params}{comma} void* __result, void* __error){noexcept} {{
+{try_}
+    {ty} __r({name}_impl({args}));
+    if (!__r) {{
+        ::new(__error) ({error})(std::move(__r.error()));
+        return false;
+    }}
+    ::new(__result) ({value})(std::move(*__r));
+    return true;
+{catch}
+}}
+}}
+"#,
+                        name = &name,
+                        noexcept = noexcept,
+                        try_ = try_,
+                        catch = catch,
+                        params = params,
+                        comma = comma,
+                        ty = cpp,
+                        value = value,
+                        error = cpp_error,
+                        args = args
+                    )?;
+                }
+            }
         }
     }
 
@@ -261,11 +1033,11 @@ void {name}({params}{comma} void* __result) {{
 
         // Generate the sizes array
         sizealign.push(format!("{{
-                {hash}ull,
+                {hash},
                 sizeof({type}),
                 rustcpp::AlignOf<{type}>::value,
                 rustcpp::Flags<{type}>::value
-            }}", hash=hash, type=class.cpp));
+            }}", hash=metadata_hash(hash), type=class.cpp));
 
         // Generate helper function.
         // (this is done in a macro, which right after a #line directing pointing to the location of
@@ -276,29 +1048,212 @@ void {name}({params}{comma} void* __result) {{
             line = class.line,
             hash = hash,
             cpp_name = class.cpp
-        )
-        .unwrap();
+        )?;
+
+        // The constructors of the From implementations, see Class::from_constructors
+        for (i, args) in class.from_constructors().unwrap_or_default().iter().enumerate() {
+            let params = args
+                .iter()
+                .enumerate()
+                .map(|(j, cpp)| format!("{} a{}, ", cpp, j))
+                .collect::<String>();
+            let values = (0..args.len()).map(|j| format!("a{}", j)).collect::<Vec<_>>().join(", ");
+            write!(output,
+                "{line}extern \"C\" RUST_CPP_EXPORT void __cpp_from_{hash}_{i}({params}void *dest) {{ ::new (dest) {name}({values}); }}\n",
+                line = class.line, hash = hash, i = i, params = params, name = class.cpp, values = values)?;
+        }
+
+        // The accessors of the fields, see Class::fields
+        for field in &class.fields {
+            write!(output,
+                "{line}extern \"C\" RUST_CPP_EXPORT void __cpp_get_{hash}_{field}(const {name} *self_, void *dest) {{ ::new (dest) {cpp}(self_->{field}); }}\n",
+                line = class.line, hash = hash, field = field.name, name = class.cpp, cpp = field.cpp)?;
+            write!(output,
+                "{line}extern \"C\" RUST_CPP_EXPORT void __cpp_set_{hash}_{field}({name} *self_, const {cpp} *value) {{ self_->{field} = *value; }}\n",
+                line = class.line, hash = hash, field = field.name, name = class.cpp, cpp = field.cpp)?;
+        }
 
         if class.derives("PartialEq") {
             write!(output,
-                "{line}extern \"C\" bool __cpp_equal_{hash}(const {name} *a, const {name} *b) {{ return *a == *b; }}\n",
-                line = class.line, hash = hash, name = class.cpp).unwrap();
+                "{line}extern \"C\" RUST_CPP_EXPORT bool __cpp_equal_{hash}(const {name} *a, const {name} *b) {{ return *a == *b; }}\n",
+                line = class.line, hash = hash, name = class.cpp)?;
         }
         if class.derives("PartialOrd") {
             write!(output,
-                "{line}extern \"C\" bool __cpp_compare_{hash}(const {name} *a, const {name} *b, int cmp) {{ return rustcpp::compare_helper(*a, *b, cmp); }}\n",
-                line = class.line, hash = hash, name = class.cpp).unwrap();
+                "{line}extern \"C\" RUST_CPP_EXPORT bool __cpp_compare_{hash}(const {name} *a, const {name} *b, int cmp) {{ return rustcpp::compare_helper(*a, *b, cmp); }}\n",
+                line = class.line, hash = hash, name = class.cpp)?;
+        }
+    }
+
+    if config.link_with_pragma() {
+        for lib in &config.link_libs {
+            // The kind and the modifiers of the cargo directive are not part of the name
+            let name = lib.rsplit('=').next().unwrap_or(lib);
+            writeln!(output, "#pragma comment(lib, \"{}\")", name)?;
         }
     }
 
-    let mut magic = vec![];
-    for mag in STRUCT_METADATA_MAGIC.iter() {
-        magic.push(format!("{}", mag));
+    write_metadata(&mut output, visitor.lib_hash, &sizealign)?;
+    if !config.appended_cpp.is_empty() {
+        // Errors in the appended code are reported in Config::append_cpp
+        write!(output, "#line 1 \"Config::append_cpp\"{}", config.appended_cpp)?;
+    }
+    drop(output);
+
+    // Printed to stderr, where a line starting with `cargo:` is not read as a directive
+    if matches!(env::var(DUMP_ENV).as_deref(), Ok("1")) {
+        let code = std::fs::read_to_string(&result_path)?;
+        eprintln!("-- rust-cpp generated code: {} --\n{}", result_path.display(), code);
+    }
+
+    if let Some(dir) = &config.amalgamation_dir {
+        write_amalgamation(&result_path, prelude_end, dir)?;
+    }
+
+    if config.cpp20_modules {
+        split_module_units(&result_path, prelude_end..snippets_end, visitor.lib_hash)?;
+    }
+    Ok(result_path)
+}
+
+/// Write the generated code in `path` to the files of [`Config::emit_amalgamation`]
+/// in `dir`. The code before `prelude_end` only defines macros and templates, so it
+/// goes to the header, which the source includes before the rest of the code.
+fn write_amalgamation(path: &Path, prelude_end: usize, dir: &Path) -> io::Result<()> {
+    let code = std::fs::read_to_string(path)?;
+    let write = |name: &str, content: String| {
+        create_dir_all(dir).and_then(|_| std::fs::write(dir.join(name), content)).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "Failed to write {} (see Config::emit_amalgamation): {}",
+                    dir.join(name).display(),
+                    e
+                ),
+            )
+        })
+    };
+    write(
+        AMALGAMATION_HEADER,
+        format!(
+            "// Generated by cpp_build {}\n#ifndef RUST_CPP_GENERATED_H\n#define RUST_CPP_GENERATED_H\n{}\n#endif\n",
+            VERSION,
+            &code[..prelude_end]
+        ),
+    )?;
+    write(
+        AMALGAMATION_SOURCE,
+        format!(
+            "// Generated by cpp_build {}\n#include \"{}\"\n{}",
+            VERSION,
+            AMALGAMATION_HEADER,
+            &code[prelude_end..]
+        ),
+    )
+}
+
+/// The name of the C++20 module of the library, see [`Config::cpp20_modules`]
+fn module_name(lib_hash: u64) -> String {
+    format!("rust_cpp_{}", lib_hash)
+}
+
+/// Split the generated code for [`Config::cpp20_modules`]: the code before `snippets`
+/// goes to a header included in the global module fragment of both units, with the
+/// `#include` directives of the snippets. The snippets go to the module interface
+/// unit, and the closures, which come after them, to the module implementation unit
+/// replacing `path`. It implicitly imports the interface, so the snippets do not need
+/// to `export` their declarations.
+fn split_module_units(
+    path: &Path,
+    snippets: std::ops::Range<usize>,
+    lib_hash: u64,
+) -> io::Result<()> {
+    let code = std::fs::read_to_string(path)?;
+    let (includes, declarations): (Vec<&str>, Vec<&str>) =
+        code[snippets.clone()].lines().partition(|l| l.trim_start().starts_with("#include"));
+    let cpp_dir = path.parent().unwrap();
+    let module = module_name(lib_hash);
+    let write = |name: &str, content: String| std::fs::write(cpp_dir.join(name), content);
+    write(MODULE_PRELUDE, format!("{}\n{}\n", &code[..snippets.start], includes.join("\n")))?;
+    write(
+        MODULE_INTERFACE,
+        format!(
+            "module;\n#include \"{}\"\nexport module {};\n{}\n",
+            MODULE_PRELUDE,
+            module,
+            declarations.join("\n")
+        ),
+    )?;
+    std::fs::write(
+        path,
+        format!(
+            "module;\n#include \"{}\"\nmodule {};\n{}",
+            MODULE_PRELUDE,
+            module,
+            &code[snippets.end..]
+        ),
+    )
+}
+
+/// Compile the module interface unit of [`Config::cpp20_modules`] with the
+/// flags of `cc`, then add to `cc` the flags which let the implementation unit
+/// import it, and the object file of the interface.
+fn compile_module_interface(
+    cc: &mut cc::Build,
+    cpp_dir: &Path,
+    lib_hash: u64,
+) -> Result<(), Error> {
+    let compiler = cc.get_compiler();
+    let module = module_name(lib_hash);
+    let interface = cpp_dir.join(MODULE_INTERFACE);
+    let object = cpp_dir.join("cpp_snippets.o");
+    let mut command = compiler.to_command();
+    let flags = if compiler.is_like_clang() {
+        let bmi = cpp_dir.join(format!("{}.pcm", module));
+        command.arg(format!("-fmodule-output={}", bmi.display())).args(["-x", "c++-module"]);
+        vec![format!("-fmodule-file={}={}", module, bmi.display())]
+    } else {
+        // GCC finds the compiled module interface through a module mapper file
+        let mapper = cpp_dir.join("module_mapper");
+        let bmi = cpp_dir.join(format!("{}.gcm", module));
+        std::fs::write(&mapper, format!("{} {}\n", module, bmi.display()))?;
+        let flags =
+            vec!["-fmodules-ts".to_owned(), format!("-fmodule-mapper={}", mapper.display())];
+        command.args(&flags).args(["-x", "c++"]);
+        flags
+    };
+    command.arg("-c").arg(&interface).arg("-o").arg(&object).current_dir(cpp_dir);
+    let output = command
+        .output()
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to run {:?}: {}", command, e)))?;
+    if !output.status.success() {
+        return Err(Error::Compile(CompileError {
+            message: "failed to compile the C++20 module interface (see Config::cpp20_modules)"
+                .to_owned(),
+            command: format!("{:?}", command),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            hint: None,
+            generated: interface,
+        }));
+    }
+    for flag in flags {
+        cc.flag(&flag);
     }
+    cc.object(object);
+    Ok(())
+}
+
+fn metadata_magic() -> String {
+    STRUCT_METADATA_MAGIC.iter().map(|mag| mag.to_string()).collect::<Vec<_>>().join(", ")
+}
 
+/// Write the helpers used to compute the metadata. They must come before any
+/// `rust!` callback, which references a `rustcpp::CallbackMetaData` holding the
+/// size of its argument and return types: these types may be local to a function,
+/// so this metadata is instantiated where the callback is used.
+fn write_metadata_types(output: &mut File) -> io::Result<()> {
     #[rustfmt::skip]
     write_add_line!(output, r#"
-
 namespace rustcpp {{
 
 template<typename T>
@@ -315,6 +1270,7 @@ struct Flags {{
     static const uintptr_t value =
         (std::is_copy_constructible<T>::value << {flag_is_copy_constructible}) |
         (std::is_default_constructible<T>::value << {flag_is_default_constructible}) |
+        (std::is_enum<T>::value << {flag_is_enum}) |
 #if !defined(__GNUC__) || (__GNUC__ + 0 >= 5) || defined(__clang__)
         (std::is_trivially_destructible<T>::value << {flag_is_trivially_destructible}) |
         (std::is_trivially_copyable<T>::value << {flag_is_trivially_copyable}) |
@@ -324,21 +1280,84 @@ struct Flags {{
 }};
 
 struct SizeAlign {{
-    uint64_t hash;
+    uint64_t hash_high;
+    uint64_t hash_low;
     uint64_t size;
     uint64_t align;
     uint64_t flags;
 }};
 
+#if defined(__GNUC__) || defined(__clang__)
+#define RUST_CPP_USED __attribute__((used))
+#else
+#define RUST_CPP_USED
+#endif
+
+// The entries are the return type, if any, followed by the argument types of a rust! callback
+template<uint64_t HashHigh, uint64_t HashLow, typename... T>
+struct CallbackMetaData {{
+    struct Data {{
+        uint8_t magic[128];
+        uint8_t version[16];
+        uint64_t endianness_check;
+        uint64_t length;
+        SizeAlign data[sizeof...(T)];
+    }};
+    static const Data data;
+}};
+
+template<uint64_t HashHigh, uint64_t HashLow, typename... T>
+RUST_CPP_USED const typename CallbackMetaData<HashHigh, HashLow, T...>::Data CallbackMetaData<HashHigh, HashLow, T...>::data = {{
+    {{ {magic} }},
+    "{version}",
+    0xffef,
+    sizeof...(T),
+    {{ {{
+        HashHigh,
+        HashLow,
+        sizeof(typename callback_type<T>::type),
+        AlignOf<typename callback_type<T>::type>::value,
+        Flags<typename callback_type<T>::type>::value
+    }}... }}
+}};
+
+}} // namespace rustcpp
+"#,
+        magic = metadata_magic(),
+        version = VERSION,
+        flag_is_copy_constructible = flags::IS_COPY_CONSTRUCTIBLE,
+        flag_is_default_constructible = flags::IS_DEFAULT_CONSTRUCTIBLE,
+        flag_is_trivially_destructible = flags::IS_TRIVIALLY_DESTRUCTIBLE,
+        flag_is_trivially_copyable = flags::IS_TRIVIALLY_COPYABLE,
+        flag_is_trivially_default_constructible = flags::IS_TRIVIALLY_DEFAULT_CONSTRUCTIBLE,
+        flag_is_enum = flags::IS_ENUM,
+    )?;
+    Ok(())
+}
+
+/// The 128 bit `hash` of a metadata entry, written as its high and low 64 bit halves
+fn metadata_hash(hash: u128) -> String {
+    format!("{}ull, {}ull", hash >> 64, hash as u64)
+}
+
+/// Write the `rustcpp::MetaData` structure with the given entries, which are
+/// found by `cpp_macros` (or `probe_type`) in the compiled library by looking
+/// for `STRUCT_METADATA_MAGIC`.
+fn write_metadata(output: &mut File, hash: u64, sizealign: &[String]) -> io::Result<()> {
+    #[rustfmt::skip]
+    write_add_line!(output, r#"
+
+namespace rustcpp {{
+
 struct MetaData {{
     uint8_t magic[128];
     uint8_t version[16];
     uint64_t endianness_check;
     uint64_t length;
-    SizeAlign data[{length}];
+    SizeAlign data[{capacity}];
 }};
 
-MetaData metadata_{hash} = {{
+RUST_CPP_EXPORT MetaData metadata_{hash} = {{
     {{ {magic} }},
     "{version}",
     0xffef,
@@ -348,37 +1367,31 @@ MetaData metadata_{hash} = {{
 
 }} // namespace rustcpp
 "#,
-        hash = *FILE_HASH,
+        hash = hash,
         data = sizealign.join(", "),
         length = sizealign.len(),
-        magic = magic.join(", "),
+        // A crate with only cpp!{{ }} snippets has no metadata, but the magic must be there
+        // for cpp_macros, and an empty array is not valid C++
+        capacity = sizealign.len().max(1),
+        magic = metadata_magic(),
         version = VERSION,
-        flag_is_copy_constructible = flags::IS_COPY_CONSTRUCTIBLE,
-        flag_is_default_constructible = flags::IS_DEFAULT_CONSTRUCTIBLE,
-        flag_is_trivially_destructible = flags::IS_TRIVIALLY_DESTRUCTIBLE,
-        flag_is_trivially_copyable = flags::IS_TRIVIALLY_COPYABLE,
-        flag_is_trivially_default_constructible = flags::IS_TRIVIALLY_DEFAULT_CONSTRUCTIBLE,
-    ).unwrap();
-
-    result_path
+    )?;
+    Ok(())
 }
 
-fn clean_artifacts() {
-    if CPP_DIR.is_dir() {
-        remove_dir_all(&*CPP_DIR).expect(
-            r#"
--- rust-cpp fatal error --
+/// Add the description of what failed to an I/O error, as its message
+fn io_context(what: &'static str) -> impl FnOnce(io::Error) -> io::Error {
+    move |e| io::Error::new(e.kind(), format!("{}: {}", what, e))
+}
 
-Failed to remove existing build artifacts from output directory."#,
-        );
+fn clean_artifacts(cpp_dir: &Path) -> io::Result<()> {
+    if cpp_dir.is_dir() {
+        remove_dir_all(cpp_dir).map_err(io_context(
+            "Failed to remove existing build artifacts from output directory",
+        ))?;
     }
 
-    create_dir(&*CPP_DIR).expect(
-        r#"
--- rust-cpp fatal error --
-
-Failed to create output object directory."#,
-    );
+    create_dir_all(cpp_dir).map_err(io_context("Failed to create output object directory"))
 }
 
 /// This struct is for advanced users of the build script. It allows providing
@@ -392,6 +1405,40 @@ Failed to create output object directory."#,
 pub struct Config {
     cc: cc::Build,
     std_flag_set: bool, // true if the -std flag was specified
+    lib_name: String,
+    warn_duplicate_closures: bool,
+    strict_types: bool,
+    hidden_visibility: bool,
+    dry_run: bool,
+    dry_run_commands: Vec<String>,
+    debug_maps_to_rust: bool,
+    prepended_snippets: String,
+    appended_cpp: String,
+    closure_prelude: String,
+    link_libs: Vec<String>,
+    msvc_link_pragma: bool,
+    forbid_rust_callbacks: bool,
+    cargo_warnings: bool,
+    exception_model: String,
+    keep_generated: bool,
+    dump_symbols: bool,
+    catch_exceptions: bool,
+    coverage: bool,
+    freestanding: bool,
+    // The include directories and definitions, passed in a response file if there are many
+    includes: Vec<PathBuf>,
+    definitions: Vec<(String, Option<String>)>,
+    // The include directories of the C++ standard library, see Config::stdlib_include
+    stdlib_includes: Vec<PathBuf>,
+    snippet_files: Vec<PathBuf>,
+    rerun_if_changed: Vec<PathBuf>,
+    rerun_if_env_changed: Vec<String>,
+    optional_flags: Vec<String>,
+    dump_preprocessed: bool,
+    cpp20_modules: bool,
+    amalgamation_dir: Option<PathBuf>,
+    intermediate_dir: Option<PathBuf>,
+    output_handler: Option<OutputHandler>,
 }
 
 impl Default for Config {
@@ -401,12 +1448,10 @@ impl Default for Config {
 }
 
 /// Convert from a preconfigured [`cc::Build`] object to [`Config`].
-/// Note that this will ensure C++ is enabled and add the proper include
-/// directories to work with `cpp`.
+/// This is equivalent to [`Config::with_cc`].
 impl From<cc::Build> for Config {
-    fn from(mut cc: cc::Build) -> Self {
-        cc.cpp(true).include(&*CARGO_MANIFEST_DIR);
-        Self { cc, std_flag_set: false }
+    fn from(cc: cc::Build) -> Self {
+        Config::with_cc(cc)
     }
 }
 
@@ -415,27 +1460,701 @@ impl Config {
     /// options which control the build. If you don't need to make any changes,
     /// `cpp_build::build` is a wrapper function around this interface.
     pub fn new() -> Config {
-        cc::Build::new().into()
+        Config::with_cc(cc::Build::new())
     }
 
-    /// Add a directory to the `-I` or include path for headers
-    pub fn include<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
-        self.cc.include(dir);
-        self
+    /// Create a new `Config` object starting from a preconfigured
+    /// [`cc::Build`], for instance one which is shared with the build of some
+    /// C code.
+    ///
+    /// The settings required by `cpp` are added on top of the given object:
+    /// C++ is enabled, the crate directory is added to the include path, and
+    /// the generated C++ file will be added to the files when building.
+    ///
+    /// ```no_run
+    /// let mut cc = cc::Build::new();
+    /// cc.include("vendor/include").define("VENDOR_STATIC", None);
+    /// cc.clone().file("vendor/src/vendor.c").compile("vendor");
+    /// cpp_build::Config::with_cc(cc).build("src/lib.rs");
+    /// ```
+    pub fn with_cc(mut cc: cc::Build) -> Config {
+        cc.cpp(true).include(&*CARGO_MANIFEST_DIR);
+        Config::from_cc(cc)
     }
 
-    /// Specify a `-D` variable with an optional value
-    pub fn define(&mut self, var: &str, val: Option<&str>) -> &mut Self {
-        self.cc.define(var, val);
-        self
+    // The default options, with a compiler which is already set up for C++
+    fn from_cc(cc: cc::Build) -> Config {
+        Config {
+            cc,
+            std_flag_set: false,
+            lib_name: DEFAULT_LIB_NAME.to_owned(),
+            warn_duplicate_closures: false,
+            strict_types: false,
+            hidden_visibility: false,
+            dry_run: false,
+            dry_run_commands: Vec::new(),
+            debug_maps_to_rust: false,
+            prepended_snippets: String::new(),
+            appended_cpp: String::new(),
+            closure_prelude: String::new(),
+            link_libs: Vec::new(),
+            msvc_link_pragma: false,
+            forbid_rust_callbacks: false,
+            cargo_warnings: true,
+            exception_model: "/EHsc".to_owned(),
+            keep_generated: false,
+            dump_symbols: false,
+            catch_exceptions: true,
+            coverage: false,
+            freestanding: false,
+            includes: Vec::new(),
+            stdlib_includes: Vec::new(),
+            definitions: Vec::new(),
+            snippet_files: Vec::new(),
+            rerun_if_changed: Vec::new(),
+            rerun_if_env_changed: Vec::new(),
+            optional_flags: Vec::new(),
+            dump_preprocessed: false,
+            cpp20_modules: false,
+            amalgamation_dir: None,
+            intermediate_dir: None,
+            output_handler: None,
+        }
     }
 
-    // XXX: Make sure that this works with sizes logic
-    /// Add an arbitrary object file to link in
-    pub fn object<P: AsRef<Path>>(&mut self, obj: P) -> &mut Self {
-        self.cc.object(obj);
-        self
-    }
+    /// Set the name of the static library which is built.
+    ///
+    /// The default is `rust_cpp_generated`. Changing it is only useful when
+    /// several libraries are built from the same build script, for instance
+    /// to compile different parts of the crate with different flags:
+    ///
+    /// ```no_run
+    /// cpp_build::Config::new().build("src/lib.rs");
+    /// cpp_build::Config::new()
+    ///     .lib_name("rust_cpp_optimized")
+    ///     .opt_level(3)
+    ///     .build("src/optimized.rs");
+    /// ```
+    ///
+    /// Each call to `build` parses the given crate root and the modules it
+    /// declares, so every `cpp!` and `cpp_class!` macro must be reachable from
+    /// exactly one of the roots. (Modules reached through `include!` are not
+    /// followed, which can be used to exclude a file from the main root.)
+    ///
+    /// `cpp_build` passes the list of built libraries to the macros through the
+    /// `RUST_CPP_LIB_NAMES` environment variable. When expanding a `cpp!`
+    /// closure or a `cpp_class!`, the macros look for its hash in the metadata
+    /// of each library, in the order in which they were built, and use the
+    /// first library which contains it.
+    ///
+    /// The name must only contain ASCII alphanumeric characters and `_`.
+    pub fn lib_name(&mut self, lib_name: &str) -> &mut Self {
+        assert!(
+            !lib_name.is_empty()
+                && lib_name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+            "Invalid rust-cpp library name `{}`: only ASCII alphanumeric characters and `_` are allowed",
+            lib_name
+        );
+        self.lib_name = lib_name.to_owned();
+        self
+    }
+
+    /// Emit a warning when two `cpp!` closures are identical.
+    ///
+    /// Closures made of the same tokens share a single generated C++
+    /// function, so any `static` variable declared in their body is also
+    /// shared between them. This is a size optimization which stays enabled,
+    /// but this option reports the location of both closures when it happens.
+    /// Defaults to `false`.
+    pub fn warn_duplicate_closures(&mut self, warn: bool) -> &mut Self {
+        self.warn_duplicate_closures = warn;
+        self
+    }
+
+    /// Fail the build when the C++ type of a capture or of a return value can't
+    /// be fully checked against the Rust type. The C++ code then fails to compile
+    /// with an error at the location of the `cpp!` macro when:
+    ///  - the type is not standard-layout (for example a class with virtual
+    ///    functions), so only its size and alignment are checked, not the order of
+    ///    its fields. The `cpp_class!` types, and the types which contain them, are
+    ///    opaque to Rust and are accepted.
+    ///  - the type is a C++ type of a Rust primitive, such as `size_t` for `usize`,
+    ///    whose width can't be checked because the pointer width of the target is
+    ///    unknown.
+    ///
+    /// The other checks, such as the sizes of the types, are always errors.
+    /// Defaults to `false`.
+    ///
+    /// ```no_run
+    /// cpp_build::Config::new()
+    ///     .strict_types(true)
+    ///     .build("src/lib.rs");
+    /// ```
+    pub fn strict_types(&mut self, strict: bool) -> &mut Self {
+        self.strict_types = strict;
+        self
+    }
+
+    /// Fail the build if a `rust!` macro is used in a `cpp!` closure or snippet,
+    /// so that the C++ code can't call back into Rust. The error lists the
+    /// location of each `rust!` macro. Defaults to `false`.
+    ///
+    /// ```no_run
+    /// cpp_build::Config::new().forbid_rust_callbacks(true).build("src/lib.rs");
+    /// ```
+    pub fn forbid_rust_callbacks(&mut self, forbid: bool) -> &mut Self {
+        self.forbid_rust_callbacks = forbid;
+        self
+    }
+
+    /// Compile the C++ code with `-fvisibility=hidden`, so that its symbols
+    /// are not exported from a `cdylib` or a shared library.
+    ///
+    /// The functions generated for the `cpp!` and `cpp_class!` macros, the
+    /// `rust!` callbacks and the metadata stay visible (with
+    /// `__attribute__((visibility("default")))`) since the Rust code needs
+    /// them. This has no effect with compilers which don't support the flag,
+    /// such as MSVC. Defaults to `false`.
+    pub fn hidden_visibility(&mut self, hidden: bool) -> &mut Self {
+        self.hidden_visibility = hidden;
+        self
+    }
+
+    /// Only parse the crate and generate the C++ code when building, without
+    /// compiling it. This allows testing a build script in an environment
+    /// without a C++ compiler.
+    ///
+    /// The library is not registered for `cpp_macros`, so the crate itself
+    /// can't use the `cpp!` macros of a library built this way. The commands
+    /// which would have been run are available with
+    /// [`Config::dry_run_commands`]. Defaults to `false`.
+    pub fn dry_run(&mut self, dry_run: bool) -> &mut Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Emit debug information for the C++ code which points to the Rust source
+    /// files, so that a debugger steps through the body of the `cpp!` macros
+    /// in the `.rs` files rather than in the generated C++ file.
+    ///
+    /// The generated code already contains `#line` directives pointing to the
+    /// Rust sources, which the compilers also use for the line table of the
+    /// debug information. This option enables the debug information (`-g`)
+    /// even in release builds, and makes the paths in the `#line` directives
+    /// absolute so that the debugger finds the files regardless of its working
+    /// directory. Defaults to `false`.
+    ///
+    /// To check it, build a test which calls a `cpp!` closure with this option,
+    /// then in `gdb` (or `lldb`), put a breakpoint on the line of the closure
+    /// body (`break src/lib.rs:42`), run the test and step: the debugger shows
+    /// the lines of the `.rs` file. `readelf --debug-dump=decodedline` also
+    /// lists the `.rs` files in the line table of the library.
+    pub fn debug_maps_to_rust(&mut self, debug_maps_to_rust: bool) -> &mut Self {
+        self.debug_maps_to_rust = debug_maps_to_rust;
+        self
+    }
+
+    /// Add some C++ code at the very beginning of the generated file, before
+    /// the internal structures of `cpp` and the headers they include.
+    ///
+    /// This can be used to define macros which affect the standard headers,
+    /// such as feature test macros. The generated file contains, in order:
+    ///  1. the snippets added with this function,
+    ///  2. the internal structures of `cpp`,
+    ///  3. the `cpp!{{ }}` snippets, in the order in which they are parsed,
+    ///  4. the code of the `cpp!` closures and `cpp_class!` macros,
+    ///  5. the metadata read by `cpp_macros`,
+    ///  6. the code added with [`Config::append_cpp`].
+    ///
+    /// ```no_run
+    /// cpp_build::Config::new()
+    ///     .prepend_snippet("#define _GNU_SOURCE 1")
+    ///     .build("src/lib.rs");
+    /// ```
+    pub fn prepend_snippet(&mut self, snippet: &str) -> &mut Self {
+        self.prepended_snippets.push_str(snippet);
+        self.prepended_snippets.push('\n');
+        self
+    }
+
+    /// Add some C++ code at the very end of the generated file, after the code of
+    /// the `cpp!` closures and `cpp_class!` macros and after the metadata array
+    /// read by `cpp_macros`.
+    ///
+    /// The code is in the same translation unit as the snippets and the closures,
+    /// so it can use them, for example in explicit template instantiations. It
+    /// must not define a symbol which is already generated.
+    ///
+    /// ```no_run
+    /// cpp_build::Config::new()
+    ///     .append_cpp("template class MyContainer<int>;")
+    ///     .build("src/lib.rs");
+    /// ```
+    pub fn append_cpp(&mut self, text: &str) -> &mut Self {
+        self.appended_cpp.push('\n');
+        self.appended_cpp.push_str(text);
+        self.appended_cpp.push('\n');
+        self
+    }
+
+    /// Link a library needed by the C++ code to the final binary. The name is
+    /// passed to the `cargo:rustc-link-lib` directive, so it may start with a
+    /// kind, as in `dylib=foo`. Use [`Config::msvc_link_pragma`] to link it with
+    /// a `#pragma comment(lib)` with MSVC instead.
+    ///
+    /// ```no_run
+    /// cpp_build::Config::new()
+    ///     .link_lib("z")
+    ///     .build("src/lib.rs");
+    /// ```
+    pub fn link_lib(&mut self, name: &str) -> &mut Self {
+        self.link_libs.push(name.to_owned());
+        self
+    }
+
+    /// With MSVC, link the libraries of [`Config::link_lib`] with a
+    /// `#pragma comment(lib, "name")` in the generated code, instead of a cargo
+    /// directive. Defaults to `false`.
+    ///
+    /// The cargo directive works with every compiler and takes the search paths of
+    /// `cargo:rustc-link-search` into account. The pragma is recorded in the object
+    /// file, so the library is also linked when the static library built by
+    /// [`Config::compile`] is used outside of cargo, and MSVC looks for it in its
+    /// `LIB` directories.
+    ///
+    /// ```no_run
+    /// cpp_build::Config::new()
+    ///     .link_lib("version")
+    ///     .msvc_link_pragma(true)
+    ///     .build("src/lib.rs");
+    /// ```
+    pub fn msvc_link_pragma(&mut self, pragma: bool) -> &mut Self {
+        self.msvc_link_pragma = pragma;
+        self
+    }
+
+    /// Whether the libraries are linked with a `#pragma comment(lib)`
+    fn link_with_pragma(&self) -> bool {
+        self.msvc_link_pragma && matches!(self.cc.try_get_compiler(), Ok(c) if c.is_like_msvc())
+    }
+
+    /// Add code at the beginning of the body of every `cpp!` closure, such as
+    /// `using` directives or declarations which should not be visible at global
+    /// scope. It is emitted inside the generated function, right after its
+    /// opening brace and before the code of the closure, so it is in the same
+    /// scope as the captures.
+    ///
+    /// ```no_run
+    /// cpp_build::Config::new()
+    ///     .closure_prelude("using namespace std::chrono;")
+    ///     .build("src/lib.rs");
+    /// ```
+    pub fn closure_prelude(&mut self, code: &str) -> &mut Self {
+        self.closure_prelude.push_str(code);
+        self.closure_prelude.push('\n');
+        self
+    }
+
+    /// Add the content of a C++ file, relative to the directory of the crate's
+    /// `Cargo.toml`, as if it was in a `cpp!{{ }}` snippet. This allows keeping
+    /// large C++ code in real files. The files are added after the snippets of
+    /// the crate, in the order of the calls, and the build script is rerun when
+    /// they change. They can't contain `rust!` macros.
+    pub fn add_snippet_file<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.snippet_files.push(CARGO_MANIFEST_DIR.join(path));
+        self
+    }
+
+    /// After building, list the symbols generated by rust-cpp (such as
+    /// `__cpp_closure_*` and `__cpp_destructor_*`) which are defined in the
+    /// library, as cargo warnings. This helps to find out whether a link error
+    /// comes from a missing definition or from a problem with the archive.
+    ///
+    /// The symbols are listed with `dumpbin` on MSVC targets, and with `nm`
+    /// otherwise. The `NM` environment variable can select the `nm` tool, and
+    /// by default the one matching the archiver (such as `llvm-nm` for
+    /// `llvm-ar`) is used. Defaults to `false`.
+    pub fn dump_symbols(&mut self, dump_symbols: bool) -> &mut Self {
+        self.dump_symbols = dump_symbols;
+        self
+    }
+
+    /// Before building, write the generated C++ code as preprocessed by the
+    /// compiler, with the same flags, include directories and definitions, to
+    /// `cpp_closures.i` next to `cpp_closures.cpp`, and print its path as a cargo
+    /// warning. This helps to understand errors coming from the headers and the
+    /// macros. The file is written even if the library then fails to compile.
+    /// Defaults to `false`.
+    pub fn dump_preprocessed(&mut self, dump_preprocessed: bool) -> &mut Self {
+        self.dump_preprocessed = dump_preprocessed;
+        self
+    }
+
+    /// **Experimental:** compile the `cpp!{{ }}` snippets as a C++20 named module
+    /// interface unit, and the closures as a module implementation unit of that
+    /// module, which can be faster for a crate with large snippets. Defaults to
+    /// `false`.
+    ///
+    /// The code before the snippets, and the `#include` directives of the snippets,
+    /// go to a header included in the global module fragment of both units. So the
+    /// snippets must not rely on an `#include` being conditional, and the macros
+    /// they define are not visible in the closures. The code is compiled with
+    /// `-std=c++20` unless another standard is set with [`Config::flag`].
+    ///
+    /// This requires GCC 11 or later (with `-fmodules-ts`), or Clang 16 or later.
+    /// The support of modules in these compilers is still incomplete: for instance
+    /// GCC 12 crashes when both units use `std::string`. With other compilers, a
+    /// warning is printed and the option is ignored.
+    pub fn cpp20_modules(&mut self, cpp20_modules: bool) -> &mut Self {
+        self.cpp20_modules = cpp20_modules;
+        self
+    }
+
+    /// Also write the generated C++ code to `dir`, relative to the directory of the
+    /// crate's `Cargo.toml`, as a self-contained `rust_cpp_generated.cpp` with the
+    /// snippets, the closures and the metadata, and the `rust_cpp_generated.h` header
+    /// it includes, with the internal structures. These files can be checked in and
+    /// compiled by another build system, and the library is still built as usual.
+    /// Use a different directory for each library.
+    ///
+    /// `cpp_macros` reads the metadata from the compiled library named after
+    /// [`Config::lib_name`] in `OUT_DIR`, by looking for its magic bytes, so the
+    /// metadata arrays must be kept as they are. The names of the generated symbols
+    /// depend on the build directory, and the `#line` directives on the paths of the
+    /// parsed files, so the output must be generated again when they change.
+    ///
+    /// ```no_run
+    /// cpp_build::Config::new()
+    ///     .emit_amalgamation("generated")
+    ///     .build("src/lib.rs");
+    /// ```
+    pub fn emit_amalgamation<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.amalgamation_dir = Some(CARGO_MANIFEST_DIR.join(dir));
+        self
+    }
+
+    /// Generate the intermediate files of the library, such as the generated C++
+    /// file, in a subdirectory of `dir` (relative to the directory of the crate's
+    /// `Cargo.toml`) instead of `OUT_DIR`, for example to keep them on a faster
+    /// disk. The subdirectory is removed and created again by each build, but not
+    /// `dir` itself. The object files and the library are still built in `OUT_DIR`,
+    /// where `cpp_macros` reads the metadata.
+    ///
+    /// ```no_run
+    /// cpp_build::Config::new()
+    ///     .intermediate_dir("/mnt/ramdisk/my-crate")
+    ///     .build("src/lib.rs");
+    /// ```
+    pub fn intermediate_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.intermediate_dir = Some(CARGO_MANIFEST_DIR.join(dir));
+        self
+    }
+
+    /// Pass each line of the messages of [`Config::build`] to `handler`, instead
+    /// of printing them as cargo warnings (or to stderr for the errors), to
+    /// integrate them in the logs of another build system.
+    ///
+    /// The diagnostics of the compiler, which the `cc` crate prints as cargo
+    /// warnings, are passed to `handler` too.
+    ///
+    /// ```no_run
+    /// cpp_build::Config::new()
+    ///     .on_compiler_output(Box::new(|line| eprintln!("[c++] {}", line)))
+    ///     .build("src/lib.rs");
+    /// ```
+    pub fn on_compiler_output(&mut self, handler: Box<dyn FnMut(&str) + Send>) -> &mut Self {
+        self.output_handler = Some(handler);
+        self
+    }
+
+    /// Print the warnings of `cpp_build` itself, such as the parse errors, the
+    /// unused captures and the duplicate closures, as cargo warnings. When
+    /// `false`, they are printed to stderr instead, which is only shown in the
+    /// build log (`cargo build -vv`, or when the build fails). Defaults to `true`.
+    ///
+    /// When `false`, the diagnostics of the compiler are printed to stderr too,
+    /// instead of as cargo warnings by the `cc` crate.
+    ///
+    /// ```no_run
+    /// cpp_build::Config::new().cargo_warnings(false).build("src/lib.rs");
+    /// ```
+    pub fn cargo_warnings(&mut self, cargo_warnings: bool) -> &mut Self {
+        self.cargo_warnings = cargo_warnings;
+        self
+    }
+
+    /// Catch the C++ exceptions which escape the body of a `cpp!` closure and
+    /// call `std::terminate`, as unwinding into the Rust code is undefined
+    /// behavior. The closures marked `noexcept` already terminate, so they are
+    /// not wrapped. Nothing is done if the C++ code is compiled without
+    /// exceptions. Defaults to `true`.
+    pub fn catch_exceptions(&mut self, catch_exceptions: bool) -> &mut Self {
+        self.catch_exceptions = catch_exceptions;
+        self
+    }
+
+    /// Set the exception handling model of MSVC, which is passed to the compiler
+    /// of the library and of [`probe_type`]. Defaults to `/EHsc`, without which the
+    /// code using exceptions or the STL gets the warning C4530. An empty string
+    /// passes no flag. This does nothing with other compilers, where exceptions are
+    /// enabled by default.
+    ///
+    /// ```no_run
+    /// cpp_build::Config::new().exception_model("/EHs").build("src/lib.rs");
+    /// ```
+    pub fn exception_model(&mut self, model: &str) -> &mut Self {
+        self.exception_model = model.to_owned();
+        self
+    }
+
+    /// Copy the generated `cpp_closures.cpp` to `OUT_DIR/{lib_name}_generated.cpp`,
+    /// which is not removed by the next build, and print its path as a cargo
+    /// warning. The copy made by the previous build is renamed to
+    /// `{lib_name}_generated.previous.cpp`, so that the two can be compared, for
+    /// instance to check the `#line` directives. Defaults to `false`.
+    ///
+    /// ```no_run
+    /// cpp_build::Config::new().keep_generated(true).build("src/lib.rs");
+    /// ```
+    pub fn keep_generated(&mut self, keep_generated: bool) -> &mut Self {
+        self.keep_generated = keep_generated;
+        self
+    }
+
+    /// Instrument the generated C++ code for coverage, so that the bodies of the
+    /// `cpp!` closures appear in the coverage reports. Defaults to `false`.
+    ///
+    /// With clang, the code is compiled with `-fprofile-instr-generate
+    /// -fcoverage-mapping`, the source based coverage which Rust uses with
+    /// `-C instrument-coverage`. When both are enabled, the program writes a
+    /// single raw profile with the counters of the Rust and the C++ code:
+    ///
+    /// ```text
+    /// RUSTFLAGS="-C instrument-coverage" LLVM_PROFILE_FILE="cov-%p.profraw" cargo test
+    /// llvm-profdata merge -sparse cov-*.profraw -o cov.profdata
+    /// llvm-cov report --instr-profile=cov.profdata --object target/debug/deps/<test binary>
+    /// ```
+    ///
+    /// The version of LLVM used by clang should match the one of rustc, as the
+    /// format of the profiles changes between versions.
+    ///
+    /// With gcc, the code is compiled with `--coverage`, which writes `.gcda`
+    /// files next to the object files in `OUT_DIR`. They are read by `gcov` or
+    /// `lcov`, and their report can be merged with the `lcov` report of the Rust
+    /// code (for instance with `lcov --add-tracefile`).
+    ///
+    /// The flag is also passed to the linker to link the profiling runtime,
+    /// which requires the linker to be a compiler driver, as is the default on
+    /// unix. With clang and `-C instrument-coverage`, the runtime linked by
+    /// rustc is used instead. The probes of [`probe_type`] are not instrumented.
+    /// Coverage is not supported with MSVC, where a warning is emitted.
+    pub fn coverage(&mut self, coverage: bool) -> &mut Self {
+        self.coverage = coverage;
+        self
+    }
+
+    /// Generate code for a freestanding environment, such as a bare-metal target
+    /// without a C runtime. Defaults to `false`.
+    ///
+    /// The internal structures of the generated code then only include the
+    /// headers which are available in a freestanding implementation
+    /// (`<cstddef>`, `<new>`, `<type_traits>`, `<utility>` and `stdint.h`), and
+    /// abort with `__builtin_trap()` instead of `std::abort()`, including in
+    /// `rustcpp::Slice::at`. The sizes of the types are always read from the
+    /// metadata of the compiled library, so nothing has to run on the target.
+    ///
+    /// The code of the closures and the snippets is not changed, and the
+    /// compiler flags (such as `-ffreestanding`) still have to be given.
+    /// The `rust!` callbacks are not available, as they use `std` to register
+    /// themselves. C++ exceptions are only caught if the code is compiled with
+    /// exceptions, which usually need a runtime.
+    pub fn freestanding(&mut self, freestanding: bool) -> &mut Self {
+        self.freestanding = freestanding;
+        self
+    }
+
+    /// Rerun the build script when the file or directory at `path` changes, by
+    /// printing `cargo:rerun-if-changed` during [`Config::build`].
+    ///
+    /// Note that once a build script prints such a directive, Cargo no longer
+    /// reruns it when any other file of the package changes, so the crate root
+    /// and the files containing `cpp!` macros should be listed too.
+    pub fn rerun_if_changed<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.rerun_if_changed.push(path.as_ref().to_owned());
+        self
+    }
+
+    /// Rerun the build script when the environment variable `var` changes, by
+    /// printing `cargo:rerun-if-env-changed` during [`Config::build`].
+    pub fn rerun_if_env_changed(&mut self, var: &str) -> &mut Self {
+        self.rerun_if_env_changed.push(var.to_owned());
+        self
+    }
+
+    /// The command lines which would have compiled the generated C++ code
+    /// during the last call to [`Config::build`] in [`Config::dry_run`] mode.
+    /// Empty if no compiler could be found.
+    pub fn dry_run_commands(&self) -> &[String] {
+        &self.dry_run_commands
+    }
+
+    /// Add a directory to the `-I` or include path for headers
+    ///
+    /// A directory added several times is only passed once, at its first position:
+    /// two paths are the same directory if they are equal once canonicalized.
+    ///
+    /// If the include directories and the definitions (see [`Config::define`])
+    /// would make the command line too long, they are passed to the compiler in
+    /// a response file (`@file`), which GCC, Clang and MSVC all support.
+    pub fn include<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.includes.push(dir.as_ref().to_owned());
+        self
+    }
+
+    /// Add a directory to the include path, relative to the directory of the
+    /// crate's `Cargo.toml`, so it does not depend on where the build is run from
+    ///
+    /// ```no_run
+    /// cpp_build::Config::new().include_from_manifest("src/headers").build("src/lib.rs");
+    /// ```
+    pub fn include_from_manifest<P: AsRef<Path>>(&mut self, relative: P) -> &mut Self {
+        self.includes.push(CARGO_MANIFEST_DIR.join(relative));
+        self
+    }
+
+    /// Add `OUT_DIR` to the include path, so that a header generated there by the
+    /// build script can be included by a `cpp!{{ }}` snippet. The header must be
+    /// written before [`Config::build`] is called. This is the same as
+    /// `include(env::var_os("OUT_DIR").unwrap())`.
+    ///
+    /// ```no_run
+    /// let out_dir = std::path::PathBuf::from(std::env::var_os("OUT_DIR").unwrap());
+    /// std::fs::write(out_dir.join("config.h"), "#define HAS_FEATURE 1\n").unwrap();
+    /// // In src/lib.rs: cpp!{{ #include "config.h" }}
+    /// cpp_build::Config::new().include_out_dir().build("src/lib.rs");
+    /// ```
+    pub fn include_out_dir(&mut self) -> &mut Self {
+        self.includes.push(OUT_DIR.clone());
+        self
+    }
+
+    /// Add the include directory of the C++ standard library, for a toolchain
+    /// or a sysroot where the compiler does not find headers such as `<vector>`
+    /// by default. It is passed with `-isystem` (`/I` with MSVC), so it is searched
+    /// after the directories of [`Config::include`], and it also applies when
+    /// probing a type (see [`probe_type`]). The library to link is set separately
+    /// with [`Config::cpp_link_stdlib`].
+    ///
+    /// The directory is searched before the default directories of the compiler,
+    /// whose standard library is selected with `-stdlib=` on Clang (see
+    /// [`Config::cpp_set_stdlib`]). To only use the headers of this directory,
+    /// also add the `-nostdinc++` flag.
+    ///
+    /// ```no_run
+    /// cpp_build::Config::new()
+    ///     .stdlib_include("/opt/toolchain/include/c++/v1")
+    ///     .cpp_link_stdlib(Some("c++"))
+    ///     .build("src/lib.rs");
+    /// ```
+    pub fn stdlib_include<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.stdlib_includes.push(dir.as_ref().to_owned());
+        self
+    }
+
+    /// Specify a `-D` variable with an optional value
+    ///
+    /// Function-like macros are defined by putting the parameters in the name,
+    /// such as `define("SQUARE(x)", Some("((x) * (x))"))`. The compiler is not
+    /// run through a shell, so no quoting is needed. MSVC does not support
+    /// function-like macros in `/D`, so on that target they are written as a
+    /// `#define` at the beginning of the generated code, before the snippets of
+    /// [`Config::prepend_snippet`].
+    ///
+    /// The definitions also apply when probing a type (see [`probe_type`]), so
+    /// that the sizes of the types are the same as in the library. A definition
+    /// without a value is passed as `-DVAR`, which defines it to `1`.
+    pub fn define(&mut self, var: &str, val: Option<&str>) -> &mut Self {
+        self.definitions.push((var.to_owned(), val.map(str::to_owned)));
+        self
+    }
+
+    /// The function-like macros of [`Config::define`] on MSVC, which are written as
+    /// `#define` at the beginning of the generated code instead of passed with `/D`
+    fn source_definitions(&self) -> String {
+        if env::var("CARGO_CFG_TARGET_ENV").unwrap_or_default() != "msvc" {
+            return String::new();
+        }
+        self.definitions
+            .iter()
+            .filter(|(var, _)| var.contains('('))
+            .map(|(var, val)| format!("#define {} {}\n", var, val.as_deref().unwrap_or("1")))
+            .collect()
+    }
+
+    /// The `cc::Build` which compiles the code, with the include directories and
+    /// the definitions. They are written to `response_file` if they are too long
+    /// for the command line.
+    fn cc_with_args(&self, response_file: &Path) -> io::Result<cc::Build> {
+        let mut cc = self.cc.clone();
+        let msvc_like = matches!(cc.try_get_compiler(), Ok(c) if c.is_like_msvc());
+        if msvc_like && !self.exception_model.is_empty() {
+            cc.flag(&self.exception_model);
+        }
+        for dir in &self.stdlib_includes {
+            if msvc_like {
+                cc.flag(format!("/I{}", dir.display()));
+            } else {
+                cc.flag("-isystem").flag(dir);
+            }
+        }
+        let includes = unique_includes(&self.includes);
+        let msvc = env::var("CARGO_CFG_TARGET_ENV").unwrap_or_default() == "msvc";
+        // See source_definitions
+        let definitions = self
+            .definitions
+            .iter()
+            .filter(|(var, _)| !msvc || !var.contains('('))
+            .collect::<Vec<_>>();
+        let args = includes
+            .iter()
+            .map(|dir| format!("-I{}", dir.display()))
+            .chain(definitions.iter().map(|(var, val)| match val {
+                Some(val) => format!("-D{}={}", var, val),
+                None => format!("-D{}", var),
+            }))
+            .collect::<Vec<_>>();
+        if args.iter().map(|a| a.len() + 1).sum::<usize>() < RESPONSE_FILE_THRESHOLD {
+            for dir in includes {
+                cc.include(dir);
+            }
+            for (var, val) in definitions {
+                cc.define(var, val.as_deref());
+            }
+            return Ok(cc);
+        }
+        let content = args.iter().map(|a| quote_response_file_arg(a, msvc)).collect::<Vec<_>>();
+        std::fs::write(response_file, content.join("\n")).map_err(io_context(
+            "Failed to write the response file with the arguments of the compiler",
+        ))?;
+        cc.flag(format!("@{}", response_file.display()));
+        Ok(cc)
+    }
+
+    /// Set an environment variable for the compiler processes, such as the
+    /// variables read by a compiler wrapper like `ccache` or `distcc`, or by a
+    /// cross toolchain. It also applies when probing a type with this `Config`
+    /// (see [`probe_type`]).
+    pub fn compiler_env<K: AsRef<OsStr>, V: AsRef<OsStr>>(&mut self, key: K, val: V) -> &mut Self {
+        self.cc.env(key, val);
+        self
+    }
+
+    // XXX: Make sure that this works with sizes logic
+    /// Add an arbitrary object file to link in
+    pub fn object<P: AsRef<Path>>(&mut self, obj: P) -> &mut Self {
+        self.cc.object(obj);
+        self
+    }
 
     /// Add an arbitrary flag to the invocation of the compiler
     pub fn flag(&mut self, flag: &str) -> &mut Self {
@@ -446,6 +2165,26 @@ impl Config {
         self
     }
 
+    /// Add flags which are only passed to the compiler if it supports them.
+    ///
+    /// Unlike [`Config::flag_if_supported`], which compiles an empty file, the
+    /// library is compiled with these flags, and compiled again without them if
+    /// that fails because of one of them. This works for flags whose support
+    /// depends on the code or on other flags.
+    ///
+    /// When the compilation fails, the compiler is run again on the generated
+    /// file to capture its diagnostics. The failure is attributed to the flags
+    /// if a line of the output names one of them and contains:
+    /// - `unrecognized command-line option` or `unrecognized argument` (GCC),
+    /// - `unknown argument` or `unsupported option` (Clang),
+    /// - `D9002` or `D8021` (MSVC, which only ignores most unknown options).
+    ///
+    /// Otherwise, the error is reported as usual.
+    pub fn optional_flags(&mut self, flags: Vec<String>) -> &mut Self {
+        self.optional_flags.extend(flags);
+        self
+    }
+
     /// Add an arbitrary flag to the invocation of the compiler if it supports it
     pub fn flag_if_supported(&mut self, flag: &str) -> &mut Self {
         if flag.starts_with("-std=") {
@@ -455,8 +2194,48 @@ impl Config {
         self
     }
 
+    /// Set the C++ standard: one of `"c++11"`, `"c++14"`, `"c++17"`, `"c++20"` or
+    /// `"c++23"`. This passes `-std=c++17`, or `/std:c++17` when targeting MSVC, to the
+    /// compiler of the library and of [`probe_type`]. Defaults to C++11.
+    ///
+    /// MSVC has no option for C++11, which is its default of C++14, and C++23 is
+    /// selected with `/std:c++latest`.
+    ///
+    /// ```no_run
+    /// cpp_build::Config::new().std("c++17").build("src/lib.rs");
+    /// ```
+    pub fn std(&mut self, standard: &str) -> &mut Self {
+        const STANDARDS: &[&str] = &["c++11", "c++14", "c++17", "c++20", "c++23"];
+        assert!(
+            STANDARDS.contains(&standard),
+            "Unknown C++ standard `{}` given to rust-cpp Config::std, expected one of {:?}",
+            standard,
+            STANDARDS
+        );
+        if env::var("CARGO_CFG_TARGET_ENV").unwrap_or_default() == "msvc" {
+            match standard {
+                "c++11" => {}
+                "c++23" => {
+                    self.cc.flag("/std:c++latest");
+                }
+                _ => {
+                    self.cc.flag(format!("/std:{}", standard));
+                }
+            }
+        } else {
+            self.cc.flag(format!("-std={}", standard));
+        }
+        self.std_flag_set = true;
+        self
+    }
+
     // XXX: Make sure this works with sizes logic
     /// Add a file which will be compiled
+    ///
+    /// With the `parallel` feature of `cpp_build`, which enables the `parallel`
+    /// feature of `cc`, these files are compiled concurrently with the generated
+    /// file. The `cpp!` closures and snippets are always in a single translation
+    /// unit, so they are not split between jobs.
     pub fn file<P: AsRef<Path>>(&mut self, p: P) -> &mut Self {
         self.cc.file(p);
         self
@@ -601,6 +2380,96 @@ impl Config {
         self
     }
 
+    /// Compile the library with the flags of [`Config::optional_flags`], or
+    /// without them if the compiler does not support one of them. On success,
+    /// `cc` is the configuration which was used.
+    fn try_compile_with_optional_flags(
+        &self,
+        cc: &mut cc::Build,
+        filename: &Path,
+    ) -> Result<(), cc::Error> {
+        if self.optional_flags.is_empty() {
+            return cc.try_compile(&self.lib_name);
+        }
+        let mut with_flags = cc.clone();
+        for flag in &self.optional_flags {
+            with_flags.flag(flag);
+        }
+        let error = match with_flags.try_compile(&self.lib_name) {
+            Ok(()) => {
+                *cc = with_flags;
+                return Ok(());
+            }
+            Err(e) => e,
+        };
+        if !rejects_flags(&with_flags, filename, &self.optional_flags) {
+            return Err(error);
+        }
+        warnln!(
+            r#"-- rust-cpp warning --
+The compiler does not support one of the optional flags {:?}, the library is built without them."#,
+            self.optional_flags
+        );
+        cc.try_compile(&self.lib_name)
+    }
+
+    /// Add the flags instrumenting the code for coverage, see [`Config::coverage`].
+    /// Returns the linker argument which links the profiling runtime, if needed.
+    fn add_coverage_flags(&mut self) -> Option<&'static str> {
+        let compiler = self.cc.try_get_compiler().ok()?;
+        if compiler.is_like_clang() {
+            self.cc.flag("-fprofile-instr-generate").flag("-fcoverage-mapping");
+            // With -C instrument-coverage, rustc already links its own copy of the runtime
+            let rustflags = env::var("CARGO_ENCODED_RUSTFLAGS").unwrap_or_default();
+            if rustflags.contains("instrument-coverage") {
+                None
+            } else {
+                Some("-fprofile-instr-generate")
+            }
+        } else if compiler.is_like_gnu() {
+            self.cc.flag("--coverage");
+            Some("--coverage")
+        } else {
+            warnln!(
+                r#"-- rust-cpp warning --
+Coverage is not supported with the compiler {}, the C++ code is not instrumented."#,
+                compiler.path().display()
+            );
+            None
+        }
+    }
+
+    /// Ensure C++11 mode is enabled. We rely on some C++11 construct, so we
+    /// must enable C++11 by default.
+    fn ensure_std_flag(&mut self) {
+        // MSVC, GCC >= 5, Clang >= 6 defaults to C++14, but since we want to
+        // supports older compiler which defaults to C++98, we need to
+        // explicitly set the "-std" flag.
+        // Ideally should be done by https://github.com/alexcrichton/cc-rs/issues/191
+        if !self.std_flag_set {
+            // MSVC has no option for C++11, it defaults to C++14
+            let msvc = matches!(self.cc.try_get_compiler(), Ok(c) if c.is_like_msvc());
+            if matches!(self.cc.is_flag_supported("-std=c++11"), Ok(true)) {
+                self.cc.flag("-std=c++11");
+            } else if !msvc {
+                warnln!(
+                    r#"-- rust-cpp warning --
+The compiler does not support -std=c++11. If it defaults to an older standard, pass a -std flag with Config::flag."#
+                );
+            }
+            self.std_flag_set = true;
+        }
+    }
+
+    /// Same as [`Config::build`], but returns the path of the static library
+    /// (such as `librust_cpp_generated.a`, or `rust_cpp_generated.lib` on MSVC),
+    /// which can then be used by further build steps. The library is not
+    /// created in [`Config::dry_run`] mode, or if the crate can't be parsed.
+    pub fn compile<P: AsRef<Path>>(&mut self, crate_root: P) -> PathBuf {
+        self.build(crate_root);
+        lib_path(&self.lib_name)
+    }
+
     /// Extracts `cpp` declarations from the passed-in crate root, and builds
     /// the associated static library to be linked in to the final binary.
     ///
@@ -609,48 +2478,446 @@ impl Config {
     ///
     /// This method may technically be called more than once for ergonomic
     /// reasons, but that usually won't do what you want. Use a different
-    /// `Config` object each time you want to build a crate.
+    /// `Config` object each time you want to build a crate. In order to build
+    /// several libraries from the same build script, give each of them a
+    /// different name with [`Config::lib_name`].
+    ///
+    /// When the `RUST_CPP_DUMP` environment variable is `1`, the generated C++
+    /// code, with its `#line` directives, is printed to the build log, which is
+    /// shown by `cargo build -vv`. Cargo does not rerun the build script when only
+    /// this variable changes, so touch `build.rs` to see the code of a crate which
+    /// was already built.
+    ///
+    /// If the generated C++ code fails to compile, the diagnostics are printed and
+    /// the build script exits with an error. Use [`Config::try_build`] to handle
+    /// this failure instead.
     pub fn build<P: AsRef<Path>>(&mut self, crate_root: P) {
+        self.with_output(|config| match config.build_impl(crate_root.as_ref()) {
+            Ok(()) => {}
+            Err(Error::Parse(err)) => {
+                warnln!(
+                    r#"-- rust-cpp parse error --
+There was an error parsing the crate for the rust-cpp build script:
+{}
+In order to provide a better error message, the build script will exit successfully, such that rustc can provide an error message."#,
+                    err
+                );
+            }
+            Err(Error::Io(e)) => panic!("\n-- rust-cpp fatal error --\n\n{}", e),
+            Err(e @ Error::ForbiddenCallbacks(_)) => {
+                errln!("\n-- rust-cpp fatal error --\n\n{}\n", e);
+                #[cfg(not(feature = "docs-only"))]
+                std::process::exit(1);
+            }
+            Err(Error::Compile(e)) => {
+                errln!("\n\nerror occurred: {}\n\n", e.message);
+                errln!("The generated C++ code was copied to {}\n\n", e.generated.display());
+                if let Some(hint) = &e.hint {
+                    errln!("{}\n\n", hint);
+                }
+                #[cfg(not(feature = "docs-only"))]
+                std::process::exit(1);
+            }
+        })
+    }
+
+    /// Like [`Config::build`], but returns an [`Error`] when the generated C++ code
+    /// fails to compile, instead of exiting. Returns the path of the library, like
+    /// [`Config::compile`].
+    ///
+    /// The crate failing to parse, the `rust!` macros forbidden by
+    /// [`Config::forbid_rust_callbacks`], and the failure to write the generated
+    /// code, are also returned as an [`Error`]. In [`Config::dry_run`] mode, the path of
+    /// the library is returned, but the library is not built.
+    ///
+    /// This lets the build script fall back gracefully, for instance by printing a
+    /// warning and exiting successfully so that rustc reports its own errors.
+    ///
+    /// ```no_run
+    /// if let Err(e) = cpp_build::Config::new().try_build("src/lib.rs") {
+    ///     println!("cargo:warning=the C++ code of the crate failed to compile: {}", e);
+    /// }
+    /// ```
+    pub fn try_build<P: AsRef<Path>>(&mut self, crate_root: P) -> Result<PathBuf, Error> {
+        self.with_output(|config| config.build_impl(crate_root.as_ref()))?;
+        Ok(lib_path(&self.lib_name))
+    }
+
+    // Run f with the output handler and the cargo_warnings option of this Config
+    fn with_output<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        let handler = self.output_handler.take();
+        let previous = OUTPUT_HANDLER.with(|h| h.replace(handler));
+        let previous_warnings = CARGO_WARNINGS.with(|w| w.replace(self.cargo_warnings));
+        let result = f(self);
+        CARGO_WARNINGS.with(|w| w.set(previous_warnings));
+        self.output_handler = OUTPUT_HANDLER.with(|h| h.replace(previous));
+        result
+    }
+
+    fn build_impl(&mut self, crate_root: &Path) -> Result<(), Error> {
         assert_eq!(
             env!("CARGO_PKG_VERSION"),
             VERSION,
             "Internal Error: mismatched cpp_common and cpp_build versions"
         );
 
+        let cpp_dir = cpp_dir(self.intermediate_dir.as_deref(), &self.lib_name);
+
+        // The scan cache of the previous build must be read before it is cleaned up
+        let scan_cache = cpp_dir.join("scan_cache");
+        let cache = parser::ScanCache::load(&scan_cache);
+
         // Clean up any leftover artifacts
-        clean_artifacts();
+        clean_artifacts(&cpp_dir)?;
 
         // Parse the crate
         let mut visitor = parser::Parser::default();
-        if let Err(err) = visitor.parse_crate(crate_root.as_ref().to_owned()) {
+        visitor.lib_hash = lib_hash(&self.lib_name);
+        visitor.cache = cache;
+        let crate_root = if self.debug_maps_to_rust {
+            // The #line directives take the path of the files as they are parsed
+            CARGO_MANIFEST_DIR.join(crate_root)
+        } else {
+            crate_root.to_owned()
+        };
+        visitor.parse_crate(crate_root).map_err(|err| Error::Parse(err.to_string()))?;
+        // The cache only speeds up the next build, so it does not matter if it can't be written
+        drop(visitor.cache.save(&scan_cache));
+        for warning in &visitor.warnings {
+            warnln!("-- rust-cpp warning --\n{}", warning);
+        }
+        if self.forbid_rust_callbacks && !visitor.rust_callbacks.is_empty() {
+            return Err(Error::ForbiddenCallbacks(visitor.rust_callbacks));
+        }
+        if !visitor.errors.is_empty() {
             warnln!(
                 r#"-- rust-cpp parse error --
-There was an error parsing the crate for the rust-cpp build script:
+There were errors parsing the cpp! macros of the crate for the rust-cpp build script:
 {}
-In order to provide a better error message, the build script will exit successfully, such that rustc can provide an error message."#,
-                err
+These macros are skipped, and the library is built with the other ones, such that rustc can provide an error message."#,
+                visitor.errors.join("\n")
             );
-            return;
+        }
+
+        if self.cpp20_modules {
+            match self.cc.try_get_compiler() {
+                Ok(compiler) if compiler.is_like_gnu() || compiler.is_like_clang() => {
+                    if !self.std_flag_set {
+                        self.flag("-std=c++20");
+                    }
+                }
+                _ => {
+                    warnln!("rust-cpp: Config::cpp20_modules is only supported with GCC and Clang, it is ignored");
+                    self.cpp20_modules = false;
+                }
+            }
         }
 
         // Generate the C++ library code
-        let filename = gen_cpp_lib(&visitor);
+        let filename = gen_cpp_lib(&visitor, &cpp_dir, self)?;
+        if self.keep_generated {
+            keep_generated(&filename, &self.lib_name)?;
+        }
 
-        // Ensure C++11 mode is enabled. We rely on some C++11 construct, so we
-        // must enable C++11 by default.
-        // MSVC, GCC >= 5, Clang >= 6 defaults to C++14, but since we want to
-        // supports older compiler which defaults to C++98, we need to
-        // explicitly set the "-std" flag.
-        // Ideally should be done by https://github.com/alexcrichton/cc-rs/issues/191
-        if !self.std_flag_set {
-            self.cc.flag_if_supported("-std=c++11");
+        self.ensure_std_flag();
+        if self.hidden_visibility {
+            self.cc.flag_if_supported("-fvisibility=hidden");
         }
+        if self.debug_maps_to_rust {
+            self.cc.debug(true);
+        }
+        let coverage_link_arg = if self.coverage { self.add_coverage_flags() } else { None };
+
+        let mut cc = self.cc_with_args(&OUT_DIR.join(format!("{}_args.rsp", self.lib_name)))?;
+        // The diagnostics of the compiler go where the messages of cpp_build go
+        if !self.cargo_warnings || OUTPUT_HANDLER.with(|h| h.borrow().is_some()) {
+            cc.cargo_warnings(false).message_logger(Some(std::sync::Arc::new(CcOutput)));
+        }
+
+        if self.dry_run {
+            self.dry_run_commands.clear();
+            self.dry_run_commands.extend(command_line(&cc, &filename));
+            return Ok(());
+        }
+
+        if let Ok(endian) = env::var("CARGO_CFG_TARGET_ENDIAN") {
+            println!("cargo:rustc-env={}={}", TARGET_ENDIAN_ENV, endian);
+        }
+
+        // Link the profiling runtime
+        if let Some(arg) = coverage_link_arg {
+            println!("cargo:rustc-link-arg={}", arg);
+        }
+
+        for path in &self.rerun_if_changed {
+            println!("cargo:rerun-if-changed={}", path.display());
+        }
+        for var in &self.rerun_if_env_changed {
+            println!("cargo:rerun-if-env-changed={}", var);
+        }
+
+        if !self.link_with_pragma() {
+            for lib in &self.link_libs {
+                println!("cargo:rustc-link-lib={}", lib);
+            }
+        }
+
+        // Make sure the linker keeps the imported symbols
+        for import in &visitor.imports {
+            println!("cargo:rustc-link-arg={}", undefined_symbol_link_arg(import));
+        }
+
+        if self.dump_preprocessed {
+            dump_preprocessed(&cc, &filename);
+        }
+
+        if self.cpp20_modules {
+            compile_module_interface(&mut cc, &cpp_dir, visitor.lib_hash)?;
+        }
+
         // Build the C++ library
-        if let Err(e) = self.cc.file(filename).try_compile(LIB_NAME) {
-            let _ = writeln!(std::io::stderr(), "\n\nerror occurred: {}\n\n", e);
-            #[cfg(not(feature = "docs-only"))]
-            std::process::exit(1);
+        cc.file(&filename);
+        if let Err(e) = self.try_compile_with_optional_flags(&mut cc, &filename) {
+            // The output directory is cleaned by the next build, so keep a copy of the code
+            // which failed to compile, where the #line directives can be looked at.
+            let kept = OUT_DIR.join(format!("{}_failed.cpp", self.lib_name));
+            let stderr = compiler_diagnostics(&cc, &filename).unwrap_or_default();
+            return Err(Error::Compile(CompileError {
+                message: e.to_string(),
+                command: command_line(&cc, &filename).unwrap_or_default(),
+                hint: undeclared_type_hint(&visitor.closures, &stderr),
+                stderr,
+                generated: if copy(&filename, &kept).is_ok() { kept } else { filename },
+            }));
+        }
+
+        // Let cpp_macros know in which libraries to look for the metadata. This is done
+        // once the library is built, so that a library which failed to build is not
+        // looked for when the build script ignores the error of Config::try_build.
+        {
+            let mut libs = BUILT_LIBRARIES.lock().unwrap();
+            if !libs.contains(&self.lib_name) {
+                libs.push(self.lib_name.clone());
+            }
+            println!("cargo:rustc-env={}={}", LIB_NAMES_ENV, libs.join(","));
+        }
+
+        write_build_info(&cc, &self.lib_name)?;
+
+        if self.dump_symbols {
+            dump_symbols(&cc, &self.lib_name);
+        }
+        Ok(())
+    }
+}
+
+/// Whether the compiler of `cc` fails because it does not support one of
+/// `flags`, which are part of its arguments. `file` is compiled again to capture
+/// the diagnostics, see [`Config::optional_flags`].
+fn rejects_flags(cc: &cc::Build, file: &Path, flags: &[String]) -> bool {
+    const UNKNOWN_FLAG_MESSAGES: &[&str] = &[
+        "unrecognized command-line option",
+        "unrecognized command line option",
+        "unrecognized argument",
+        "unknown argument",
+        "unsupported option",
+        "D9002",
+        "D8021",
+    ];
+    let output = match compiler_diagnostics(cc, file) {
+        Some(output) => output,
+        None => return false,
+    };
+    output.lines().any(|line| {
+        flags.iter().any(|f| line.contains(f.as_str()))
+            && UNKNOWN_FLAG_MESSAGES.iter().any(|m| line.contains(m))
+    })
+}
+
+/// The command line which compiles `file`, as printed in [`Config::dry_run`] mode
+fn command_line(cc: &cc::Build, file: &Path) -> Option<String> {
+    let compiler = cc.try_get_compiler().ok()?;
+    let mut command = quote_command_arg(&compiler.path().to_string_lossy());
+    for arg in compiler.args() {
+        command.push(' ');
+        command.push_str(&quote_command_arg(&arg.to_string_lossy()));
+    }
+    command.push_str(" -c ");
+    command.push_str(&quote_command_arg(&file.to_string_lossy()));
+    Some(command)
+}
+
+/// Compile `file` again with the arguments of `cc`, and return the output of the
+/// compiler, with its diagnostics.
+fn compiler_diagnostics(cc: &cc::Build, file: &Path) -> Option<String> {
+    let compiler = cc.try_get_compiler().ok()?;
+    let object = OUT_DIR.join("rust_cpp_diagnostics.o");
+    let mut command = compiler.to_command();
+    if compiler.is_like_msvc() {
+        command.arg("/c").arg(file).arg(format!("/Fo{}", object.display()));
+    } else {
+        command.arg("-c").arg(file).arg("-o").arg(&object);
+    }
+    let output = command.output().ok()?;
+    let _ = remove_file(&object);
+    Some(format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    ))
+}
+
+/// Copy the generated code out of the directory cleaned by each build, see
+/// [`Config::keep_generated`]
+fn keep_generated(filename: &Path, lib_name: &str) -> io::Result<()> {
+    let kept = OUT_DIR.join(format!("{}_generated.cpp", lib_name));
+    if kept.is_file() {
+        let previous = OUT_DIR.join(format!("{}_generated.previous.cpp", lib_name));
+        let _ = std::fs::rename(&kept, previous);
+    }
+    copy(filename, &kept).map_err(io_context("Failed to copy the generated C++ code"))?;
+    warnln!("rust-cpp: generated C++ written to {}", kept.display());
+    Ok(())
+}
+
+/// Write the path, the version and the flags of the compiler to
+/// `OUT_DIR/{lib_name}_build_info.rs`, which is included by `cpp::build_info!`
+fn write_build_info(cc: &cc::Build, lib_name: &str) -> io::Result<()> {
+    let (compiler, version, flags) = match cc.try_get_compiler() {
+        Ok(compiler) => {
+            // cl.exe prints its version when it has nothing to compile
+            let mut command = compiler.to_command();
+            if !compiler.is_like_msvc() {
+                command.arg("--version");
+            }
+            let version = command
+                .output()
+                .ok()
+                .and_then(|output| {
+                    let out = [output.stdout, output.stderr];
+                    out.iter().find_map(|o| {
+                        let text = String::from_utf8_lossy(o);
+                        text.lines().map(str::trim).find(|l| !l.is_empty()).map(str::to_owned)
+                    })
+                })
+                .unwrap_or_default();
+            let flags = compiler
+                .args()
+                .iter()
+                .map(|a| a.to_string_lossy().into_owned())
+                .collect::<Vec<_>>();
+            (compiler.path().to_string_lossy().into_owned(), version, flags)
+        }
+        Err(_) => Default::default(),
+    };
+    let path = OUT_DIR.join(format!("{}_build_info.rs", lib_name));
+    let mut output = File::create(path).map_err(io_context("Unable to write the build info"))?;
+    writeln!(output, "({:?}, {:?}, &{:?})", compiler, version, flags)
+}
+
+/// Whether a symbol is one of those generated by rust-cpp. The C symbols may
+/// have a leading `_`, depending on the target.
+fn is_generated_symbol(symbol: &str) -> bool {
+    let bare = symbol.strip_prefix('_').unwrap_or(symbol);
+    [symbol, bare].iter().any(|s| s.starts_with("__cpp_") || s.starts_with("rust_cpp_callbacks"))
+}
+
+/// Write the preprocessed `file` next to it, with the `.i` extension, see
+/// [`Config::dump_preprocessed`]
+fn dump_preprocessed(cc: &cc::Build, file: &Path) {
+    let compiler = match cc.try_get_compiler() {
+        Ok(compiler) => compiler,
+        Err(e) => {
+            warnln!("rust-cpp: could not find the compiler to preprocess the code: {}", e);
+            return;
+        }
+    };
+    let preprocessed = file.with_extension("i");
+    let mut command = compiler.to_command();
+    if compiler.is_like_msvc() {
+        command.arg("/P").arg(format!("/Fi{}", preprocessed.display())).arg(file);
+    } else {
+        command.arg("-E").arg(file).arg("-o").arg(&preprocessed);
+    }
+    match command.output() {
+        Ok(output) if output.status.success() => {
+            warnln!("rust-cpp: the preprocessed C++ code is in {}", preprocessed.display());
+        }
+        Ok(output) => {
+            warnln!(
+                "rust-cpp: {:?} failed to preprocess the code:\n{}",
+                command,
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        Err(e) => {
+            warnln!("rust-cpp: could not run {:?} to preprocess the code: {}", command, e);
+        }
+    }
+}
+
+/// List the symbols generated by rust-cpp which are defined in the library,
+/// see `Config::dump_symbols`
+fn dump_symbols(cc: &cc::Build, lib_name: &str) {
+    let msvc = env::var("CARGO_CFG_TARGET_ENV").unwrap_or_default() == "msvc";
+    let (mut command, lib) = if msvc {
+        let mut command = Command::new("dumpbin");
+        command.arg("/SYMBOLS");
+        (command, lib_path(lib_name))
+    } else {
+        let nm = env::var_os("NM").unwrap_or_else(|| {
+            // Use the nm of the same toolchain as the archiver
+            cc.try_get_archiver()
+                .ok()
+                .map(|ar| ar.get_program().to_string_lossy().into_owned())
+                .and_then(|ar| ar.strip_suffix("ar").map(|prefix| format!("{}nm", prefix)))
+                .unwrap_or_else(|| "nm".to_owned())
+                .into()
+        });
+        (Command::new(nm), lib_path(lib_name))
+    };
+    let output = match command.arg(&lib).output() {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            warnln!(
+                "rust-cpp: {:?} failed to list the symbols of {}:\n{}",
+                command,
+                lib.display(),
+                String::from_utf8_lossy(&output.stderr)
+            );
+            return;
         }
+        Err(e) => {
+            warnln!("rust-cpp: could not run {:?} to list the symbols: {}", command, e);
+            return;
+        }
+    };
+
+    // nm prints `<address> <type> <name>`, where the type `U` means undefined, and
+    // dumpbin prints `... SECTn ... External | <name>`, or `UNDEF` when undefined.
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut symbols = stdout
+        .lines()
+        .filter_map(|line| {
+            if msvc {
+                let (attributes, name) = line.split_once('|')?;
+                (attributes.contains("External") && !attributes.contains("UNDEF"))
+                    .then(|| name.split_whitespace().next())?
+            } else {
+                let fields = line.split_whitespace().collect::<Vec<_>>();
+                match fields[..] {
+                    [_, kind, name] if !kind.eq_ignore_ascii_case("u") => Some(name),
+                    _ => None,
+                }
+            }
+        })
+        .filter(|name| is_generated_symbol(name))
+        .collect::<Vec<_>>();
+    symbols.sort_unstable();
+    symbols.dedup();
+    warnln!("rust-cpp: {} symbols defined in {}:", symbols.len(), lib.display());
+    for symbol in symbols {
+        warnln!("  {}", symbol);
     }
 }
 
@@ -659,3 +2926,270 @@ In order to provide a better error message, the build script will exit successfu
 pub fn build<P: AsRef<Path>>(path: P) {
     Config::new().build(path)
 }
+
+/// The failure of [`Config::try_build`]
+#[derive(Debug)]
+pub enum Error {
+    /// The crate could not be parsed. [`Config::build`] prints a warning and exits
+    /// successfully in this case, so that rustc reports the error.
+    Parse(String),
+    /// The generated C++ code, or another file of the build, could not be written
+    Io(io::Error),
+    /// The generated C++ code failed to compile
+    Compile(CompileError),
+    /// [`Config::forbid_rust_callbacks`] is set, but `rust!` macros are used at these
+    /// locations (`path:line`)
+    ForbiddenCallbacks(Vec<String>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Parse(err) => write!(f, "failed to parse the crate: {}", err),
+            Error::Io(err) => err.fmt(f),
+            Error::Compile(err) => err.fmt(f),
+            Error::ForbiddenCallbacks(sites) => write!(
+                f,
+                "Config::forbid_rust_callbacks is set, but rust! macros are used at:\n{}",
+                sites.join("\n")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Parse(_) | Error::ForbiddenCallbacks(_) => None,
+            Error::Io(err) => Some(err),
+            Error::Compile(err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+/// The failure of the compilation of the generated C++ code, see [`Error::Compile`]
+#[derive(Debug)]
+pub struct CompileError {
+    /// The error reported by `cc`
+    pub message: String,
+    /// The command line of the compiler
+    pub command: String,
+    /// The diagnostics of the compiler, which is run again on the generated code
+    pub stderr: String,
+    /// A copy of the generated C++ code, which is kept by the next build
+    pub generated: PathBuf,
+    /// The explanation of the error, when its cause is a known mistake, such as a
+    /// C++ type of a closure which is not declared at global scope
+    pub hint: Option<String>,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "failed to compile {}: {}", self.generated.display(), self.message)?;
+        if !self.stderr.is_empty() {
+            write!(f, "\n{}", self.stderr.trim_end())?;
+        }
+        if let Some(hint) = &self.hint {
+            write!(f, "\n{}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// The size, alignment and type traits of a C++ type, as computed by the C++
+/// compiler. See [`probe_type`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TypeMetadata {
+    /// `sizeof` the type
+    pub size: usize,
+    /// `alignof` the type
+    pub align: usize,
+    flags: u64,
+}
+
+impl TypeMetadata {
+    fn has_flag(&self, f: u32) -> bool {
+        self.flags & (1 << f) != 0
+    }
+
+    /// `std::is_copy_constructible`
+    pub fn is_copy_constructible(&self) -> bool {
+        self.has_flag(flags::IS_COPY_CONSTRUCTIBLE)
+    }
+
+    /// `std::is_default_constructible`
+    pub fn is_default_constructible(&self) -> bool {
+        self.has_flag(flags::IS_DEFAULT_CONSTRUCTIBLE)
+    }
+
+    /// `std::is_trivially_destructible`
+    ///
+    /// Note: always false with GCC older than 5.
+    pub fn is_trivially_destructible(&self) -> bool {
+        self.has_flag(flags::IS_TRIVIALLY_DESTRUCTIBLE)
+    }
+
+    /// `std::is_trivially_copyable`
+    ///
+    /// Note: always false with GCC older than 5.
+    pub fn is_trivially_copyable(&self) -> bool {
+        self.has_flag(flags::IS_TRIVIALLY_COPYABLE)
+    }
+
+    /// `std::is_trivially_default_constructible`
+    ///
+    /// Note: always false with GCC older than 5.
+    pub fn is_trivially_default_constructible(&self) -> bool {
+        self.has_flag(flags::IS_TRIVIALLY_DEFAULT_CONSTRUCTIBLE)
+    }
+
+    /// `std::is_enum`
+    pub fn is_enum(&self) -> bool {
+        self.has_flag(flags::IS_ENUM)
+    }
+}
+
+/// Compile a small C++ file with the given configuration in order to find out
+/// the size, the alignment and some type traits of `cpp_type`, the same way it
+/// is done for the types used in `cpp!` and `cpp_class!` macros.
+///
+/// This can be used by build scripts which generate some code depending on the
+/// properties of C++ types. The headers declaring the type can be included with
+/// [`probe_type_with_prelude`].
+///
+/// ```no_run
+/// let config = cpp_build::Config::new();
+/// let metadata = cpp_build::probe_type("int64_t", &config);
+/// assert_eq!(metadata.size, 8);
+/// ```
+///
+/// Panics if the C++ code cannot be compiled.
+pub fn probe_type(cpp_type: &str, config: &Config) -> TypeMetadata {
+    probe_type_with_prelude("", cpp_type, config)
+}
+
+/// Like [`probe_type`], but `prelude` is put in the C++ file before probing the
+/// type. It typically contains the `#include` directives needed to declare it.
+///
+/// Each call compiles the prelude again. The types of the `cpp!` and `cpp_class!`
+/// macros are not probed this way: their sizes are in the metadata of the library
+/// built by [`Config::build`], so the headers of the crate are compiled only once,
+/// with exactly the declarations which the closures see.
+///
+/// ```no_run
+/// let config = cpp_build::Config::new();
+/// let metadata = cpp_build::probe_type_with_prelude("#include <string>", "std::string", &config);
+/// assert!(!metadata.is_trivially_copyable());
+/// ```
+pub fn probe_type_with_prelude(prelude: &str, cpp_type: &str, config: &Config) -> TypeMetadata {
+    let hash = {
+        let mut hasher = DefaultHasher::new();
+        prelude.hash(&mut hasher);
+        cpp_type.hash(&mut hasher);
+        hasher.finish()
+    };
+    let probe_dir = OUT_DIR.join("rust_cpp_probe").join(format!("{:x}", hash));
+    create_dir_all(&probe_dir).expect(
+        r#"
+-- rust-cpp fatal error --
+
+Failed to create output object directory."#,
+    );
+
+    let probe_path = probe_dir.join("probe.cpp");
+    {
+        let mut output = File::create(&probe_path).expect("Unable to generate temporary C++ file");
+        write!(output, "{}", config.source_definitions()).unwrap();
+        if config.freestanding {
+            write!(output, "{}", FREESTANDING_DEFINE).unwrap();
+        }
+        write!(output, "{}", INTERNAL_CPP_STRUCTS).unwrap();
+        write_metadata_types(&mut output).unwrap();
+        write!(output, "\n{}\n\n", prelude).unwrap();
+        let sizealign = format!("{{
+                {hash},
+                sizeof({type}),
+                rustcpp::AlignOf<{type}>::value,
+                rustcpp::Flags<{type}>::value
+            }}", hash=metadata_hash(u128::from(hash)), type=cpp_type);
+        write_metadata(&mut output, hash, &[sizealign]).unwrap();
+    }
+
+    // The probe is compiled like the library, without its other options
+    let mut config = Config {
+        std_flag_set: config.std_flag_set,
+        lib_name: "rust_cpp_probe".to_owned(),
+        exception_model: config.exception_model.clone(),
+        freestanding: config.freestanding,
+        includes: config.includes.clone(),
+        stdlib_includes: config.stdlib_includes.clone(),
+        definitions: config.definitions.clone(),
+        ..Config::from_cc(config.cc.clone())
+    };
+    config.ensure_std_flag();
+    let mut cc = config
+        .cc_with_args(&probe_dir.join("args.rsp"))
+        .unwrap_or_else(|e| panic!("\n-- rust-cpp fatal error --\n\n{}", e));
+    cc.file(&probe_path).out_dir(&probe_dir).cargo_metadata(false);
+    if let Err(e) = cc.try_compile(&config.lib_name) {
+        panic!(
+            r#"
+-- rust-cpp fatal error --
+
+Failed to compile the C++ code probing the type `{}`:
+{}"#,
+            cpp_type, e
+        );
+    }
+
+    let lib = [msvc_lib_file_name(&config.lib_name), lib_file_name(&config.lib_name)]
+        .iter()
+        .find_map(|name| std::fs::read(probe_dir.join(name)).ok())
+        .expect(
+            r#"
+-- rust-cpp fatal error --
+
+Failed to open the library probing the type."#,
+        );
+    read_probe_metadata(&lib).expect(
+        r#"
+-- rust-cpp fatal error --
+
+Struct metadata not present in the library probing the type."#,
+    )
+}
+
+/// Read the single entry of the metadata written by `probe_type_with_prelude`
+fn read_probe_metadata(lib: &[u8]) -> Option<TypeMetadata> {
+    let magic = &STRUCT_METADATA_MAGIC[..];
+    let start = lib.windows(magic.len()).position(|w| w == magic)? + magic.len();
+    // Skip the version, which is checked by the assert in `Config::build`
+    let mut words = lib.get(start + 16..)?.chunks_exact(8).map(|x| {
+        let mut word = [0; 8];
+        word.copy_from_slice(x);
+        word
+    });
+    let target_endian = env::var("CARGO_CFG_TARGET_ENDIAN").ok();
+    let big_endian =
+        metadata_is_big_endian(u64::from_le_bytes(words.next()?), target_endian.as_deref());
+    let mut next = move || {
+        words.next().map(|w| if big_endian { u64::from_be_bytes(w) } else { u64::from_le_bytes(w) })
+    };
+    let length = next()?;
+    if length != 1 {
+        return None;
+    }
+    let _hash = (next()?, next()?);
+    let size = next()? as usize;
+    let align = next()? as usize;
+    let flags = next()?;
+    Some(TypeMetadata { size, align, flags })
+}
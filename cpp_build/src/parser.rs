@@ -1,12 +1,21 @@
+use crate::{metadata_hash, TARGET_POINTER_WIDTH};
 use cpp_common::{Class, Closure, Macro, RustInvocation};
 use lazy_static::lazy_static;
+use proc_macro2::TokenTree;
 use regex::Regex;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fmt;
 use std::fs::File;
-use std::io::Read;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
 use std::mem::swap;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use syn::punctuated::Punctuated;
 use syn::visit::Visit;
+use syn::Token;
 
 #[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
@@ -55,70 +64,77 @@ impl From<LexError> for LineError {
 
 enum ExpandSubMacroType<'a> {
     Lit,
-    Closure(&'a mut u32), // the offset
+    Closure(&'a mut u32, u64), // the offset, and the hash of the library
 }
 
 // Given a string containing some C++ code with a rust! macro,
 // this functions expand the rust! macro to a call to an extern
-// function
-fn expand_sub_rust_macro(input: String, mut t: ExpandSubMacroType) -> Result<String, LineError> {
-    let mut result = input;
+// function.
+// The output is built in a single pass over the input, so that snippets with many rust! macros
+// are not copied again for each of them. The lines of the rust! macros are added to `lines`.
+fn expand_sub_rust_macro(
+    input: &str,
+    mut t: ExpandSubMacroType,
+    lines: &mut Vec<u32>,
+) -> Result<String, LineError> {
+    let mut result = String::with_capacity(input.len());
     let mut extra_decl = String::new();
-    let mut search_index = 0;
+    // The part of the input before this offset has already been copied to the result
+    let mut copied = 0;
+    let mut cursor = new_cursor(input);
 
     loop {
-        let (begin, end, line) = {
-            let mut begin = 0;
-            let mut cursor = new_cursor(&result);
-            cursor.advance(search_index);
-            while !cursor.is_empty() {
-                cursor = skip_whitespace(cursor);
-                let r = skip_literal(cursor)?;
-                cursor = r.0;
-                if r.1 {
-                    continue;
-                }
-                if cursor.is_empty() {
-                    break;
-                }
-                if let Ok((cur, ident)) = symbol(cursor) {
-                    begin = cursor.off as usize;
-                    cursor = cur;
-                    if ident != "rust" {
-                        continue;
-                    }
-                } else {
-                    cursor = cursor.advance(1);
-                    continue;
-                }
-                cursor = skip_whitespace(cursor);
-                if !cursor.starts_with("!") {
+        let mut begin = 0;
+        while !cursor.is_empty() {
+            cursor = skip_whitespace(cursor);
+            let r = skip_literal(cursor)?;
+            cursor = r.0;
+            if r.1 {
+                continue;
+            }
+            if cursor.is_empty() {
+                break;
+            }
+            if let Ok((cur, ident)) = symbol(cursor) {
+                begin = cursor.off as usize;
+                cursor = cur;
+                if ident != "rust" {
                     continue;
                 }
-                break;
+            } else {
+                cursor = cursor.advance(1);
+                continue;
             }
-            if cursor.is_empty() {
-                return Ok(extra_decl + &result);
+            cursor = skip_whitespace(cursor);
+            if !cursor.starts_with("!") {
+                continue;
             }
-            let end = find_delimited((find_delimited(cursor, "(")?.0).advance(1), ")")?.0;
-            (begin, end.off as usize + 1, cursor.line)
-        };
-        let input: ::proc_macro2::TokenStream = result[begin..end]
+            break;
+        }
+        if cursor.is_empty() {
+            result.push_str(&input[copied..]);
+            extra_decl.push_str(&result);
+            return Ok(extra_decl);
+        }
+        let line = cursor.line;
+        lines.push(line);
+        cursor = find_delimited((find_delimited(cursor, "(")?.0).advance(1), ")")?.0.advance(1);
+        let end = cursor.off as usize;
+        let tokens: ::proc_macro2::TokenStream = input[begin..end]
             .parse()
             .map_err(|_| LineError(line, "TokenStream parse error".into()))?;
         let rust_invocation =
-            ::syn::parse2::<RustInvocation>(input).map_err(|e| LineError(line, e.to_string()))?;
+            ::syn::parse2::<RustInvocation>(tokens).map_err(|e| LineError(line, e.to_string()))?;
         let fn_name = match t {
             ExpandSubMacroType::Lit => {
                 extra_decl.push_str(&format!("extern \"C\" void {}();\n", rust_invocation.id));
                 rust_invocation.id.clone().to_string()
             }
-            ExpandSubMacroType::Closure(ref mut offset) => {
-                use cpp_common::FILE_HASH;
+            ExpandSubMacroType::Closure(ref mut offset, lib_hash) => {
                 **offset += 1;
                 format!(
-                    "rust_cpp_callbacks{file_hash}[{offset}]",
-                    file_hash = *FILE_HASH,
+                    "rust_cpp_callbacks{lib_hash}[{offset}]",
+                    lib_hash = lib_hash,
                     offset = **offset - 1
                 )
             }
@@ -127,19 +143,26 @@ fn expand_sub_rust_macro(input: String, mut t: ExpandSubMacroType) -> Result<Str
         let mut decl_types = rust_invocation
             .arguments
             .iter()
-            .map(|(_, val)| format!("rustcpp::argument_helper<{}>::type", val))
+            .map(|(_, _, val)| format!("rustcpp::argument_helper<{}>::type", val))
             .collect::<Vec<_>>();
         let mut call_args =
-            rust_invocation.arguments.iter().map(|(val, _)| val.to_string()).collect::<Vec<_>>();
+            rust_invocation.arguments.iter().map(|(val, _, _)| val.to_string()).collect::<Vec<_>>();
 
-        let fn_call = match rust_invocation.return_type {
+        let fn_call = match &rust_invocation.return_type {
             None => format!(
                 "reinterpret_cast<void (*)({types})>({f})({args})",
                 f = fn_name,
                 types = decl_types.join(", "),
                 args = call_args.join(", ")
             ),
-            Some(rty) => {
+            Some((_, rty)) if rust_invocation.return_reference => format!(
+                "reinterpret_cast<{rty}(*)({types})>({f})({args})",
+                rty = rty,
+                f = fn_name,
+                types = decl_types.join(", "),
+                args = call_args.join(", ")
+            ),
+            Some((_, rty)) => {
                 decl_types.push(format!("rustcpp::return_helper<{rty}>", rty = rty));
                 call_args.push("0".to_string());
                 format!(
@@ -152,56 +175,337 @@ fn expand_sub_rust_macro(input: String, mut t: ExpandSubMacroType) -> Result<Str
             }
         };
 
-        let fn_call = {
-            // remove the rust! macro from the C++ snippet
-            let orig = result.drain(begin..end);
-            // add \ņ to the invocation in order to keep the same amount of line numbers
-            // so errors point to the right line.
-            orig.filter(|x| *x == '\n').fold(fn_call, |mut res, _| {
-                res.push('\n');
-                res
-            })
+        // Reference the metadata of the argument and return types, so cpp_macros can check that
+        // they match the Rust types. The comma operator keeps the value category of the call.
+        let types = rust_invocation
+            .return_type
+            .iter()
+            .map(|(_, rty)| rty)
+            .chain(rust_invocation.arguments.iter().map(|(_, _, val)| val))
+            .map(|t| format!(", {}", t))
+            .collect::<String>();
+        let fn_call = if types.is_empty() {
+            fn_call
+        } else {
+            format!(
+                "((void)&rustcpp::CallbackMetaData<{hash}{types}>::data, {call})",
+                hash = metadata_hash(rust_invocation.name_hash()),
+                types = types,
+                call = fn_call
+            )
         };
+
         // add the invocation of call where the rust! macro used to be.
-        result.insert_str(begin, &fn_call);
-        search_index = begin + fn_call.len();
+        result.push_str(&input[copied..begin]);
+        result.push_str(&fn_call);
+        // add \n to the invocation in order to keep the same amount of line numbers
+        // so errors point to the right line.
+        result.extend(input[begin..end].matches('\n'));
+        copied = end;
     }
 }
 
 #[test]
 fn test_expand_sub_rust_macro() {
-    let x = expand_sub_rust_macro("{ rust!(xxx [] { 1 }); }".to_owned(), ExpandSubMacroType::Lit);
+    let x =
+        expand_sub_rust_macro("{ rust!(xxx [] { 1 }); }", ExpandSubMacroType::Lit, &mut Vec::new());
     assert_eq!(x.unwrap(), "extern \"C\" void xxx();\n{ reinterpret_cast<void (*)()>(xxx)(); }");
 
     let x = expand_sub_rust_macro(
-        "{ hello( rust!(xxx [] { 1 }), rust!(yyy [] { 2 }); ) }".to_owned(),
+        "{ hello( rust!(xxx [] { 1 }), rust!(yyy [] { 2 }); ) }",
         ExpandSubMacroType::Lit,
+        &mut Vec::new(),
     );
     assert_eq!(x.unwrap(), "extern \"C\" void xxx();\nextern \"C\" void yyy();\n{ hello( reinterpret_cast<void (*)()>(xxx)(), reinterpret_cast<void (*)()>(yyy)(); ) }");
 
-    let s = "{ /* rust! */  /* rust!(xxx [] { 1 }) */ }".to_owned();
-    assert_eq!(expand_sub_rust_macro(s.clone(), ExpandSubMacroType::Lit).unwrap(), s);
+    let s = "{ /* rust! */  /* rust!(xxx [] { 1 }) */ }";
+    assert_eq!(expand_sub_rust_macro(s, ExpandSubMacroType::Lit, &mut Vec::new()).unwrap(), s);
+
+    // The metadata of the types is referenced, return type first
+    let x = expand_sub_rust_macro(
+        r#"{ rust!(xxx [a : i32 as "int"] -> f64 as "double" { 1.0 }); }"#,
+        ExpandSubMacroType::Lit,
+        &mut Vec::new(),
+    )
+    .unwrap();
+    assert!(x.contains("rustcpp::CallbackMetaData<"), "{}", x);
+    assert!(x.contains("ull, double, int>::data, std::move("), "{}", x);
+
+    // C++ only escape sequences before the rust! macro
+    let x = expand_sub_rust_macro(
+        r#"{ puts("\033[0m\e[1m"); rust!(xxx [] { 1 }); }"#,
+        ExpandSubMacroType::Lit,
+        &mut Vec::new(),
+    );
+    assert_eq!(
+        x.unwrap(),
+        r#"extern "C" void xxx();
+{ puts("\033[0m\e[1m"); reinterpret_cast<void (*)()>(xxx)(); }"#
+    );
+}
+
+#[test]
+fn test_expand_many_rust_macros() {
+    // Large generated snippets can contain thousands of rust! macros: they must all be expanded
+    // in a single pass, keeping the line numbers.
+    const COUNT: u32 = 5000;
+    let input = (0..COUNT)
+        .map(|i| format!("int f{i}(int a) {{\n  return rust!(xxx{i} [a : i32 as \"int\"]\n    -> i32 as \"int\" {{ a }});\n}}\n", i = i))
+        .collect::<String>();
+    let mut offset = 0;
+    let mut lines = Vec::new();
+    let x = expand_sub_rust_macro(&input, ExpandSubMacroType::Closure(&mut offset, 42), &mut lines)
+        .unwrap();
+    assert_eq!(offset, COUNT);
+    assert_eq!(lines, (0..COUNT).map(|i| 4 * i + 1).collect::<Vec<_>>());
+    assert!(!x.contains("rust!"));
+    assert_eq!(x.lines().count(), input.lines().count());
+    // Each macro is replaced on its own line, by the call of its callback
+    for (i, line) in x.lines().skip(1).step_by(4).enumerate() {
+        assert!(line.contains(&format!("rust_cpp_callbacks42[{}]", i)), "{}", line);
+    }
+}
+
+// Given a string containing some C++ code, replace the rust_const!(NAME) macros by the value of
+// the constant, as returned by `value`.
+fn expand_rust_const<F>(input: &str, mut value: F) -> Result<String, LineError>
+where
+    F: FnMut(&str) -> Result<String, String>,
+{
+    let mut result = String::new();
+    let mut cursor = new_cursor(input);
+    let mut copied = 0;
+    while !cursor.is_empty() {
+        cursor = skip_whitespace(cursor);
+        let r = skip_literal(cursor)?;
+        cursor = r.0;
+        if r.1 || cursor.is_empty() {
+            continue;
+        }
+        let begin = cursor.off as usize;
+        let (cur, ident) = match symbol(cursor) {
+            Ok(x) => x,
+            Err(_) => {
+                cursor = cursor.advance(1);
+                continue;
+            }
+        };
+        cursor = cur;
+        if ident != "rust_const" {
+            continue;
+        }
+        let bang = skip_whitespace(cursor);
+        if !bang.starts_with("!") {
+            continue;
+        }
+        let open = skip_whitespace(bang.advance(1));
+        if !open.starts_with("(") {
+            continue;
+        }
+        let close = find_delimited(open.advance(1), ")")?.0;
+        let name = input[open.off as usize + 1..close.off as usize].trim();
+        let value = value(name).map_err(|e| LineError(cursor.line, e))?;
+        result.push_str(&input[copied..begin]);
+        result.push_str(&value);
+        // keep the same amount of lines so errors point to the right line.
+        result.extend(input[begin..close.off as usize].matches('\n'));
+        cursor = close.advance(1);
+        copied = cursor.off as usize;
+    }
+    result.push_str(&input[copied..]);
+    Ok(result)
+}
+
+#[test]
+fn test_expand_rust_const() {
+    let value = |name: &str| match name {
+        "N" => Ok("42".to_owned()),
+        _ => Err(format!("unknown {}", name)),
+    };
+    let x = expand_rust_const("{ int buf[rust_const!(N)]; }", value);
+    assert_eq!(x.unwrap(), "{ int buf[42]; }");
+
+    let x = expand_rust_const("{ int a = rust_const! (\n N\n) + 1; // rust_const!(X)\n}", value);
+    assert_eq!(x.unwrap(), "{ int a = 42\n\n + 1; // rust_const!(X)\n}");
+
+    let s = r#"{ puts("rust_const!(X)"); /* rust_const!(X) */ rust_const; }"#;
+    assert_eq!(expand_rust_const(s, value).unwrap(), s);
+
+    assert!(expand_rust_const("rust_const!(X)", value).is_err());
+}
+
+// Find the name of the functions declared with the rustcpp_import marker in a cpp! snippet.
+// The name is the identifier right before the parameter list.
+fn find_imports(input: &str) -> Result<Vec<String>, LineError> {
+    lazy_static! {
+        static ref NAME: Regex = Regex::new(r"([A-Za-z_][A-Za-z0-9_]*)\s*$").unwrap();
+    }
+    let mut imports = Vec::new();
+    let mut cursor = new_cursor(input);
+    while !cursor.is_empty() {
+        cursor = skip_whitespace(cursor);
+        let r = skip_literal(cursor)?;
+        cursor = r.0;
+        if r.1 || cursor.is_empty() {
+            continue;
+        }
+        let (cur, ident) = match symbol(cursor) {
+            Ok(x) => x,
+            Err(_) => {
+                cursor = cursor.advance(1);
+                continue;
+            }
+        };
+        cursor = cur;
+        if ident != "rustcpp_import" {
+            continue;
+        }
+        let paren = find_delimited(cursor, "(")?.0;
+        let decl = &input[cursor.off as usize..paren.off as usize];
+        let name = NAME.captures(decl).ok_or_else(|| {
+            LineError(cursor.line, "expected a function declaration after rustcpp_import".into())
+        })?;
+        imports.push(name[1].to_owned());
+        cursor = paren;
+    }
+    Ok(imports)
+}
+
+#[test]
+fn test_find_imports() {
+    let x = find_imports(
+        r#"extern "C" rustcpp_import void foo(); /* rustcpp_import bar() */
+        extern "C" rustcpp_import const char * baz (int x, int (*f)());
+        const char *s = "rustcpp_import qux()";"#,
+    );
+    assert_eq!(x.unwrap(), vec!["foo".to_owned(), "baz".to_owned()]);
+    assert!(find_imports("rustcpp_import int x;").is_err());
+}
+
+/// Evaluate an integer constant expression made of integer literals, references to other
+/// constants, casts, and arithmetic operators.
+fn eval_const_expr(
+    expr: &syn::Expr,
+    consts: &HashMap<String, Option<syn::Expr>>,
+    depth: u32,
+) -> Result<i128, String> {
+    use syn::{BinOp, Expr, UnOp};
+    if depth > 64 {
+        return Err("recursion limit reached while evaluating the constant".into());
+    }
+    let overflow = || "overflow while evaluating the constant".to_owned();
+    let eval = |e: &Expr| eval_const_expr(e, consts, depth + 1);
+    match expr {
+        Expr::Lit(syn::ExprLit { lit: syn::Lit::Int(i), .. }) => {
+            i.base10_parse::<i128>().map_err(|e| e.to_string())
+        }
+        Expr::Path(p) if p.qself.is_none() => {
+            let name = p.path.segments.last().map(|x| x.ident.to_string()).unwrap_or_default();
+            eval_const(&name, consts, depth + 1)
+        }
+        Expr::Paren(syn::ExprParen { expr, .. }) | Expr::Group(syn::ExprGroup { expr, .. }) => {
+            eval(expr)
+        }
+        Expr::Cast(syn::ExprCast { expr, ty, .. }) => cast_integer(eval(expr)?, ty),
+        Expr::Unary(syn::ExprUnary { op: UnOp::Neg(_), expr, .. }) => {
+            eval(expr)?.checked_neg().ok_or_else(overflow)
+        }
+        Expr::Binary(syn::ExprBinary { left, op, right, .. }) => {
+            let (l, r) = (eval(left)?, eval(right)?);
+            match op {
+                BinOp::Add(_) => l.checked_add(r),
+                BinOp::Sub(_) => l.checked_sub(r),
+                BinOp::Mul(_) => l.checked_mul(r),
+                BinOp::Div(_) => l.checked_div(r),
+                BinOp::Rem(_) => l.checked_rem(r),
+                BinOp::Shl(_) => u32::try_from(r).ok().and_then(|r| l.checked_shl(r)),
+                BinOp::Shr(_) => u32::try_from(r).ok().and_then(|r| l.checked_shr(r)),
+                BinOp::BitAnd(_) => Some(l & r),
+                BinOp::BitOr(_) => Some(l | r),
+                BinOp::BitXor(_) => Some(l ^ r),
+                _ => return Err("unsupported operator in the constant expression".into()),
+            }
+            .ok_or_else(overflow)
+        }
+        _ => Err("the value of the constant is not an integer constant expression".into()),
+    }
+}
+
+/// Convert the value to the integer type of an `as` cast: it is truncated or
+/// sign-extended like Rust does.
+fn cast_integer(value: i128, ty: &syn::Type) -> Result<i128, String> {
+    let name = match ty {
+        syn::Type::Path(p) if p.qself.is_none() => p.path.get_ident().map(|i| i.to_string()),
+        _ => None,
+    };
+    let name = name.ok_or("unsupported cast in the constant expression")?;
+    let unsupported = || format!("unsupported cast to `{}` in the constant expression", name);
+    let (signed, width) = match (name.strip_prefix('i'), name.strip_prefix('u')) {
+        (Some(width), _) => (true, width),
+        (_, Some(width)) => (false, width),
+        _ => return Err(unsupported()),
+    };
+    let bits = match width {
+        "8" | "16" | "32" | "64" => width.parse().unwrap(),
+        "size" => {
+            TARGET_POINTER_WIDTH.ok_or_else(|| format!("the width of `{}` is not known", name))?
+        }
+        "128" if signed || value >= 0 => return Ok(value),
+        "128" => return Err(format!("`{}` as u128 does not fit in an i128", value)),
+        _ => return Err(unsupported()),
+    };
+    let truncated = value & ((1i128 << bits) - 1);
+    if signed && truncated >= 1 << (bits - 1) {
+        Ok(truncated - (1 << bits))
+    } else {
+        Ok(truncated)
+    }
+}
+
+#[test]
+fn test_cast_integer() {
+    let eval = |s: &str| eval_const_expr(&syn::parse_str(s).unwrap(), &HashMap::new(), 0);
+    assert_eq!(eval("300 as u8"), Ok(44));
+    assert_eq!(eval("-1i32 as u32"), Ok(0xffff_ffff));
+    assert_eq!(eval("200 as i8"), Ok(-56));
+    assert_eq!(eval("(1 << 40) as i32 + 1"), Ok(1));
+    assert_eq!(eval("-5 as i128"), Ok(-5));
+    assert!(eval("-1 as u128").is_err());
+    assert!(eval("1 as f32").is_err());
+}
+
+fn eval_const(
+    name: &str,
+    consts: &HashMap<String, Option<syn::Expr>>,
+    depth: u32,
+) -> Result<i128, String> {
+    match consts.get(name) {
+        Some(Some(expr)) => eval_const_expr(expr, consts, depth)
+            .map_err(|e| format!("cannot evaluate the constant `{}`: {}", name, e)),
+        Some(None) => Err(format!("the constant `{}` is defined several times", name)),
+        None => Err(format!("no constant named `{}` was found in the crate", name)),
+    }
 }
 
-#[path = "strnom.rs"]
-mod strnom;
 use crate::strnom::*;
 
+// The escape sequences are not validated: this is also used to skip over the
+// literals of the C++ code, which may contain escapes that are not valid in Rust.
 fn skip_literal(mut input: Cursor) -> PResult<bool> {
     //input = whitespace(input)?.0;
     if input.starts_with("\"") {
-        input = cooked_string(input.advance(1))?.0;
+        input = skip_cooked_string(input.advance(1))?.0;
         debug_assert!(input.starts_with("\""));
         return Ok((input.advance(1), true));
     }
     if input.starts_with("b\"") {
-        input = cooked_byte_string(input.advance(2))?.0;
+        input = skip_cooked_string(input.advance(2))?.0;
         debug_assert!(input.starts_with("\""));
         return Ok((input.advance(1), true));
     }
     if input.starts_with("\'") {
         input = input.advance(1);
-        let cur = cooked_char(input)?.0;
+        let cur = skip_cooked_char(input)?.0;
         if !cur.starts_with("\'") {
             return Ok((symbol(input)?.0, true));
         }
@@ -225,7 +529,7 @@ fn skip_literal(mut input: Cursor) -> PResult<bool> {
     Ok((input, false))
 }
 
-fn new_cursor(s: &str) -> Cursor {
+fn new_cursor(s: &str) -> Cursor<'_> {
     Cursor { rest: s, off: 0, line: 0, column: 0 }
 }
 
@@ -242,6 +546,9 @@ fn test_skip_literal() -> Result<(), LexError> {
     assert!((skip_literal(new_cursor("b'\''ok xx"))?.0).starts_with("ok"));
     assert!((skip_literal(new_cursor("'abc ok xx"))?.0).starts_with(" ok"));
     assert!((skip_literal(new_cursor("'a ok xx"))?.0).starts_with(" ok"));
+    assert!((skip_literal(new_cursor(r#""\e[1m\033[0m\?"ok xx"#))?.0).starts_with("ok"));
+    assert!((skip_literal(new_cursor(r#"'\033'ok xx"#))?.0).starts_with("ok"));
+    assert!((skip_literal(new_cursor(r#"'\e'ok xx"#))?.0).starts_with("ok"));
 
     assert!((skip_whitespace(new_cursor("ok xx"))).starts_with("ok"));
     assert!((skip_whitespace(new_cursor("   ok xx"))).starts_with("ok"));
@@ -262,7 +569,7 @@ fn find_delimited<'a>(mut input: Cursor<'a>, needle: &str) -> PResult<'a, ()> {
         }
         if stack.is_empty() && input.starts_with(needle) {
             return Ok((input, ()));
-        } else if stack.last().map_or(false, |x| input.starts_with(x)) {
+        } else if matches!(stack.last(), Some(x) if input.starts_with(x)) {
             stack.pop();
         } else if input.starts_with("(") {
             stack.push(")");
@@ -300,29 +607,153 @@ fn test_cursor_advance() -> Result<(), LexError> {
 }
 
 fn line_directive(path: &Path, cur: Cursor) -> String {
-    let mut line =
-        format!("#line {} \"{}\"\n", cur.line + 1, path.to_string_lossy().replace('\\', "\\\\"));
+    let mut line = format!(
+        "#line {} \"{}\"\n",
+        cur.line + 1,
+        crate::escape_cpp_string(&path.to_string_lossy())
+    );
     for _ in 0..cur.column {
         line.push(' ');
     }
     line
 }
 
+#[test]
+fn test_line_directive() {
+    let cursor = new_cursor("");
+    let path = Path::new(r#"C:\Users\Foo Bar\my "crate"\src\lib.rs"#);
+    assert_eq!(
+        line_directive(path, cursor),
+        "#line 1 \"C:\\\\Users\\\\Foo Bar\\\\my \\\"crate\\\"\\\\src\\\\lib.rs\"\n"
+    );
+}
+
+/// The location of the content of a cpp! or cpp_class! macro
+#[derive(Clone, Debug, PartialEq)]
+struct MacroSite {
+    class: bool,
+    body: Range<usize>,
+}
+
+/// Find the cpp! and cpp_class! macros of `source`, except those in the `disabled` ranges
+fn find_macro_sites(source: &str, disabled: &[Range<usize>]) -> Result<Vec<MacroSite>, LexError> {
+    let mut sites = Vec::new();
+    let mut cursor = new_cursor(source);
+    while !cursor.is_empty() {
+        cursor = skip_whitespace(cursor);
+        let r = skip_literal(cursor)?;
+        cursor = r.0;
+        if r.1 {
+            continue;
+        }
+        if let Ok((cur, ident)) = symbol(cursor) {
+            let begin = cursor.off as usize;
+            cursor = cur;
+            if ident != "cpp" && ident != "cpp_class" {
+                continue;
+            }
+            cursor = skip_whitespace(cursor);
+            if !cursor.starts_with("!") {
+                continue;
+            }
+            cursor = skip_whitespace(cursor.advance(1));
+            let delim = if cursor.starts_with("(") {
+                ")"
+            } else if cursor.starts_with("[") {
+                "]"
+            } else if cursor.starts_with("{") {
+                "}"
+            } else {
+                continue;
+            };
+            cursor = cursor.advance(1);
+            let start = cursor.off as usize;
+            cursor = find_delimited(cursor, delim)?.0;
+            if disabled.iter().any(|r| r.contains(&begin)) {
+                continue;
+            }
+            sites.push(MacroSite { class: ident == "cpp_class", body: start..cursor.off as usize });
+            continue;
+        }
+        if cursor.is_empty() {
+            break;
+        }
+        cursor = cursor.advance(1); // Not perfect, but should work
+    }
+    Ok(sites)
+}
+
 #[derive(Default)]
 pub struct Parser {
     pub closures: Vec<Closure>,
     pub classes: Vec<Class>,
-    pub snippets: String,
+    /// The expanded `cpp!{{ }}` snippets, in the order they are parsed
+    pub snippets: Vec<String>,
     pub callbacks_count: u32,
+    /// The locations of the rust! macros, in the closures and in the snippets
+    pub rust_callbacks: Vec<String>,
+    /// The hash of the library being built, see `cpp_common::lib_hash`
+    pub lib_hash: u64,
+    /// The `extern "C"` functions declared with `rustcpp_import` in the cpp! snippets
+    pub imports: Vec<String>,
+    /// The warnings about the cpp! closures, with their location
+    pub warnings: Vec<String>,
+    /// The names of the enums deriving `CppEnum`, whose values returned by the cpp!
+    /// closures are checked
+    pub checked_enums: Vec<String>,
+    /// The errors in the cpp! and cpp_class! macros, with their location. These
+    /// macros are skipped, but the others are still parsed.
+    pub errors: Vec<String>,
+    /// The summaries of the files scanned by the previous build, and of the files
+    /// scanned by this one
+    pub cache: ScanCache,
+    // The `const` items of the crate, for rust_const!. None if several have the same name
+    consts: HashMap<String, Option<syn::Expr>>,
     current_path: PathBuf, // The current file being parsed
     mod_dir: PathBuf,
-    mod_error: Option<Error>, // An error occuring while visiting the modules
+    generic_scopes: Vec<GenericScope>, // The functions of the current file with generic parameters
 }
 
 impl Parser {
     pub fn parse_crate(&mut self, crate_root: PathBuf) -> Result<(), Error> {
         let parent = crate_root.parent().map(|x| x.to_owned()).unwrap_or_default();
-        self.parse_mod(crate_root, parent)
+        self.parse_mod(crate_root, parent)?;
+        self.expand_rust_consts();
+        Ok(())
+    }
+
+    // Replace the rust_const! macros once all the constants of the crate are known
+    fn expand_rust_consts(&mut self) {
+        let consts = &self.consts;
+        let value = |name: &str| {
+            eval_const(name, consts, 0).map(|v| {
+                if v > i64::MAX as i128 {
+                    format!("{}ull", v)
+                } else if v < 0 {
+                    format!("({})", v)
+                } else {
+                    v.to_string()
+                }
+            })
+        };
+        let errors = &mut self.errors;
+        for closure in &mut self.closures {
+            match expand_rust_const(&closure.body_str, value) {
+                Ok(body) => closure.body_str = body,
+                Err(e) => errors.push(format!(
+                    "{}: error while expanding rust_const! in cpp! macro: {}",
+                    closure.location, e.1
+                )),
+            }
+        }
+        for snippet in &mut self.snippets {
+            match expand_rust_const(snippet, value) {
+                Ok(s) => *snippet = s,
+                Err(e) => {
+                    errors.push(format!("error while expanding rust_const! in cpp! macro: {}", e.1))
+                }
+            }
+        }
     }
 
     fn parse_mod(&mut self, mod_path: PathBuf, submod_dir: PathBuf) -> Result<(), Error> {
@@ -334,10 +765,15 @@ impl Parser {
             src_path: mod_path.to_str().unwrap().to_owned(),
         })?;
 
-        let fi = syn::parse_file(&s).map_err(|x| Error::ParseSyntaxError {
-            src_path: mod_path.to_str().unwrap().to_owned(),
-            error: x,
-        })?;
+        let key = FileKey::new(&f, &s);
+        let summary = match self.cache.lookup(&mod_path, &key) {
+            Some(summary) => summary,
+            None => {
+                let summary = scan_file(&mod_path, &s)?;
+                self.cache.insert(mod_path.clone(), key, summary.clone());
+                summary
+            }
+        };
 
         let mut current_path = mod_path;
         let mut mod_dir = submod_dir;
@@ -345,10 +781,15 @@ impl Parser {
         swap(&mut self.current_path, &mut current_path);
         swap(&mut self.mod_dir, &mut mod_dir);
 
-        self.find_cpp_macros(&s)?;
-        self.visit_file(&fi);
-        if let Some(err) = self.mod_error.take() {
-            return Err(err);
+        self.generic_scopes = summary.generic_scopes;
+        self.handle_macros(&s, &summary.macros);
+        for (name, expr) in summary.consts {
+            let expr = syn::parse_str::<syn::Expr>(&expr).ok();
+            self.consts.entry(name).and_modify(|e| *e = None).or_insert(expr);
+        }
+        self.checked_enums.extend(summary.checked_enums);
+        for decl in &summary.mods {
+            self.parse_mod_decl(decl)?;
         }
 
         swap(&mut self.current_path, &mut current_path);
@@ -357,6 +798,34 @@ impl Parser {
         Ok(())
     }
 
+    /// Parse the file of a `mod foo;` declaration of the current file
+    fn parse_mod_decl(&mut self, decl: &ModDecl) -> Result<(), Error> {
+        let mut mod_dir = self.mod_dir.clone();
+        mod_dir.extend(&decl.inline);
+
+        // parse #[path = "foo.rs"]: read module from the specified path
+        if let Some(path) = &decl.path {
+            let parent = mod_dir.parent().map(|x| x.to_owned()).unwrap_or_default();
+            return self.parse_mod(mod_dir.join(path), parent);
+        }
+
+        let subdir = mod_dir.join(&decl.name);
+        let subdir_mod = subdir.join("mod.rs");
+        if subdir_mod.is_file() {
+            return self.parse_mod(subdir_mod, subdir);
+        }
+
+        let adjacent = mod_dir.join(format!("{}.rs", decl.name));
+        if adjacent.is_file() {
+            return self.parse_mod(adjacent, subdir);
+        }
+
+        panic!(
+            "No file with module definition for `mod {}` in file {:?}",
+            decl.name, self.current_path
+        );
+    }
+
     /*
     fn parse_macro(&mut self, tts: TokenStream) {
         let mut last_ident: Option<syn::Ident> = None;
@@ -388,66 +857,38 @@ impl Parser {
     }
     */
 
-    fn find_cpp_macros(&mut self, source: &str) -> Result<(), Error> {
-        let mut cursor = new_cursor(source);
-        while !cursor.is_empty() {
-            cursor = skip_whitespace(cursor);
-            let r = skip_literal(cursor).map_err(|e| self.lex_error(e))?;
-            cursor = r.0;
-            if r.1 {
-                continue;
-            }
-            if let Ok((cur, ident)) = symbol(cursor) {
-                cursor = cur;
-                if ident != "cpp" && ident != "cpp_class" {
-                    continue;
-                }
-                cursor = skip_whitespace(cursor);
-                if !cursor.starts_with("!") {
-                    continue;
-                }
-                cursor = skip_whitespace(cursor.advance(1));
-                let delim = if cursor.starts_with("(") {
-                    ")"
-                } else if cursor.starts_with("[") {
-                    "]"
-                } else if cursor.starts_with("{") {
-                    "}"
-                } else {
-                    continue;
-                };
-                cursor = cursor.advance(1);
-                let mut macro_cur = cursor;
-                cursor = find_delimited(cursor, delim).map_err(|e| self.lex_error(e))?.0;
-                let size = (cursor.off - macro_cur.off) as usize;
-                macro_cur.rest = &macro_cur.rest[..size];
-                if ident == "cpp" {
-                    self.handle_cpp(macro_cur).unwrap_or_else(|e| {
-                        panic!("Error while parsing cpp! macro:\n{:?}:{}", self.current_path, e)
-                    });
-                } else {
-                    debug_assert_eq!(ident, "cpp_class");
-                    self.handle_cpp_class(macro_cur).unwrap_or_else(|e| {
-                        panic!(
-                            "Error while parsing cpp_class! macro:\n{:?}:{}",
-                            self.current_path, e
-                        )
-                    });
-                }
-                continue;
-            }
-            if cursor.is_empty() {
-                break;
-            }
-            cursor = cursor.advance(1); // Not perfect, but should work
-        }
+    #[cfg(test)]
+    fn find_cpp_macros(&mut self, source: &str, disabled: &[Range<usize>]) -> Result<(), Error> {
+        let sites = find_macro_sites(source, disabled).map_err(|e| Error::LexError {
+            src_path: self.current_path.to_str().unwrap().to_owned(),
+            line: e.line,
+        })?;
+        self.handle_macros(source, &sites);
         Ok(())
     }
 
-    fn lex_error(&self, e: LexError) -> Error {
-        Error::LexError {
-            src_path: self.current_path.clone().to_str().unwrap().to_owned(),
-            line: e.line,
+    fn handle_macros(&mut self, source: &str, sites: &[MacroSite]) {
+        let mut cursor = new_cursor(source);
+        for site in sites {
+            cursor = cursor.advance(site.body.start - cursor.off as usize);
+            let mut macro_cur = cursor;
+            macro_cur.rest = &macro_cur.rest[..site.body.len()];
+            let (ident, result) = if site.class {
+                ("cpp_class", self.handle_cpp_class(macro_cur))
+            } else {
+                ("cpp", self.handle_cpp(macro_cur))
+            };
+            // Keep going, so that all the errors are reported and the other
+            // macros can still be used
+            if let Err(e) = result {
+                self.errors.push(format!(
+                    "{}:{}: error while parsing {}! macro: {}",
+                    self.current_path.display(),
+                    e.0 + 1,
+                    ident,
+                    e.1
+                ));
+            }
         }
     }
 
@@ -462,27 +903,91 @@ impl Parser {
         match ::syn::parse2::<Macro>(input).map_err(|e| LineError(x.line, e.to_string()))? {
             Macro::Closure(mut c) => {
                 c.callback_offset = self.callbacks_count;
-                c.body_str = line_directive(&self.current_path, begin)
+                c.location =
+                    format!("{}:{}:{}", self.current_path.display(), x.line + 1, x.column + 1);
+                c.line = line_directive(&self.current_path, x);
+                c.generic_captures = self.generic_captures(&c, x.off as usize);
+                // An unused capture costs a parameter and a size check, and is usually a mistake
+                for capture in &c.sig.captures {
+                    let name = capture.name.to_string();
+                    if !name.starts_with('_') && !mentions_ident(&c.body, &name) {
+                        self.warnings.push(format!(
+                            "{}: the capture `{}` is not used in the body of the cpp! closure, \
+                             it can be removed (or prefixed with `_`)",
+                            c.location, name
+                        ));
+                    }
+                }
+                // The value of the const captures is found by expand_rust_consts
+                let consts = c.sig.captures.iter().filter(|c| c.constant).map(|c| {
+                    format!(
+                        "constexpr {cpp} {name} = rust_const!({name});\n",
+                        cpp = c.cpp,
+                        name = c.name
+                    )
+                });
+                let mut lines = Vec::new();
+                c.body_str = c.line.clone()
+                    + &consts.collect::<String>()
+                    + &line_directive(&self.current_path, begin)
                     + &expand_sub_rust_macro(
-                        extracted.to_string(),
-                        ExpandSubMacroType::Closure(&mut self.callbacks_count),
+                        extracted,
+                        ExpandSubMacroType::Closure(&mut self.callbacks_count, self.lib_hash),
+                        &mut lines,
                     )
                     .map_err(|e| e.add_line(begin.line))?;
+                self.add_rust_callbacks(begin.line, lines);
                 self.closures.push(c);
             }
             Macro::Lit(_l) => {
-                self.snippets.push('\n');
+                self.imports.extend(find_imports(extracted).map_err(|e| e.add_line(begin.line))?);
+                let mut lines = Vec::new();
                 let snip = expand_sub_rust_macro(
-                    line_directive(&self.current_path, begin) + extracted,
+                    &(line_directive(&self.current_path, begin) + extracted),
                     ExpandSubMacroType::Lit,
+                    &mut lines,
                 )
                 .map_err(|e| e.add_line(begin.line))?;
-                self.snippets.push_str(&snip);
+                // The #line directive comes first
+                self.add_rust_callbacks(begin.line, lines.into_iter().map(|l| l - 1).collect());
+                self.snippets.push(snip);
             }
         }
         Ok(())
     }
 
+    // Record the rust! macros found at `lines`, relative to the line `first`
+    fn add_rust_callbacks(&mut self, first: u32, lines: Vec<u32>) {
+        let path = self.current_path.display();
+        for line in lines {
+            self.rust_callbacks.push(format!("{}:{}", path, first + line + 1));
+        }
+    }
+
+    // The captures of the closure at `offset` which are generic parameters of the
+    // innermost enclosing function, and are not shadowed before the macro
+    fn generic_captures(&self, closure: &Closure, offset: usize) -> Vec<String> {
+        let scope = match self
+            .generic_scopes
+            .iter()
+            .filter(|s| s.body.contains(&offset))
+            .max_by_key(|s| s.body.start)
+        {
+            Some(scope) => scope,
+            None => return Vec::new(),
+        };
+        closure
+            .sig
+            .captures
+            .iter()
+            .map(|c| c.name.to_string())
+            .filter(|name| {
+                scope.params.contains(name)
+                    && !scope.bindings.iter().any(|(b, off)| b == name && *off < offset)
+            })
+            .collect()
+    }
+
     fn handle_cpp_class(&mut self, x: Cursor) -> Result<(), LineError> {
         let input: ::proc_macro2::TokenStream =
             x.rest.parse().map_err(|_| LineError(x.line, "TokenStream parse error".into()))?;
@@ -494,7 +999,248 @@ impl Parser {
     }
 }
 
-impl<'ast> Visit<'ast> for Parser {
+/// Whether the identifier `name` appears in the tokens of `tt`. A C++ macro
+/// could use it without naming it, but this is rare in the body of a closure.
+fn mentions_ident(tt: &TokenTree, name: &str) -> bool {
+    match tt {
+        TokenTree::Ident(ident) => ident == name,
+        TokenTree::Group(group) => group.stream().into_iter().any(|t| mentions_ident(&t, name)),
+        _ => false,
+    }
+}
+
+// parse #[cfg(feature = "feature")]: returns true if the item is not enabled by current features
+fn cfg_disabled(attrs: &[syn::Attribute]) -> bool {
+    let mut disabled = false;
+    for attr in attrs {
+        if let syn::Meta::List(list @ syn::MetaList { path, .. }) = &attr.meta {
+            if !path.is_ident("cfg") {
+                continue;
+            }
+            drop(list.parse_nested_meta(|meta| {
+                if meta.path.is_ident("feature") {
+                    let feature: syn::LitStr = meta.value()?.parse()?;
+                    let feature_env_var = "CARGO_FEATURE_".to_owned()
+                        + &feature.value().to_uppercase().replace('-', "_");
+                    if std::env::var_os(feature_env_var).is_none() {
+                        disabled = true;
+                    }
+                }
+                Ok(())
+            }))
+        }
+    }
+    disabled
+}
+
+/// Collects the byte ranges of the content of the inline modules not enabled
+/// by current features, so that the cpp! macros they contain are skipped.
+#[derive(Default)]
+struct DisabledInlineModules(Vec<Range<usize>>);
+
+impl<'ast> Visit<'ast> for DisabledInlineModules {
+    fn visit_item_mod(&mut self, item: &'ast syn::ItemMod) {
+        if let Some((brace, _)) = &item.content {
+            if cfg_disabled(&item.attrs) {
+                self.0.push(brace.span.join().byte_range());
+            } else {
+                syn::visit::visit_item_mod(self, item);
+            }
+        }
+    }
+}
+
+/// A function with parameters whose type is a generic parameter
+#[derive(Clone, Debug, PartialEq)]
+struct GenericScope {
+    body: Range<usize>,
+    params: Vec<String>,
+    /// The names bound in the body, with their offset, which may shadow the parameters
+    bindings: Vec<(String, usize)>,
+}
+
+/// Collects the functions which take parameters of a generic type (`T`, `impl Trait`
+/// or `Self` in a generic impl or a trait). These parameters can't be captured by
+/// cpp!, as their type is only known after monomorphization.
+#[derive(Default)]
+struct GenericParameters {
+    scopes: Vec<GenericScope>,
+    // The type parameters of the enclosing impl or trait
+    outer: Vec<String>,
+}
+
+impl GenericParameters {
+    fn check_fn(&mut self, sig: &syn::Signature, block: &syn::Block) {
+        let mut names = self.outer.clone();
+        names.extend(sig.generics.type_params().map(|p| p.ident.to_string()));
+        let params = sig
+            .inputs
+            .iter()
+            .filter_map(|input| match input {
+                syn::FnArg::Receiver(r) if r.reference.is_none() && !self.outer.is_empty() => {
+                    Some("self".to_owned())
+                }
+                syn::FnArg::Typed(t) => match &*t.pat {
+                    syn::Pat::Ident(p) if is_generic_type(&t.ty, &names) => {
+                        Some(p.ident.to_string())
+                    }
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if params.is_empty() {
+            return;
+        }
+        let mut bindings = Bindings::default();
+        bindings.visit_block(block);
+        self.scopes.push(GenericScope {
+            body: block.brace_token.span.join().byte_range(),
+            params,
+            bindings: bindings.0,
+        });
+    }
+
+    fn with_outer(&mut self, outer: Vec<String>, f: impl FnOnce(&mut Self)) {
+        let outer = std::mem::replace(&mut self.outer, outer);
+        f(self);
+        self.outer = outer;
+    }
+}
+
+impl<'ast> Visit<'ast> for GenericParameters {
+    fn visit_item_impl(&mut self, item: &'ast syn::ItemImpl) {
+        let mut outer =
+            item.generics.type_params().map(|p| p.ident.to_string()).collect::<Vec<_>>();
+        if !outer.is_empty() {
+            outer.push("Self".to_owned());
+        }
+        self.with_outer(outer, |v| syn::visit::visit_item_impl(v, item));
+    }
+
+    fn visit_item_trait(&mut self, item: &'ast syn::ItemTrait) {
+        let mut outer =
+            item.generics.type_params().map(|p| p.ident.to_string()).collect::<Vec<_>>();
+        outer.push("Self".to_owned());
+        self.with_outer(outer, |v| syn::visit::visit_item_trait(v, item));
+    }
+
+    fn visit_item_fn(&mut self, item: &'ast syn::ItemFn) {
+        // A nested function does not see the generics of the enclosing items
+        self.with_outer(Vec::new(), |v| {
+            v.check_fn(&item.sig, &item.block);
+            syn::visit::visit_item_fn(v, item);
+        });
+    }
+
+    fn visit_impl_item_fn(&mut self, item: &'ast syn::ImplItemFn) {
+        self.check_fn(&item.sig, &item.block);
+        syn::visit::visit_impl_item_fn(self, item);
+    }
+
+    fn visit_trait_item_fn(&mut self, item: &'ast syn::TraitItemFn) {
+        if let Some(block) = &item.default {
+            self.check_fn(&item.sig, block);
+        }
+        syn::visit::visit_trait_item_fn(self, item);
+    }
+}
+
+/// Whether `ty` is one of the generic type parameters `names`, or an `impl Trait`.
+/// References and other types built from a generic parameter may have a known size,
+/// so they are not reported.
+fn is_generic_type(ty: &syn::Type, names: &[String]) -> bool {
+    match ty {
+        syn::Type::ImplTrait(_) => true,
+        syn::Type::Path(p) if p.qself.is_none() => {
+            matches!(p.path.get_ident(), Some(i) if names.iter().any(|n| i == n))
+        }
+        syn::Type::Paren(p) => is_generic_type(&p.elem, names),
+        syn::Type::Group(g) => is_generic_type(&g.elem, names),
+        _ => false,
+    }
+}
+
+/// The identifiers bound by the patterns of a function body, with their offset
+#[derive(Default)]
+struct Bindings(Vec<(String, usize)>);
+
+impl<'ast> Visit<'ast> for Bindings {
+    fn visit_pat_ident(&mut self, pat: &'ast syn::PatIdent) {
+        self.0.push((pat.ident.to_string(), pat.ident.span().byte_range().start));
+        syn::visit::visit_pat_ident(self, pat);
+    }
+}
+
+/// A `mod foo;` declaration, whose file is looked for when the declaring file is parsed
+#[derive(Clone, Debug, PartialEq)]
+struct ModDecl {
+    /// The inline modules containing the declaration
+    inline: Vec<String>,
+    name: String,
+    /// The value of the `#[path]` attribute
+    path: Option<String>,
+}
+
+/// What the parser needs from a source file: the location of its cpp! macros, and
+/// the items found by syn. It only depends on the content of the file and on the
+/// enabled features, so it can be reused while the file is unchanged.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct FileSummary {
+    macros: Vec<MacroSite>,
+    generic_scopes: Vec<GenericScope>,
+    /// The `const` items, with the source of their value
+    consts: Vec<(String, String)>,
+    checked_enums: Vec<String>,
+    mods: Vec<ModDecl>,
+}
+
+fn scan_file(path: &Path, source: &str) -> Result<FileSummary, Error> {
+    let fi = syn::parse_file(source).map_err(|x| Error::ParseSyntaxError {
+        src_path: path.to_str().unwrap().to_owned(),
+        error: x,
+    })?;
+
+    let mut disabled = DisabledInlineModules::default();
+    disabled.visit_file(&fi);
+    let mut generic = GenericParameters::default();
+    generic.visit_file(&fi);
+
+    let mut items = Items { source, summary: FileSummary::default(), inline: Vec::new() };
+    items.summary.macros = find_macro_sites(source, &disabled.0).map_err(|e| Error::LexError {
+        src_path: path.to_str().unwrap().to_owned(),
+        line: e.line,
+    })?;
+    items.summary.generic_scopes = generic.scopes;
+    items.visit_file(&fi);
+    Ok(items.summary)
+}
+
+/// Collects the constants, the enums deriving `CppEnum` and the module declarations
+struct Items<'a> {
+    source: &'a str,
+    summary: FileSummary,
+    // The inline modules being visited
+    inline: Vec<String>,
+}
+
+impl<'ast> Visit<'ast> for Items<'_> {
+    fn visit_item_const(&mut self, item: &'ast syn::ItemConst) {
+        let range = syn::spanned::Spanned::span(&*item.expr).byte_range();
+        self.summary.consts.push((item.ident.to_string(), self.source[range].to_owned()));
+    }
+
+    fn visit_item_enum(&mut self, item: &'ast syn::ItemEnum) {
+        let derives_cpp_enum = item.attrs.iter().filter(|a| a.path().is_ident("derive")).any(|a| {
+            a.parse_args_with(Punctuated::<syn::Path, Token![,]>::parse_terminated)
+                .map(|paths| paths.iter().any(|p| p.segments.last().unwrap().ident == "CppEnum"))
+                .unwrap_or(false)
+        });
+        if derives_cpp_enum {
+            self.summary.checked_enums.push(item.ident.to_string());
+        }
+    }
+
     /* This is currently commented out because proc_macro2 don't allow us to get the text verbatim
        (https://github.com/alexcrichton/proc-macro2/issues/110#issuecomment-411959999)
     fn visit_macro(&mut self, mac: &syn::Macro) {
@@ -511,76 +1257,328 @@ impl<'ast> Visit<'ast> for Parser {
     }*/
 
     fn visit_item_mod(&mut self, item: &'ast syn::ItemMod) {
-        if self.mod_error.is_some() {
+        // Don't follow modules not enabled by current features. The cpp! macros of the inline
+        // modules were already skipped by find_macro_sites.
+        if cfg_disabled(&item.attrs) {
             return;
         }
 
         if item.content.is_some() {
-            let mut parent = self.mod_dir.join(item.ident.to_string());
-            swap(&mut self.mod_dir, &mut parent);
+            self.inline.push(item.ident.to_string());
             syn::visit::visit_item_mod(self, item);
-            swap(&mut self.mod_dir, &mut parent);
+            self.inline.pop();
             return;
         }
 
-        let mut cfg_disabled = false;
-
-        // Determine the path of the inner module's file
-        for attr in &item.attrs {
-            match &attr.meta {
-                // parse #[path = "foo.rs"]: read module from the specified path
-                syn::Meta::NameValue(syn::MetaNameValue {
-                    path,
-                    value: syn::Expr::Lit(syn::ExprLit { lit: syn::Lit::Str(s), .. }),
-                    ..
-                }) if path.is_ident("path") => {
-                    let mod_path = self.mod_dir.join(s.value());
-                    let parent = self.mod_dir.parent().map(|x| x.to_owned()).unwrap_or_default();
-                    return self
-                        .parse_mod(mod_path, parent)
-                        .unwrap_or_else(|err| self.mod_error = Some(err));
-                }
-                // parse #[cfg(feature = "feature")]: don't follow modules not enabled by current features
-                syn::Meta::List(list @ syn::MetaList { path, .. }) if path.is_ident("cfg") => {
-                    drop(list.parse_nested_meta(|meta| {
-                        if meta.path.is_ident("feature") {
-                            let feature: syn::LitStr = meta.value()?.parse()?;
-                            let feature_env_var = "CARGO_FEATURE_".to_owned()
-                                + &feature.value().to_uppercase().replace('-', "_");
-                            if std::env::var_os(feature_env_var).is_none() {
-                                cfg_disabled = true;
-                            }
-                        }
-                        Ok(())
-                    }))
-                }
-                _ => {}
-            }
-        }
+        let path = item.attrs.iter().find_map(|attr| match &attr.meta {
+            syn::Meta::NameValue(syn::MetaNameValue {
+                path,
+                value: syn::Expr::Lit(syn::ExprLit { lit: syn::Lit::Str(s), .. }),
+                ..
+            }) if path.is_ident("path") => Some(s.value()),
+            _ => None,
+        });
+        self.summary.mods.push(ModDecl {
+            inline: self.inline.clone(),
+            name: item.ident.to_string(),
+            path,
+        });
+    }
+}
 
-        if cfg_disabled {
-            return;
+/// Identifies the content of a file: the summary of a file is reused if none of
+/// these changed since it was scanned.
+#[derive(Clone, Debug, PartialEq)]
+struct FileKey {
+    /// The modification time, in nanoseconds since the Unix epoch
+    mtime: u128,
+    len: u64,
+    hash: u64,
+}
+
+impl FileKey {
+    fn new(file: &File, content: &str) -> FileKey {
+        let mtime = file
+            .metadata()
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_nanos());
+        let mut hasher = DefaultHasher::new();
+        content.hash(&mut hasher);
+        FileKey { mtime, len: content.len() as u64, hash: hasher.finish() }
+    }
+}
+
+/// The summaries of the scanned files, kept between builds in the intermediate
+/// directory so that the unchanged files of a crate are not parsed again.
+#[derive(Default)]
+pub struct ScanCache {
+    previous: HashMap<PathBuf, (FileKey, FileSummary)>,
+    current: Vec<(PathBuf, FileKey, FileSummary)>,
+    /// The number of files whose summary was found in the cache
+    pub hits: usize,
+}
+
+impl ScanCache {
+    /// Read the cache written by [`ScanCache::save`]. The cache is empty if the file
+    /// is missing or invalid, or if it was written with other enabled features.
+    pub fn load(path: &Path) -> ScanCache {
+        let previous = std::fs::read_to_string(path)
+            .ok()
+            .and_then(|content| parse_cache(&content))
+            .unwrap_or_default();
+        ScanCache { previous, ..ScanCache::default() }
+    }
+
+    /// Write the summaries of the files scanned by this build
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut out = format!("{}\n", cache_header());
+        for (path, key, summary) in &self.current {
+            write_cache_entry(&mut out, path, key, summary);
         }
+        File::create(path)?.write_all(out.as_bytes())
+    }
 
-        let mod_name = item.ident.to_string();
-        let subdir = self.mod_dir.join(&mod_name);
-        let subdir_mod = subdir.join("mod.rs");
-        if subdir_mod.is_file() {
-            return self
-                .parse_mod(subdir_mod, subdir)
-                .unwrap_or_else(|err| self.mod_error = Some(err));
+    fn lookup(&mut self, path: &Path, key: &FileKey) -> Option<FileSummary> {
+        match self.previous.remove(path) {
+            Some((k, summary)) if k == *key => {
+                self.hits += 1;
+                self.current.push((path.to_owned(), k, summary.clone()));
+                Some(summary)
+            }
+            _ => None,
         }
+    }
 
-        let adjacent = self.mod_dir.join(format!("{}.rs", mod_name));
-        if adjacent.is_file() {
-            return self
-                .parse_mod(adjacent, subdir)
-                .unwrap_or_else(|err| self.mod_error = Some(err));
+    fn insert(&mut self, path: PathBuf, key: FileKey, summary: FileSummary) {
+        self.current.push((path, key, summary));
+    }
+}
+
+/// The first line of the cache, which invalidates it when cpp_build or the
+/// enabled features change
+fn cache_header() -> String {
+    let mut features = std::env::vars_os()
+        .map(|(k, _)| k.to_string_lossy().into_owned())
+        .filter(|k| k.starts_with("CARGO_FEATURE_"))
+        .collect::<Vec<_>>();
+    features.sort();
+    let mut hasher = DefaultHasher::new();
+    features.hash(&mut hasher);
+    format!("rust-cpp scan cache {} {:x}", crate::VERSION, hasher.finish())
+}
+
+fn escape_field(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+fn unescape_field(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
         }
+        match chars.next() {
+            Some('t') => result.push('\t'),
+            Some('n') => result.push('\n'),
+            Some('r') => result.push('\r'),
+            Some(c) => result.push(c),
+            None => break,
+        }
+    }
+    result
+}
 
-        panic!(
-            "No file with module definition for `mod {}` in file {:?}",
-            mod_name, self.current_path
-        );
+// One entry per line, with tab separated fields:
+//   file <path> <mtime> <len> <hash>
+//   macro <cpp|cpp_class> <start> <end>
+//   scope <start> <end> <params> <name:offset bindings>
+//   const <name> <value>
+//   enum <name>
+//   mod <name> <path, or empty> <inline modules>
+fn write_cache_entry(out: &mut String, path: &Path, key: &FileKey, summary: &FileSummary) {
+    use std::fmt::Write;
+    let path = escape_field(&path.to_string_lossy());
+    writeln!(out, "file\t{}\t{}\t{}\t{}", path, key.mtime, key.len, key.hash).unwrap();
+    for site in &summary.macros {
+        let ident = if site.class { "cpp_class" } else { "cpp" };
+        writeln!(out, "macro\t{}\t{}\t{}", ident, site.body.start, site.body.end).unwrap();
     }
+    for scope in &summary.generic_scopes {
+        let bindings =
+            scope.bindings.iter().map(|(n, off)| format!("{}:{}", n, off)).collect::<Vec<_>>();
+        writeln!(
+            out,
+            "scope\t{}\t{}\t{}\t{}",
+            scope.body.start,
+            scope.body.end,
+            scope.params.join(" "),
+            bindings.join(" ")
+        )
+        .unwrap();
+    }
+    for (name, value) in &summary.consts {
+        writeln!(out, "const\t{}\t{}", name, escape_field(value)).unwrap();
+    }
+    for name in &summary.checked_enums {
+        writeln!(out, "enum\t{}", name).unwrap();
+    }
+    for decl in &summary.mods {
+        let path = decl.path.as_deref().map(escape_field).unwrap_or_default();
+        writeln!(out, "mod\t{}\t{}\t{}", decl.name, path, decl.inline.join(" ")).unwrap();
+    }
+}
+
+fn parse_cache(content: &str) -> Option<HashMap<PathBuf, (FileKey, FileSummary)>> {
+    let mut lines = content.lines();
+    if lines.next()? != cache_header() {
+        return None;
+    }
+    let words = |s: &str| s.split(' ').filter(|w| !w.is_empty()).map(str::to_owned).collect();
+    let mut entries = HashMap::new();
+    let mut current: Option<&mut FileSummary> = None;
+    for line in lines {
+        let fields = line.split('\t').collect::<Vec<_>>();
+        match fields[..] {
+            ["file", path, mtime, len, hash] => {
+                let key = FileKey {
+                    mtime: mtime.parse().ok()?,
+                    len: len.parse().ok()?,
+                    hash: hash.parse().ok()?,
+                };
+                let entry = entries
+                    .entry(PathBuf::from(unescape_field(path)))
+                    .or_insert((key, FileSummary::default()));
+                current = Some(&mut entry.1);
+            }
+            ["macro", ident, start, end] => current.as_mut()?.macros.push(MacroSite {
+                class: ident == "cpp_class",
+                body: start.parse().ok()?..end.parse().ok()?,
+            }),
+            ["scope", start, end, params, bindings] => {
+                let bindings = bindings
+                    .split(' ')
+                    .filter(|b| !b.is_empty())
+                    .map(|b| {
+                        let (name, off) = b.split_once(':')?;
+                        Some((name.to_owned(), off.parse().ok()?))
+                    })
+                    .collect::<Option<_>>()?;
+                current.as_mut()?.generic_scopes.push(GenericScope {
+                    body: start.parse().ok()?..end.parse().ok()?,
+                    params: words(params),
+                    bindings,
+                });
+            }
+            ["const", name, value] => {
+                current.as_mut()?.consts.push((name.to_owned(), unescape_field(value)))
+            }
+            ["enum", name] => current.as_mut()?.checked_enums.push(name.to_owned()),
+            ["mod", name, path, inline] => current.as_mut()?.mods.push(ModDecl {
+                inline: words(inline),
+                name: name.to_owned(),
+                path: Some(unescape_field(path)).filter(|p| !p.is_empty()),
+            }),
+            _ => return None,
+        }
+    }
+    Some(entries)
+}
+
+#[test]
+fn test_collect_errors() {
+    let mut parser = Parser::default();
+    let source = r#"
+fn a() { cpp!([x as] {}) }
+fn b() -> i32 { cpp!([] -> i32 as "int" { return 1; }) }
+cpp_class!(unsafe struct);
+"#;
+    parser.find_cpp_macros(source, &[]).unwrap();
+    assert_eq!(parser.closures.len(), 1);
+    assert_eq!(parser.errors.len(), 2, "{:?}", parser.errors);
+    assert!(
+        parser.errors[0].starts_with(":2: error while parsing cpp! macro"),
+        "{:?}",
+        parser.errors
+    );
+    assert!(
+        parser.errors[1].starts_with(":4: error while parsing cpp_class! macro"),
+        "{:?}",
+        parser.errors
+    );
+}
+
+#[test]
+fn test_generic_captures() {
+    let mut parser = Parser::default();
+    let source = r#"
+fn a<T>(x: T, y: &T, z: impl Copy, w: i32) {
+    cpp!([x as "int", y as "const void*", z as "int", w as "int"] {});
+    let x = 1;
+    cpp!([x as "int"] {});
+}
+struct S<T>(T);
+impl<T> S<T> {
+    fn b(self, t: T) { cpp!([self as "int", t as "int"] {}) }
+    fn c(&self) { cpp!([self as "const void*"] {}) }
+}
+fn d(x: i32) { cpp!([x as "int"] {}) }
+"#;
+    let fi = syn::parse_file(source).unwrap();
+    let mut generic = GenericParameters::default();
+    generic.visit_file(&fi);
+    parser.generic_scopes = generic.scopes;
+    parser.find_cpp_macros(source, &[]).unwrap();
+    let generic = parser.closures.iter().map(|c| c.generic_captures.clone()).collect::<Vec<_>>();
+    assert_eq!(generic, vec![vec!["x", "z"], vec![], vec!["self", "t"], vec![], vec![]]);
+}
+
+#[test]
+fn test_unused_capture_warning() {
+    let mut parser = Parser::default();
+    let source = r#"
+fn a(x: i32, y: i32, _z: i32) {
+    cpp!([x as "int", y as "int", _z as "int"] { return rust!(A [x : i32 as "int"] { x }) + f("y"); })
+}
+"#;
+    parser.find_cpp_macros(source, &[]).unwrap();
+    assert_eq!(parser.warnings.len(), 1, "{:?}", parser.warnings);
+    assert!(parser.warnings[0].contains("the capture `y` is not used"), "{:?}", parser.warnings);
+}
+
+#[test]
+fn test_scan_cache() {
+    let dir = std::env::temp_dir().join(format!("rust_cpp_scan_cache_{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("inner")).unwrap();
+    let lib = "const N: i32 = 2 * 3;\nmod sub;\nmod inner { mod a; }\n";
+    std::fs::write(dir.join("lib.rs"), lib).unwrap();
+    std::fs::write(dir.join("sub.rs"), "fn f() { cpp!([] { return rust_const!(N); }) }\n").unwrap();
+    std::fs::write(dir.join("inner").join("a.rs"), "cpp_class!(pub unsafe struct A as \"A\");\n")
+        .unwrap();
+
+    let parse = |cache: ScanCache| {
+        let mut parser = Parser { cache, ..Parser::default() };
+        parser.parse_crate(dir.join("lib.rs")).unwrap();
+        parser
+    };
+    let first = parse(ScanCache::load(&dir.join("cache")));
+    assert_eq!(first.cache.hits, 0);
+    first.cache.save(&dir.join("cache")).unwrap();
+
+    let second = parse(ScanCache::load(&dir.join("cache")));
+    assert_eq!(second.cache.hits, 3);
+    assert_eq!(second.cache.current, first.cache.current);
+    assert!(second.closures[0].body_str.contains("return 6;"), "{}", second.closures[0].body_str);
+    assert_eq!(second.classes.len(), 1);
+    second.cache.save(&dir.join("cache")).unwrap();
+
+    std::fs::write(dir.join("sub.rs"), "fn g() { cpp!([] {}) }\nfn f() { cpp!([] {}) }\n").unwrap();
+    let third = parse(ScanCache::load(&dir.join("cache")));
+    assert_eq!(third.cache.hits, 2);
+    assert_eq!(third.closures.len(), 2);
+
+    std::fs::remove_dir_all(&dir).unwrap();
 }
@@ -128,7 +128,7 @@ pub fn whitespace(input: Cursor) -> PResult<()> {
     Ok((input.advance(input.len()), ()))
 }
 
-pub fn block_comment(input: Cursor) -> PResult<&str> {
+pub fn block_comment(input: Cursor<'_>) -> PResult<'_, &str> {
     if !input.starts_with("/*") {
         return Err(LexError { line: input.line });
     }
@@ -177,7 +177,7 @@ fn is_ident_continue(c: char) -> bool {
     c.is_ascii_alphanumeric() || c == '_' || (c > '\x7f' && UnicodeXID::is_xid_continue(c))
 }
 
-pub fn symbol(input: Cursor) -> PResult<&str> {
+pub fn symbol(input: Cursor<'_>) -> PResult<'_, &str> {
     let mut chars = input.char_indices();
 
     let raw = input.starts_with("r#");
@@ -252,6 +252,45 @@ pub fn cooked_string(input: Cursor) -> PResult<()> {
     Err(LexError { line: input.line })
 }
 
+/// Like `cooked_string`, but accepts any escape sequence, including the ones
+/// which are only valid in C++ (`\e`, `\033`, `\?`, ...). Only the closing
+/// quote needs to be found when skipping over a literal.
+pub fn skip_cooked_string(input: Cursor) -> PResult<()> {
+    let mut chars = input.char_indices();
+    while let Some((byte_offset, ch)) = chars.next() {
+        match ch {
+            '"' => {
+                return Ok((input.advance(byte_offset), ()));
+            }
+            '\\' => {
+                chars.next();
+            }
+            _ => {}
+        }
+    }
+    Err(LexError { line: input.line })
+}
+
+/// Like `cooked_char`, but accepts any escape sequence, including octal and
+/// hexadecimal escapes with any number of digits.
+pub fn skip_cooked_char(input: Cursor) -> PResult<()> {
+    if !input.starts_with("\\") {
+        return cooked_char(input);
+    }
+    let mut chars = input.char_indices().skip(1);
+    if let Some((_, '\n')) | None = chars.next() {
+        return Err(LexError { line: input.line });
+    }
+    for (byte_offset, ch) in chars {
+        match ch {
+            '\'' => return Ok((input.advance(byte_offset), ())),
+            '\n' => break,
+            _ => {}
+        }
+    }
+    Err(LexError { line: input.line })
+}
+
 pub fn cooked_byte_string(mut input: Cursor) -> PResult<()> {
     let mut bytes = input.bytes().enumerate();
     'outer: while let Some((offset, b)) = bytes.next() {
@@ -0,0 +1,507 @@
+//! Checks of the configurations of cpp_build which the test crate does not link.
+//! They run outside of a build script, with the environment that cargo gives to
+//! build scripts set up by `out_dir`.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex, Once};
+
+const MSVC: bool = cfg!(target_env = "msvc");
+
+/// The OUT_DIR shared by all the tests, after setting the environment of a build
+/// script for the host target. It must be called before any use of cpp_build,
+/// which reads the environment once.
+fn out_dir() -> PathBuf {
+    static SETUP: Once = Once::new();
+    let out_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("cpp_build_tests");
+    SETUP.call_once(|| {
+        let _ = std::fs::remove_dir_all(&out_dir);
+        std::fs::create_dir_all(&out_dir).unwrap();
+        let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+        let version = Command::new(rustc).arg("-vV").output().unwrap();
+        let version = String::from_utf8(version.stdout).unwrap();
+        let host = version.lines().find_map(|l| l.strip_prefix("host: ")).unwrap().to_owned();
+        let pointer_width = (std::mem::size_of::<usize>() * 8).to_string();
+        let endian = if cfg!(target_endian = "little") { "little" } else { "big" };
+        let target_env = if MSVC {
+            "msvc"
+        } else if cfg!(target_env = "gnu") {
+            "gnu"
+        } else {
+            ""
+        };
+        for (var, value) in [
+            ("OUT_DIR", out_dir.to_str().unwrap()),
+            ("TARGET", &host),
+            ("HOST", &host),
+            ("OPT_LEVEL", "0"),
+            ("DEBUG", "false"),
+            ("PROFILE", "debug"),
+            ("CARGO_CFG_TARGET_ENV", target_env),
+            ("CARGO_CFG_TARGET_POINTER_WIDTH", &pointer_width),
+            ("CARGO_CFG_TARGET_ENDIAN", endian),
+        ] {
+            std::env::set_var(var, value);
+        }
+    });
+    out_dir
+}
+
+/// Write `source` to the file `name` of OUT_DIR, and return its path
+fn write_source(name: &str, source: &str) -> PathBuf {
+    let path = out_dir().join(name);
+    std::fs::write(&path, source).unwrap();
+    path
+}
+
+/// A crate with a closure, for the checks of the commands
+fn closure_source(name: &str) -> PathBuf {
+    write_source(
+        name,
+        "fn f() -> i32 {\n    unsafe { cpp!([] -> i32 as \"int\" { return 1; }) }\n}\n",
+    )
+}
+
+fn compiler() -> String {
+    std::env::var("CXX").unwrap_or_else(|_| "c++".to_owned())
+}
+
+/// A handler which collects the output of cpp_build and of the compiler in `output`
+fn collect_into(output: &Arc<Mutex<Vec<String>>>) -> Box<dyn FnMut(&str) + Send> {
+    let output = output.clone();
+    Box::new(move |line| output.lock().unwrap().push(line.to_owned()))
+}
+
+#[test]
+fn dry_run() {
+    let root = closure_source("dry_run.rs");
+    let mut dry_run = cpp_build::Config::new();
+    dry_run
+        .lib_name("rust_cpp_dry_run")
+        .define("RUST_CPP_TWICE(x)", Some("((x) * 2)"))
+        .coverage(true)
+        .dry_run(true)
+        .build(&root);
+    assert_eq!(dry_run.dry_run_commands().len(), 1);
+    let command = &dry_run.dry_run_commands()[0];
+    if !MSVC {
+        assert!(command.contains(" --coverage") || command.contains(" -fcoverage-mapping"));
+        // The function-like macro is quoted for the shell (MSVC gets a #define instead)
+        assert!(command.contains(" \"-DRUST_CPP_TWICE(x)=((x) * 2)\" "), "{}", command);
+    }
+    // The path is quoted if OUT_DIR contains a space
+    assert!(command.trim_end_matches('"').ends_with("cpp_closures.cpp"), "{}", command);
+}
+
+#[test]
+fn probe_definitions() {
+    // The probe sees the definitions of the library, with or without a value
+    out_dir();
+    let mut config = cpp_build::Config::new();
+    config
+        .define("PROBE_SIZE", Some("3"))
+        .define("PROBE_FLAG", None)
+        .define("PROBE_TWICE(x)", Some("((x) * 2)"));
+    let probed = cpp_build::probe_type_with_prelude(
+        "#if PROBE_FLAG != 1\n#error PROBE_FLAG\n#endif\nstruct Probed { char c[PROBE_TWICE(PROBE_SIZE)]; };",
+        "Probed",
+        &config,
+    );
+    assert_eq!(probed.size, 6);
+}
+
+#[test]
+#[cfg_attr(target_env = "msvc", ignore)]
+fn probe_compiler_env() {
+    // The probe sees the environment of the compiler too, such as the directories
+    // where GCC and Clang look for headers
+    let include = out_dir().join("compiler_env");
+    std::fs::create_dir_all(&include).unwrap();
+    std::fs::write(include.join("compiler_env.h"), "struct CompilerEnvType { char data[3]; };\n")
+        .unwrap();
+    let mut config = cpp_build::Config::new();
+    config.compiler_env("CPLUS_INCLUDE_PATH", &include);
+    let probed =
+        cpp_build::probe_type_with_prelude("#include <compiler_env.h>", "CompilerEnvType", &config);
+    assert_eq!(probed.size, 3);
+}
+
+#[test]
+fn long_args() {
+    // Many long definitions, and an include directory with a space, are passed in a
+    // response file
+    let spaced_include = out_dir().join("include dir");
+    std::fs::create_dir_all(&spaced_include).unwrap();
+    std::fs::write(spaced_include.join("long_args.h"), "typedef int16_t LongArgsType;\n").unwrap();
+    let mut long_args = cpp_build::Config::new();
+    long_args.include(&spaced_include);
+    for i in 0..200 {
+        long_args.define(
+            &format!("RUST_CPP_LONG_DEFINITION_NUMBER_{}", i),
+            Some("\"a \\\"string\\\"\""),
+        );
+    }
+    let probed = cpp_build::probe_type_with_prelude(
+        "#include \"long_args.h\"\n\
+         static_assert(sizeof(RUST_CPP_LONG_DEFINITION_NUMBER_199) == 11, \"definition\");",
+        "LongArgsType",
+        &long_args,
+    );
+    assert_eq!(probed.size, 2);
+    long_args.lib_name("rust_cpp_long_args").dry_run(true).build(closure_source("long_args.rs"));
+    assert!(long_args.dry_run_commands()[0].contains(" @"), "{:?}", long_args.dry_run_commands());
+}
+
+#[test]
+fn dedup_includes() {
+    // A duplicate include directory is passed once, and the first occurrence decides
+    // which of two headers with the same name is found
+    let first_include = out_dir().join("first_include");
+    let second_include = out_dir().join("second_include");
+    for (dir, ty) in [(&first_include, "int16_t"), (&second_include, "int32_t")] {
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(dir.join("dedup.h"), format!("typedef {} DedupType;\n", ty)).unwrap();
+    }
+    let mut dedup = cpp_build::Config::new();
+    dedup
+        .include(&second_include)
+        .include(&first_include)
+        .include(first_include.join("..").join("second_include"));
+    assert_eq!(
+        cpp_build::probe_type_with_prelude("#include <dedup.h>", "DedupType", &dedup).size,
+        4
+    );
+    dedup.lib_name("rust_cpp_dedup").dry_run(true).build(closure_source("dedup.rs"));
+    let command = &dedup.dry_run_commands()[0];
+    assert_eq!(command.matches("second_include").count(), 1, "{}", command);
+}
+
+#[test]
+fn std17() {
+    // The standard set with Config::std applies to the probe and to the library
+    out_dir();
+    let mut std17 = cpp_build::Config::new();
+    std17.std("c++17");
+    let probed = cpp_build::probe_type_with_prelude(
+        "#if __cplusplus < 201703L && !defined(_MSC_VER)\n#error not C++17\n#endif\ninline int std17 = 1;\nstruct Std17 { char c[5]; };",
+        "Std17",
+        &std17,
+    );
+    assert_eq!(probed.size, 5);
+    std17.lib_name("rust_cpp_std17").dry_run(true).build(closure_source("std17.rs"));
+    let command = &std17.dry_run_commands()[0];
+    if MSVC {
+        assert!(command.contains("/std:c++17"), "{}", command);
+    } else {
+        assert!(command.contains("-std=c++17") && !command.contains("c++11"), "{}", command);
+    }
+}
+
+#[test]
+#[should_panic]
+fn std98() {
+    out_dir();
+    cpp_build::Config::new().std("c++98");
+}
+
+#[test]
+fn stdlib_include() {
+    // The include directory of the standard library is passed to the probe and the library
+    let stdlib_include = out_dir().join("stdlib_include");
+    std::fs::create_dir_all(&stdlib_include).unwrap();
+    std::fs::write(stdlib_include.join("rust_cpp_stdlib.h"), "typedef int64_t StdlibType;\n")
+        .unwrap();
+    let mut stdlib = cpp_build::Config::new();
+    stdlib.stdlib_include(&stdlib_include);
+    assert_eq!(
+        cpp_build::probe_type_with_prelude("#include <rust_cpp_stdlib.h>", "StdlibType", &stdlib)
+            .size,
+        8
+    );
+    stdlib
+        .lib_name("rust_cpp_stdlib_include")
+        .dry_run(true)
+        .build(closure_source("stdlib_include.rs"));
+    if !MSVC {
+        assert!(stdlib.dry_run_commands()[0].contains(" -isystem "));
+    }
+}
+
+#[test]
+#[cfg_attr(target_env = "msvc", ignore)]
+fn optional_flags() {
+    // The library is built without the optional flags which the compiler rejects. The
+    // warnings go to the output handler instead of cargo.
+    let root =
+        write_source("optional_flags.rs", "cpp! {{ int rust_cpp_optional_flags_test = 1; }}\n");
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let lib = cpp_build::Config::new()
+        .lib_name("rust_cpp_optional_flags")
+        .optional_flags(vec!["-Wall".to_owned(), "-fno-such-option-for-rust-cpp".to_owned()])
+        .dump_preprocessed(true)
+        .on_compiler_output(collect_into(&output))
+        .compile(&root);
+    assert!(lib.is_file(), "{} was not built", lib.display());
+    let output = output.lock().unwrap();
+    assert!(output.iter().any(|l| l.contains("does not support one of the optional flags")));
+    // The diagnostics of the compiler, printed by cc, are there too
+    assert!(output.iter().any(|l| l.contains("-fno-such-option-for-rust-cpp")));
+    assert!(output.iter().any(|l| l.contains("the preprocessed C++ code is in")));
+    let preprocessed = out_dir().join("rust_cpp_rust_cpp_optional_flags/cpp_closures.i");
+    let preprocessed = std::fs::read_to_string(preprocessed).unwrap();
+    assert!(preprocessed.contains("int rust_cpp_optional_flags_test = 1;"));
+}
+
+#[test]
+#[cfg_attr(target_env = "msvc", ignore)]
+fn cpp20_modules() {
+    // The snippets are compiled as a C++20 module interface unit, imported by the closures
+    if !supports_cpp20_modules() {
+        return;
+    }
+    let root = write_source(
+        "cpp20_modules.rs",
+        r#"cpp! {{
+    #include <stdint.h>
+    struct ModulePoint { int32_t x; int32_t y; };
+    inline int32_t module_sum(ModulePoint p) { return p.x + p.y; }
+}}
+fn sum() -> i32 {
+    unsafe { cpp!([] -> i32 as "int32_t" { return module_sum(ModulePoint{2, 3}); }) }
+}
+"#,
+    );
+    let lib =
+        cpp_build::Config::new().lib_name("rust_cpp_modules").cpp20_modules(true).compile(&root);
+    assert!(lib.is_file(), "{} was not built", lib.display());
+    let interface = out_dir().join("rust_cpp_rust_cpp_modules/cpp_snippets.cppm");
+    assert!(std::fs::read_to_string(interface).unwrap().contains("export module rust_cpp_"));
+}
+
+#[test]
+fn amalgamation() {
+    // The generated code is also written as a source file and its header, which compile
+    // without the flags of cpp_build
+    let root = write_source(
+        "amalgamation.rs",
+        r#"cpp! {{ struct AmalgamationPair { int a; int b; }; }}
+fn sum() -> i32 {
+    unsafe { cpp!([] -> i32 as "int" { AmalgamationPair p{1, 2}; return p.a + p.b; }) }
+}
+"#,
+    );
+    let amalgamation = out_dir().join("amalgamation");
+    cpp_build::Config::new()
+        .lib_name("rust_cpp_amalgamation")
+        .emit_amalgamation(&amalgamation)
+        .build(&root);
+    let source = std::fs::read_to_string(amalgamation.join("rust_cpp_generated.cpp")).unwrap();
+    assert!(source.contains("#include \"rust_cpp_generated.h\""));
+    assert!(source.contains("struct AmalgamationPair"));
+    assert!(amalgamation.join("rust_cpp_generated.h").is_file());
+    if MSVC {
+        return;
+    }
+    let status = Command::new(compiler())
+        .args(["-std=c++11", "-c", "rust_cpp_generated.cpp", "-o", "rust_cpp_generated.o"])
+        .current_dir(&amalgamation)
+        .status()
+        .unwrap();
+    assert!(status.success(), "the amalgamation does not compile");
+
+    // The generated code reports clearly that it needs C++11
+    let output = Command::new(compiler())
+        .args(["-std=c++98", "-fsyntax-only", "-x", "c++", "rust_cpp_generated.h"])
+        .current_dir(&amalgamation)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("rust-cpp requires C++11"));
+}
+
+const STRICT_TYPES_SOURCE: &str = r#"cpp! {{
+    struct StrictPlain { int a; };
+    struct StrictMixed { public: int a; private: int b; };
+    struct StrictVirtual { virtual ~StrictVirtual() {} };
+}}
+cpp_class!(pub unsafe struct StrictClass as "StrictVirtual");
+fn strict(plain: StrictPlain, mixed: StrictMixed, opaque: StrictClass) {
+    unsafe {
+        cpp!([plain as "StrictPlain", mixed as "StrictMixed", opaque as "StrictVirtual"] {
+            (void)plain; (void)mixed; (void)opaque;
+        })
+    }
+}
+"#;
+
+#[test]
+#[cfg_attr(target_env = "msvc", ignore)]
+fn strict_types() {
+    // With strict_types, a captured type which is not standard-layout is an error at the
+    // location of the closure, unless it is the type of a cpp_class!
+    let root = write_source("strict_types.rs", STRICT_TYPES_SOURCE);
+    cpp_build::Config::new()
+        .lib_name("rust_cpp_strict")
+        .strict_types(true)
+        .dry_run(true)
+        .build(&root);
+    let output = Command::new(compiler())
+        .args(["-std=c++11", "-fsyntax-only", "cpp_closures.cpp"])
+        .current_dir(out_dir().join("rust_cpp_rust_cpp_strict"))
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("strict_types.rs:9"), "{}", stderr);
+    assert!(stderr.contains("`StrictMixed` at the capture site of `mixed`"), "{}", stderr);
+    assert_eq!(stderr.matches("rust-cpp: C++ type").count(), 1, "{}", stderr);
+}
+
+#[test]
+fn intermediate_dir() {
+    // The intermediate files are generated in the configured directory
+    let scratch = out_dir().join("scratch");
+    cpp_build::Config::new()
+        .lib_name("rust_cpp_scratch")
+        .intermediate_dir(&scratch)
+        .build(write_source("scratch.rs", STRICT_TYPES_SOURCE));
+    assert!(scratch.join("rust_cpp_rust_cpp_scratch").join("cpp_closures.cpp").is_file());
+    assert!(!out_dir().join("rust_cpp_rust_cpp_scratch").exists());
+}
+
+#[test]
+fn forbidden_callbacks() {
+    // With forbid_rust_callbacks, the build fails listing the rust! macros of the closures
+    // and of the snippets
+    let root = write_source(
+        "forbidden_callbacks.rs",
+        r#"cpp! {{
+    int snippet() {
+        return rust!(ForbiddenInSnippet [] -> i32 as "int" { 1 });
+    }
+}}
+fn closure() -> i32 {
+    unsafe {
+        cpp!([] -> i32 as "int" {
+            return rust!(ForbiddenInClosure [] -> i32 as "int" { 2 });
+        })
+    }
+}
+"#,
+    );
+    let error = cpp_build::Config::new()
+        .lib_name("rust_cpp_forbidden")
+        .forbid_rust_callbacks(true)
+        .dry_run(true)
+        .try_build(&root)
+        .unwrap_err();
+    let message = error.to_string();
+    assert!(matches!(error, cpp_build::Error::ForbiddenCallbacks(_)), "{}", message);
+    assert!(message.contains("forbidden_callbacks.rs:3\n"), "{}", message);
+    assert!(message.contains("forbidden_callbacks.rs:9"), "{}", message);
+}
+
+#[test]
+fn compile_error() {
+    // try_build returns the failure of the compilation instead of exiting. The
+    // diagnostics go to the handler, instead of being printed as cargo warnings.
+    let root = write_source("compile_error.rs", "cpp! {{\n#error rust-cpp try_build test\n}}\n");
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let error = cpp_build::Config::new()
+        .lib_name("rust_cpp_compile_error")
+        .on_compiler_output(collect_into(&output))
+        .try_build(&root)
+        .unwrap_err();
+    assert!(output.lock().unwrap().iter().any(|l| l.contains("rust-cpp try_build test")));
+    let error = match error {
+        cpp_build::Error::Compile(error) => error,
+        error => panic!("unexpected error: {}", error),
+    };
+    assert!(error.stderr.contains("rust-cpp try_build test"), "{}", error);
+    assert!(error.command.contains("cpp_closures.cpp"), "{}", error.command);
+    assert!(std::fs::read_to_string(&error.generated).unwrap().contains("#error"));
+    assert!(error.hint.is_none(), "{}", error);
+}
+
+#[test]
+fn undeclared_type() {
+    // The failure to compile a closure whose captured C++ type is not declared at
+    // global scope is explained, naming the capture
+    let root = write_source(
+        "undeclared_type.rs",
+        "fn f(x: *const u8) {\n    unsafe { cpp!([x as \"const Undeclared*\"] {}) }\n}\n",
+    );
+    let error = cpp_build::Config::new()
+        .lib_name("rust_cpp_undeclared_type")
+        .on_compiler_output(Box::new(|_| ()))
+        .try_build(&root)
+        .unwrap_err();
+    let hint = match error {
+        cpp_build::Error::Compile(error) => error.hint,
+        error => panic!("unexpected error: {}", error),
+    };
+    let hint = hint.expect("no hint for an undeclared capture type");
+    assert!(hint.contains("the capture `x` at "), "{}", hint);
+    assert!(hint.contains("undeclared_type.rs:2"), "{}", hint);
+}
+
+#[test]
+fn parse_error() {
+    // try_build returns the failure to parse the crate, instead of exiting successfully
+    let root = write_source("parse_error.rs", "fn f( {}\n");
+    let error =
+        cpp_build::Config::new().lib_name("rust_cpp_parse_error").try_build(&root).unwrap_err();
+    assert!(matches!(error, cpp_build::Error::Parse(_)), "{}", error);
+}
+
+#[test]
+fn keep_generated() {
+    // keep_generated copies the generated code out of the cleaned directory, next to
+    // the copy of the previous build
+    let root = closure_source("keep_generated.rs");
+    let output = Arc::new(Mutex::new(Vec::new()));
+    for _ in 0..2 {
+        cpp_build::Config::new()
+            .lib_name("rust_cpp_kept")
+            .keep_generated(true)
+            .on_compiler_output(collect_into(&output))
+            .dry_run(true)
+            .build(&root);
+    }
+    let kept = out_dir().join("rust_cpp_kept_generated.cpp");
+    let written = format!("generated C++ written to {}", kept.display());
+    assert_eq!(output.lock().unwrap().iter().filter(|m| m.contains(&written)).count(), 2);
+    assert_eq!(
+        std::fs::read_to_string(&kept).unwrap(),
+        std::fs::read_to_string(out_dir().join("rust_cpp_kept_generated.previous.cpp")).unwrap()
+    );
+}
+
+#[test]
+#[cfg_attr(target_env = "msvc", ignore)]
+fn freestanding() {
+    // The internal structures do not include the hosted headers in freestanding mode
+    out_dir();
+    let mut freestanding = cpp_build::Config::new();
+    freestanding.freestanding(true);
+    let probed = cpp_build::probe_type_with_prelude(
+        "#if defined(assert) || defined(EXIT_FAILURE)\n#error hosted header included\n#endif",
+        "rustcpp::Slice<uint16_t>",
+        &freestanding,
+    );
+    assert_eq!(probed.size, 2 * std::mem::size_of::<usize>());
+}
+
+/// Whether the C++ compiler is GCC 11 or Clang 16, or a later version
+fn supports_cpp20_modules() -> bool {
+    let output = |arg: &str| {
+        Command::new(compiler())
+            .arg(arg)
+            .output()
+            .map(|o| String::from_utf8_lossy(&o.stdout).into_owned())
+            .unwrap_or_default()
+    };
+    let version = output("-dumpversion");
+    let major = version.trim().split('.').next().and_then(|v| v.parse::<u32>().ok());
+    major.unwrap_or(0) >= if output("--version").contains("clang") { 16 } else { 11 }
+}
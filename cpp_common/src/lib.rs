@@ -1,11 +1,33 @@
 //! Implementation detail for the `cpp` crate.
 //!
-//! The purpose of this crate is only to allow sharing code between the
+//! The purpose of this crate is mainly to allow sharing code between the
 //! `cpp_build` and the `cpp_macros` crates.
+//!
+//! # Stable API
+//!
+//! Tools which generate code for the `cpp!` closures of a crate can rely on the
+//! following items, which follow semver:
+//!
+//! - the `Parse` implementation of [`Capture`], and its fields `mutable`, `name`
+//!   and `cpp`, as well as the capture mode fields `constant`, `arc`, `moved` and
+//!   `by_value`: a capture with one of them set is not passed by reference, so a
+//!   tool must handle it, or reject it;
+//! - the `Parse` implementation of [`Closure`], its field `sig`, and the fields
+//!   `captures`, `ret`, `cpp` and `std_body` of [`ClosureSig`];
+//! - [`ClosureSig::name_hash`] and [`ClosureSig::extern_name`], which give the
+//!   name of the `extern "C"` function generated for a closure by `cpp_build`
+//!   and called by `cpp_macros` of the same version.
+//!
+//! The values of the hashes are not stable: they can change between versions
+//! of `cpp_common` and `syn`, so they must be computed with the version used by
+//! the crate. These structures are `#[non_exhaustive]`: new fields may be added
+//! in minor versions, so they can only be obtained by parsing. Everything else is
+//! an implementation detail.
 
 #[macro_use]
 extern crate syn;
 extern crate proc_macro2;
+extern crate siphasher;
 
 #[macro_use]
 extern crate lazy_static;
@@ -16,6 +38,7 @@ use std::hash::{Hash, Hasher};
 use std::path::PathBuf;
 
 use proc_macro2::{Span, TokenStream, TokenTree};
+use siphasher::sip128::{Hasher128, SipHasher13};
 use syn::ext::IdentExt;
 use syn::parse::{Parse, ParseStream, Result};
 use syn::{Attribute, Ident, Type};
@@ -25,17 +48,118 @@ pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 pub const LIB_NAME: &str = "librust_cpp_generated.a";
 pub const MSVC_LIB_NAME: &str = "rust_cpp_generated.lib";
 
+/// The name of the library built by `cpp_build` when no other name is configured.
+pub const DEFAULT_LIB_NAME: &str = "rust_cpp_generated";
+
+/// Environment variable set by `cpp_build` for the compilation of the crate. It
+/// contains the comma separated list of the library names which were built, in
+/// the order in which `cpp_macros` looks them up.
+pub const LIB_NAMES_ENV: &str = "RUST_CPP_LIB_NAMES";
+
+/// Environment variable set by `cpp_build` for the compilation of the crate, with
+/// the endianness of the target (`CARGO_CFG_TARGET_ENDIAN`), which is only set for
+/// build scripts.
+pub const TARGET_ENDIAN_ENV: &str = "RUST_CPP_TARGET_ENDIAN";
+
+/// Whether the metadata written by the C++ compiler is big endian, from the value of
+/// its `endianness_check` field (`0xffef`) read as little endian. The metadata has
+/// the endianness of the target, which may differ from the host reading it. When
+/// `target_endian` is known (`"little"` or `"big"`), the metadata must match it,
+/// otherwise the C++ code was compiled for another target and this panics.
+pub fn metadata_is_big_endian(check: u64, target_endian: Option<&str>) -> bool {
+    let big_endian = match check {
+        0xffef => false,
+        0xefff_0000_0000_0000 => true,
+        _ => panic!("Endianness check value matches neither little nor big endian."),
+    };
+    let endian = if big_endian { "big" } else { "little" };
+    if let Some(target) = target_endian {
+        assert!(
+            target == endian,
+            r#"
+-- rust-cpp fatal error --
+
+The C++ library is {} endian, but the target is {} endian.
+NOTE: Make sure that the C++ compiler targets the same architecture as rustc (the cc crate reads the CXX_<target> environment variable)."#,
+            endian,
+            target
+        );
+    }
+    big_endian
+}
+
+/// File name of the static library called `lib_name` on non-MSVC targets.
+pub fn lib_file_name(lib_name: &str) -> String {
+    format!("lib{}.a", lib_name)
+}
+
+/// File name of the static library called `lib_name` on MSVC targets.
+pub fn msvc_lib_file_name(lib_name: &str) -> String {
+    format!("{}.lib", lib_name)
+}
+
 pub mod flags {
     pub const IS_COPY_CONSTRUCTIBLE: u32 = 0;
     pub const IS_DEFAULT_CONSTRUCTIBLE: u32 = 1;
     pub const IS_TRIVIALLY_DESTRUCTIBLE: u32 = 2;
     pub const IS_TRIVIALLY_COPYABLE: u32 = 3;
     pub const IS_TRIVIALLY_DEFAULT_CONSTRUCTIBLE: u32 = 4;
+    /// Set by cpp_build on a capture whose Rust type is a generic parameter
+    pub const IS_GENERIC_CAPTURE: u32 = 5;
+    /// Set by cpp_build on the return type of a closure returning an enum deriving `CppEnum`
+    pub const IS_CHECKED_ENUM: u32 = 6;
+    pub const IS_ENUM: u32 = 7;
+}
+
+/// Mapping between some Rust primitive types and the C++ types which must be
+/// used to capture or return them.
+///
+/// `cpp_build` checks that these C++ types have the expected width on the
+/// target.
+pub const PRIMITIVE_TYPES: &[(&str, &[&str])] = &[
+    ("usize", &["size_t", "std::size_t", "uintptr_t", "std::uintptr_t"]),
+    ("isize", &["ptrdiff_t", "std::ptrdiff_t", "intptr_t", "std::intptr_t"]),
+    ("f16", &["_Float16", "__fp16", "std::float16_t"]),
+    ("char", &["char32_t"]),
+];
+
+/// The width in bits of a Rust type of the `PRIMITIVE_TYPES` table, given the
+/// width of a pointer on the target, if it is known.
+pub fn primitive_width(rust: &str, pointer_width: Option<u32>) -> Option<u32> {
+    match rust {
+        "f16" => Some(16),
+        "char" => Some(32),
+        _ => pointer_width,
+    }
+}
+
+/// Return the Rust primitive type which corresponds to the given C++ type in
+/// the `PRIMITIVE_TYPES` table, if any. A `const` qualifier is ignored.
+pub fn rust_primitive_for(cpp: &str) -> Option<&'static str> {
+    let cpp = cpp.trim();
+    let cpp = cpp.strip_prefix("const ").unwrap_or(cpp).trim();
+    PRIMITIVE_TYPES.iter().find(|(_, cpp_types)| cpp_types.contains(&cpp)).map(|(rust, _)| *rust)
+}
+
+/// Whether the C++ type is a pointer to `wchar_t`, or a `rustcpp::Slice` or a
+/// `std::span` of `wchar_t`, whose elements must have the size of `wchar_t` on the
+/// Rust side. The metadata of such a capture holds `sizeof(wchar_t)` in its upper
+/// 32 bits.
+pub fn is_wide_char_pointer(cpp: &str) -> bool {
+    let cpp = cpp.split_whitespace().collect::<String>();
+    let slice = cpp.strip_prefix("rustcpp::Slice<").or_else(|| cpp.strip_prefix("std::span<"));
+    let element = match slice {
+        Some(slice) => slice.strip_suffix('>'),
+        None => cpp.strip_suffix('*'),
+    };
+    element.map(|e| e.trim_start_matches("const").trim_end_matches("const")) == Some("wchar_t")
 }
 
 pub mod kw {
     #![allow(non_camel_case_types)]
     custom_keyword!(rust);
+    custom_keyword!(arc);
+    custom_keyword!(copy);
 }
 
 /// This constant is expected to be a unique string within the compiled binary
@@ -73,63 +197,234 @@ NOTE: rustc must be run by Cargo."#
     };
 }
 
+/// Hash used to name the per-library symbols (the metadata and the `rust!`
+/// callbacks array) of the library called `lib_name`, so that several libraries
+/// can be linked in the same crate.
+/// The default library keeps using `FILE_HASH`.
+pub fn lib_hash(lib_name: &str) -> u64 {
+    if lib_name == DEFAULT_LIB_NAME {
+        return *FILE_HASH;
+    }
+    let mut hasher = DefaultHasher::new();
+    OUT_DIR.hash(&mut hasher);
+    lib_name.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The 128 bit hash naming the generated symbols of a closure, a class or a
+/// `rust!` callback, and identifying their entries in the metadata.
+///
+/// It is the SipHash-1-3 of `value` with null keys, which does not depend on the
+/// version of rustc, unlike `DefaultHasher`. It only needs to match between
+/// `cpp_build` and `cpp_macros` of the same version: the hashed values are syn
+/// types, so the hashes can change with the versions of rust-cpp and syn.
+/// Identical closures have the same hash and share the same generated function.
+pub fn name_hash<T: Hash + ?Sized>(value: &T) -> u128 {
+    let mut hasher = SipHasher13::new();
+    value.hash(&mut hasher);
+    hasher.finish128().as_u128()
+}
+
+/// A variable captured by a `cpp!` closure, such as `mut foo as "int"`
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
+#[non_exhaustive]
 pub struct Capture {
+    /// The capture is `mut`, and passed to C++ by non-const reference
     pub mutable: bool,
+    /// A `const` capture is a Rust constant whose value is put in the C++ code
+    /// by the build script, instead of being passed at runtime.
+    pub constant: bool,
+    /// An `arc` capture is an `Arc<T>` which is cloned, and whose pointer from
+    /// `Arc::into_raw` is passed to the C++ code, which then owns that reference.
+    pub arc: bool,
+    /// A `move` capture is an `Option<Box<T>>` which is consumed: the pointer from
+    /// `Box::into_raw`, or null for `None`, is passed to the C++ code, which then
+    /// owns the box.
+    pub moved: bool,
+    /// A `copy` capture is a pointer passed by value, instead of by reference: the
+    /// C++ parameter is a copy of it, which the C++ code may modify.
+    pub by_value: bool,
+    /// The name of the Rust variable, which is also the name of the C++ parameter
     pub name: Ident,
+    /// The C++ type, without the `&` of a reference, as captures are always
+    /// passed by reference
     pub cpp: String,
 }
 
 impl Parse for Capture {
     /// Parse a single captured variable inside within a `cpp!` macro.
-    /// Example: `mut foo as "int"`
+    /// Example: `mut foo as "int"`, `const FOO as "int"`, `arc foo as "const void*"`,
+    /// `move foo as "Foo*"` or `copy foo as "void*"`
     fn parse(input: ParseStream) -> Result<Self> {
+        let constant = input.parse::<Option<Token![const]>>()?.is_some();
+        // `arc as "int"` captures a variable called `arc`
+        let arc = !constant && input.peek(kw::arc) && input.peek2(Ident);
+        if arc {
+            input.parse::<kw::arc>()?;
+        }
+        let moved = !constant && !arc && input.parse::<Option<Token![move]>>()?.is_some();
+        let by_value = !constant && !arc && !moved && input.peek(kw::copy) && input.peek2(Ident);
+        if by_value {
+            input.parse::<kw::copy>()?;
+        }
+        let mutable = !constant && !arc && !moved && !by_value;
         Ok(Capture {
-            mutable: input.parse::<Option<Token![mut]>>()?.is_some(),
+            mutable: mutable && input.parse::<Option<Token![mut]>>()?.is_some(),
+            constant,
+            arc,
+            moved,
+            by_value,
             name: input.call(Ident::parse_any)?,
             cpp: {
                 input.parse::<Token![as]>()?;
-                input.parse::<syn::LitStr>()?.value()
+                let cpp = input.parse::<syn::LitStr>()?.value();
+                // Captures are always passed by reference, so `T&` is the same as `T`
+                match cpp.trim_end().strip_suffix('&') {
+                    Some(t) if !t.ends_with('&') => t.trim_end().to_owned(),
+                    _ => cpp,
+                }
             },
         })
     }
 }
 
+/// The signature of a `cpp!` closure, which identifies the generated function
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
+#[non_exhaustive]
 pub struct ClosureSig {
+    /// The captured variables, in order
     pub captures: Vec<Capture>,
+    /// The Rust return type, `None` for `void`
     pub ret: Option<Type>,
+    /// The C++ return type, `"void"` if there is none
     pub cpp: String,
+    /// The closure is marked `noexcept`
+    pub noexcept: bool,
+    /// The headers of the `#[cpp_include("<header.h>")]` attributes
+    pub includes: Vec<String>,
+    /// The C++ code of the body without any whitespace, so that the closures
+    /// which only differ by their body have different names
     pub std_body: String,
 }
 
 impl ClosureSig {
-    pub fn name_hash(&self) -> u64 {
-        // XXX: Use a better hasher than the default?
-        let mut hasher = DefaultHasher::new();
-        self.hash(&mut hasher);
-        hasher.finish()
+    /// The hash of the signature, see [`name_hash`](fn@name_hash)
+    pub fn name_hash(&self) -> u128 {
+        name_hash(self)
     }
 
+    /// The name of the `extern "C"` function generated for the closure
     pub fn extern_name(&self) -> Ident {
         Ident::new(&format!("__cpp_closure_{}", self.name_hash()), Span::call_site())
     }
+
+    /// Returns the wrapper if the closure returns `std::optional<T>` as an
+    /// `Option<T>`, or `std::expected<T, E>` as a `Result<T, E>`.
+    pub fn return_wrapper(&self) -> Option<ReturnWrapper> {
+        let segment = match &self.ret {
+            Some(Type::Path(p)) if p.qself.is_none() => p.path.segments.last()?,
+            _ => return None,
+        };
+        let rust = match &segment.arguments {
+            syn::PathArguments::AngleBracketed(a) => a
+                .args
+                .iter()
+                .map(|x| match x {
+                    syn::GenericArgument::Type(t) => Some(t.clone()),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()?,
+            _ => return None,
+        };
+        let cpp = self.cpp.trim();
+        if let Some(inner) = cpp.strip_prefix("std::optional<").and_then(|x| x.strip_suffix('>')) {
+            if segment.ident != "Option" || rust.len() != 1 {
+                return None;
+            }
+            return Some(ReturnWrapper::Optional {
+                rust: rust[0].clone(),
+                cpp: inner.trim().to_owned(),
+            });
+        }
+        if let Some(inner) = cpp.strip_prefix("std::expected<").and_then(|x| x.strip_suffix('>')) {
+            if segment.ident != "Result" || rust.len() != 2 {
+                return None;
+            }
+            // Split the template arguments on the comma which is not nested
+            let mut depth = 0;
+            let comma = inner.char_indices().find(|&(_, c)| {
+                match c {
+                    '<' | '(' | '[' => depth += 1,
+                    '>' | ')' | ']' => depth -= 1,
+                    _ => {}
+                }
+                c == ',' && depth == 0
+            })?;
+            return Some(ReturnWrapper::Expected {
+                rust: rust[0].clone(),
+                cpp: inner[..comma.0].trim().to_owned(),
+                rust_error: rust[1].clone(),
+                cpp_error: inner[comma.0 + 1..].trim().to_owned(),
+            });
+        }
+        None
+    }
+}
+
+/// A C++ type returned by a `cpp!` closure which is decoded into the
+/// corresponding Rust type, see `ClosureSig::return_wrapper`.
+/// Only the value (and the error) are passed from C++ to Rust, so their
+/// sizes are checked rather than the size of the wrapper itself.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReturnWrapper {
+    /// `std::optional<cpp>` returned as `Option<rust>`
+    Optional { rust: Type, cpp: String },
+    /// `std::expected<cpp, cpp_error>` returned as `Result<rust, rust_error>`
+    Expected { rust: Type, cpp: String, rust_error: Type, cpp_error: String },
 }
 
+/// A `cpp!` closure, as parsed by `cpp_build`
 #[derive(Clone, Debug)]
+#[non_exhaustive]
 pub struct Closure {
     pub sig: ClosureSig,
     pub body: TokenTree,
     pub body_str: String, // with `rust!` macro replaced
     pub callback_offset: u32,
+    pub location: String, // `path:line:column` of the macro, for diagnostics
+    pub line: String,     // the #line directive pointing to the macro
+    /// The captures whose Rust type is a generic parameter of the enclosing function
+    pub generic_captures: Vec<String>,
 }
 
 impl Parse for Closure {
     /// Parse the inside of a `cpp!` macro when this macro is a closure.
-    /// Example: `unsafe [foo as "int"] -> u32 as "int" { /*... */ }
+    /// Example: `unsafe #[cpp_include("<foo.h>")] [foo as "int"] -> u32 as "int" noexcept { /*... */ }
     fn parse(input: ParseStream) -> Result<Self> {
         input.parse::<Option<Token![unsafe]>>()?;
 
+        // Headers which are only needed by this closure
+        let mut includes = Vec::new();
+        for attr in input.call(Attribute::parse_outer)? {
+            if !attr.path().is_ident("cpp_include") {
+                return Err(syn::Error::new_spanned(
+                    attr,
+                    "unsupported attribute on a cpp! closure, only `cpp_include` is allowed",
+                ));
+            }
+            let header = attr.parse_args::<syn::LitStr>()?;
+            let value = header.value();
+            let quoted = |open, close| value.starts_with(open) && value.ends_with(close);
+            if value.len() < 3 || !(quoted('<', '>') || quoted('"', '"')) {
+                return Err(syn::Error::new_spanned(
+                    header,
+                    r#"the header must be written as "<header.h>" or "\"header.h\"""#,
+                ));
+            }
+            includes.push(value);
+        }
+
         // Capture
         let capture_content;
         bracketed!(capture_content in input);
@@ -149,16 +444,25 @@ impl Parse for Closure {
             (None, "void".to_owned())
         };
 
+        // Optional noexcept specifier
+        let noexcept = matches!(input.fork().parse::<Ident>(), Ok(i) if i == "noexcept");
+        if noexcept {
+            input.parse::<Ident>()?;
+        }
+
         let body = input.parse::<TokenTree>()?;
         // Need to filter the spaces because there is a difference between
         // proc_macro2 and proc_macro and the hashes would not match
         let std_body = body.to_string().chars().filter(|x| !x.is_whitespace()).collect();
 
         Ok(Closure {
-            sig: ClosureSig { captures, ret, cpp, std_body },
+            sig: ClosureSig { captures, ret, cpp, noexcept, includes, std_body },
             body,
             body_str: String::new(),
             callback_offset: 0,
+            location: String::new(),
+            line: String::new(),
+            generic_captures: Vec::new(),
         })
     }
 }
@@ -169,14 +473,64 @@ pub struct Class {
     pub cpp: String,
     pub attrs: Vec<Attribute>,
     pub line: String, // the #line directive
+    /// The fields given in braces after the C++ type, which get accessor methods
+    pub fields: Vec<ClassField>,
+}
+
+/// A field of a `cpp_class!`, declared as `pub name: RustType as "CppType"`
+#[derive(Clone, Debug)]
+pub struct ClassField {
+    pub vis: syn::Visibility,
+    pub name: Ident,
+    pub rust: Type,
+    pub cpp: String,
+}
+
+impl Parse for ClassField {
+    fn parse(input: ParseStream) -> Result<Self> {
+        Ok(ClassField {
+            vis: input.parse()?,
+            name: input.parse()?,
+            rust: {
+                input.parse::<Token![:]>()?;
+                input.parse()?
+            },
+            cpp: {
+                input.parse::<Token![as]>()?;
+                input.parse::<syn::LitStr>()?.value()
+            },
+        })
+    }
 }
 
 impl Class {
-    pub fn name_hash(&self) -> u64 {
-        let mut hasher = DefaultHasher::new();
-        self.name.hash(&mut hasher);
-        self.cpp.hash(&mut hasher);
-        hasher.finish()
+    pub fn name_hash(&self) -> u128 {
+        name_hash(&(&self.name, &self.cpp))
+    }
+
+    /// Whether the class has the `#[rustcpp::no_drop]` attribute
+    pub fn no_drop(&self) -> bool {
+        self.attrs.iter().any(|x| {
+            let segments = &x.path().segments;
+            segments.len() == 2 && segments[0].ident == "rustcpp" && segments[1].ident == "no_drop"
+        })
+    }
+
+    /// The C++ types of the arguments of the constructors given with the
+    /// `#[rustcpp::from("const char*", "size_t")]` attributes, which implement `From`
+    pub fn from_constructors(&self) -> Result<Vec<Vec<String>>> {
+        let mut result = Vec::new();
+        for attr in &self.attrs {
+            let segments = &attr.path().segments;
+            if segments.len() == 2 && segments[0].ident == "rustcpp" && segments[1].ident == "from"
+            {
+                let args = attr.parse_args_with(
+                    syn::punctuated::Punctuated::<syn::LitStr, Token![,]>::parse_terminated,
+                )?;
+                result.push(args.iter().map(|a| a.value()).collect());
+            }
+        }
+        Ok(result)
     }
 
     pub fn derives(&self, i: &str) -> bool {
@@ -198,7 +552,7 @@ impl Class {
 
 impl Parse for Class {
     /// Parse the inside of a `cpp_class!` macro.
-    /// Example: `#[derive(Default)] pub unsafe struct Foobar as "FooBar"`
+    /// Example: `#[derive(Default)] pub unsafe struct Foobar as "FooBar" { pub x: i32 as "int" }`
     fn parse(input: ParseStream) -> Result<Self> {
         Ok(Class {
             attrs: input.call(Attribute::parse_outer)?,
@@ -213,6 +567,13 @@ impl Parse for Class {
                 input.parse::<syn::LitStr>()?.value()
             },
             line: String::new(),
+            fields: if input.peek(syn::token::Brace) {
+                let content;
+                braced!(content in input);
+                content.parse_terminated(ClassField::parse, Token![,])?.into_iter().collect()
+            } else {
+                Vec::new()
+            },
         })
     }
 }
@@ -241,8 +602,19 @@ pub struct RustInvocation {
     pub begin: Span,
     pub end: Span,
     pub id: Ident,
-    pub return_type: Option<String>,
-    pub arguments: Vec<(Ident, String)>, // Vec of name and type
+    pub return_type: Option<(Type, String)>, // Rust and C++ type
+    pub arguments: Vec<(Ident, Type, String)>, // Vec of name, Rust type and C++ type
+    /// The Rust return type is a reference, which the callback returns as a pointer
+    /// instead of writing it to the return value
+    pub return_reference: bool,
+}
+
+impl RustInvocation {
+    /// Hash of the metadata of the argument and return types, which is found
+    /// from the unique identifier of the callback.
+    pub fn name_hash(&self) -> u128 {
+        name_hash(&self.id.to_string())
+    }
 }
 
 impl Parse for RustInvocation {
@@ -252,7 +624,7 @@ impl Parse for RustInvocation {
         input.parse::<Token![!]>()?;
         let macro_content;
         let p = parenthesized!(macro_content in input);
-        let r = RustInvocation {
+        let mut r = RustInvocation {
             begin: rust_token.span,
             end: p.span.close(),
             id: macro_content.parse()?,
@@ -261,13 +633,13 @@ impl Parse for RustInvocation {
                 bracketed!(capture_content in macro_content);
                 capture_content
                     .parse_terminated(
-                        |input: ParseStream| -> Result<(Ident, String)> {
+                        |input: ParseStream| -> Result<(Ident, Type, String)> {
                             let i = input.call(Ident::parse_any)?;
                             input.parse::<Token![:]>()?;
-                            input.parse::<Type>()?;
+                            let t = input.parse::<Type>()?;
                             input.parse::<Token![as]>()?;
                             let s = input.parse::<syn::LitStr>()?.value();
-                            Ok((i, s))
+                            Ok((i, t, s))
                         },
                         Token![,],
                     )?
@@ -276,13 +648,15 @@ impl Parse for RustInvocation {
             },
             return_type: if macro_content.peek(Token![->]) {
                 macro_content.parse::<Token![->]>()?;
-                macro_content.parse::<Type>()?;
+                let t = macro_content.parse::<Type>()?;
                 macro_content.parse::<Token![as]>()?;
-                Some(macro_content.parse::<syn::LitStr>()?.value())
+                Some((t, macro_content.parse::<syn::LitStr>()?.value()))
             } else {
                 None
             },
+            return_reference: false,
         };
+        r.return_reference = matches!(r.return_type, Some((Type::Reference(_), _)));
         macro_content.parse::<TokenTree>()?;
         Ok(r)
     }
@@ -10,17 +10,22 @@ extern crate syn;
 extern crate proc_macro;
 use proc_macro2::Span;
 
-use cpp_common::{flags, kw, RustInvocation, FILE_HASH, LIB_NAME, MSVC_LIB_NAME, OUT_DIR, VERSION};
+use cpp_common::{
+    flags, is_wide_char_pointer, kw, lib_file_name, lib_hash, metadata_is_big_endian,
+    msvc_lib_file_name, rust_primitive_for, ReturnWrapper, RustInvocation, DEFAULT_LIB_NAME,
+    LIB_NAMES_ENV, OUT_DIR, PRIMITIVE_TYPES, TARGET_ENDIAN_ENV, VERSION,
+};
 use std::collections::HashMap;
 use std::iter::FromIterator;
 use syn::parse::Parser;
+use syn::spanned::Spanned;
 use syn::Ident;
 
 use byteorder::{BigEndian, ByteOrder, LittleEndian, ReadBytesExt};
 use lazy_static::lazy_static;
 use quote::{quote, quote_spanned};
 use std::fs::File;
-use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::io::{self, Read};
 
 struct MetaData {
     size: usize,
@@ -33,59 +38,101 @@ impl MetaData {
     }
 }
 
+/// The metadata read from one of the libraries built by `cpp_build`
+struct LibMetaData {
+    name: String,
+    data: HashMap<u128, Vec<MetaData>>,
+}
+
 lazy_static! {
-    static ref METADATA: HashMap<u64, Vec<MetaData>> = {
-        let file = match open_lib_file() {
-            Ok(x) => x,
-            Err(e) => {
-                #[cfg(not(feature = "docs-only"))]
-                panic!(
-                    r#"
+    static ref METADATA: Vec<LibMetaData> = lib_names()
+        .into_iter()
+        .map(|name| {
+            let data = read_lib_metadata(&name);
+            LibMetaData { name, data }
+        })
+        .collect();
+}
+
+/// The names of the libraries built by `cpp_build`, in the order they were built.
+fn lib_names() -> Vec<String> {
+    match std::env::var(LIB_NAMES_ENV) {
+        Ok(names) if !names.is_empty() => names.split(',').map(String::from).collect(),
+        _ => vec![DEFAULT_LIB_NAME.to_owned()],
+    }
+}
+
+fn read_lib_metadata(lib_name: &str) -> HashMap<u128, Vec<MetaData>> {
+    let file = match open_lib_file(lib_name) {
+        Ok(x) => x,
+        Err(e) => {
+            #[cfg(not(feature = "docs-only"))]
+            panic!(
+                r#"
 -- rust-cpp fatal error --
 
 Failed to open the target library file.
 NOTE: Did you make sure to add the rust-cpp build script?
 {}"#,
-                    e
-                );
-                #[cfg(feature = "docs-only")]
-                {
-                    eprintln!("Error while opening target library: {}", e);
-                    return Default::default();
-                };
-            }
-        };
+                e
+            );
+            #[cfg(feature = "docs-only")]
+            {
+                eprintln!("Error while opening target library: {}", e);
+                return Default::default();
+            };
+        }
+    };
 
-        read_metadata(file).expect(
-            r#"
+    read_metadata(file).expect(
+        r#"
 -- rust-cpp fatal error --
 
 I/O error while reading metadata from target library file."#,
-        )
-    };
+    )
+}
+
+/// Find the metadata for the given hash. The libraries are searched in the order
+/// in which they were built, and the first one which contains the hash is used.
+/// Returns the name of that library along with the metadata.
+fn find_metadata(hash: u128) -> Option<(&'static str, &'static [MetaData])> {
+    METADATA.iter().find_map(|lib| lib.data.get(&hash).map(|x| (&*lib.name, &x[..])))
 }
 
 /// NOTE: This panics when it can produce a better error message
-fn read_metadata(file: File) -> io::Result<HashMap<u64, Vec<MetaData>>> {
-    let mut file = BufReader::new(file);
-    let end = {
-        const AUTO_KEYWORD: &[&[u8]] = &[&cpp_common::STRUCT_METADATA_MAGIC];
-        let aut = aho_corasick::AhoCorasick::new(AUTO_KEYWORD).unwrap();
-        let found = aut.stream_find_iter(&mut file).next().expect(
-            r#"
+///
+/// The library contains the metadata of all the closures and classes, and one
+/// more metadata structure for each `rust!` callback.
+fn read_metadata(mut file: File) -> io::Result<HashMap<u128, Vec<MetaData>>> {
+    let mut lib = Vec::new();
+    file.read_to_end(&mut lib)?;
+
+    const AUTO_KEYWORD: &[&[u8]] = &[&cpp_common::STRUCT_METADATA_MAGIC];
+    let aut = aho_corasick::AhoCorasick::new(AUTO_KEYWORD).unwrap();
+    let mut metadata = HashMap::new();
+    let mut found_any = false;
+    for found in aut.find_iter(&lib) {
+        found_any = true;
+        for (hash, entries) in read_metadata_at(&lib[found.end()..])? {
+            metadata.entry(hash).or_insert(entries);
+        }
+    }
+    assert!(
+        found_any,
+        r#"
 -- rust-cpp fatal error --
 
 Struct metadata not present in target library file.
-NOTE: Double-check that the version of cpp_build and cpp_macros match"#,
-        )?;
-        found.end()
-    };
-    file.seek(SeekFrom::Start(end as u64))?;
+NOTE: Double-check that the version of cpp_build and cpp_macros match"#
+    );
+    Ok(metadata)
+}
 
+fn read_metadata_at(mut data: &[u8]) -> io::Result<HashMap<u128, Vec<MetaData>>> {
     // Read & convert the version buffer into a string & compare with our
     // version.
     let mut version_buf = [0; 16];
-    file.read_exact(&mut version_buf)?;
+    data.read_exact(&mut version_buf)?;
     let version =
         version_buf.iter().take_while(|b| **b != b'\0').map(|b| *b as char).collect::<String>();
 
@@ -96,26 +143,59 @@ NOTE: Double-check that the version of cpp_build and cpp_macros match"#,
 
 Version mismatch between cpp_macros and cpp_build for same crate."#
     );
-    let endianness_check = file.read_u64::<LittleEndian>()?;
-    if endianness_check == 0xffef {
-        read_metadata_rest::<LittleEndian>(file)
-    } else if endianness_check == 0xefff000000000000 {
-        read_metadata_rest::<BigEndian>(file)
+    // The metadata has the byte order of the target, not of the host running this macro
+    let endianness_check = data.read_u64::<LittleEndian>()?;
+    let target_endian = std::env::var(TARGET_ENDIAN_ENV).ok();
+    if metadata_is_big_endian(endianness_check, target_endian.as_deref()) {
+        read_metadata_rest::<BigEndian>(data)
     } else {
-        panic!("Endianness check value matches neither little nor big endian.");
+        read_metadata_rest::<LittleEndian>(data)
+    }
+}
+
+#[test]
+fn test_read_big_endian_metadata() {
+    let mut data = [0u8; 16].to_vec();
+    data[..VERSION.len()].copy_from_slice(VERSION.as_bytes());
+    for word in [0xffef, 1, 0x0102, 0x0304, 24, 8, 1 << 3] {
+        data.extend_from_slice(&u64::to_be_bytes(word));
     }
+    let metadata = read_metadata_at(&data).unwrap();
+    assert_eq!(metadata.len(), 1);
+    let entries = &metadata[&(0x0102 << 64 | 0x0304)];
+    assert_eq!((entries[0].size, entries[0].align, entries[0].flags), (24, 8, 1 << 3));
+}
+
+#[test]
+fn test_integer_size_check() {
+    let capture: cpp_common::Capture = syn::parse_str("n as \"int\"").unwrap();
+    assert!(is_integer_type(&capture.cpp));
+    let check = integer_size_check(&capture, 4, &quote!(n)).to_string();
+    assert!(check.contains(
+        "argument `n` has the width of a pointer, but is captured as the C++ type `int` which \
+         does not have the same size. Capture a `usize` as one of: size_t, std::size_t, \
+         uintptr_t, std::uintptr_t; and an `isize` as one of: ptrdiff_t, std::ptrdiff_t, \
+         intptr_t, std::intptr_t"
+    ));
+    assert!(!is_integer_type("size_t"));
+    assert!(is_integer_type("const unsigned long long"));
+    assert!(is_integer_type("std::uint32_t"));
+}
+
+#[test]
+#[should_panic(expected = "is big endian, but the target is little endian")]
+fn test_metadata_endianness_mismatch() {
+    metadata_is_big_endian(0xefff_0000_0000_0000, Some("little"));
 }
 
-fn read_metadata_rest<E: ByteOrder>(
-    mut file: BufReader<File>,
-) -> io::Result<HashMap<u64, Vec<MetaData>>> {
-    let length = file.read_u64::<E>()?;
+fn read_metadata_rest<E: ByteOrder>(mut data: &[u8]) -> io::Result<HashMap<u128, Vec<MetaData>>> {
+    let length = data.read_u64::<E>()?;
     let mut metadata = HashMap::new();
     for _ in 0..length {
-        let hash = file.read_u64::<E>()?;
-        let size = file.read_u64::<E>()? as usize;
-        let align = file.read_u64::<E>()? as usize;
-        let flags = file.read_u64::<E>()?;
+        let hash = (u128::from(data.read_u64::<E>()?) << 64) | u128::from(data.read_u64::<E>()?);
+        let size = data.read_u64::<E>()? as usize;
+        let align = data.read_u64::<E>()? as usize;
+        let flags = data.read_u64::<E>()?;
 
         metadata.entry(hash).or_insert_with(Vec::new).push(MetaData { size, align, flags });
     }
@@ -123,13 +203,13 @@ fn read_metadata_rest<E: ByteOrder>(
 }
 
 /// Try to open a file handle to the lib file. This is used to scan it for
-/// metadata. We check both `MSVC_LIB_NAME` and `LIB_NAME`, in case we are on
-/// or are targeting Windows.
-fn open_lib_file() -> io::Result<File> {
-    if let Ok(file) = File::open(OUT_DIR.join(MSVC_LIB_NAME)) {
+/// metadata. We check both the MSVC and the regular file names, in case we are
+/// on or are targeting Windows.
+fn open_lib_file(lib_name: &str) -> io::Result<File> {
+    if let Ok(file) = File::open(OUT_DIR.join(msvc_lib_file_name(lib_name))) {
         Ok(file)
     } else {
-        File::open(OUT_DIR.join(LIB_NAME))
+        File::open(OUT_DIR.join(lib_file_name(lib_name)))
     }
 }
 
@@ -161,6 +241,90 @@ fn find_all_rust_macro(
     Ok(r)
 }
 
+/// If the closure returns one of the Rust types of the `PRIMITIVE_TYPES` table, but
+/// not as one of the corresponding C++ types, return that entry of the table.
+fn primitive_return_type(
+    sig: &cpp_common::ClosureSig,
+) -> Option<(&'static str, &'static [&'static str])> {
+    let path = match &sig.ret {
+        Some(syn::Type::Path(p)) if p.qself.is_none() => &p.path,
+        _ => return None,
+    };
+    PRIMITIVE_TYPES
+        .iter()
+        .find(|(rust, _)| path.is_ident(rust))
+        .filter(|(rust, _)| rust_primitive_for(&sig.cpp) != Some(rust))
+        .copied()
+}
+
+/// Whether the C++ type of a capture is a builtin integer type, other than the
+/// pointer-width ones of the `PRIMITIVE_TYPES` table.
+fn is_integer_type(cpp: &str) -> bool {
+    let cpp = cpp.trim();
+    let cpp = cpp.strip_prefix("const ").unwrap_or(cpp).split_whitespace().collect::<Vec<_>>();
+    let cpp = cpp.join(" ");
+    let cpp = cpp.strip_prefix("std::").unwrap_or(&cpp);
+    let fixed = cpp.trim_start_matches('u');
+    let builtin = cpp.trim_start_matches("signed ").trim_start_matches("unsigned ");
+    rust_primitive_for(cpp).is_none()
+        && ((fixed.starts_with("int") && fixed.ends_with("_t"))
+            || matches!(
+                builtin,
+                "char"
+                    | "short"
+                    | "short int"
+                    | "int"
+                    | "unsigned"
+                    | "signed"
+                    | "long"
+                    | "long int"
+                    | "long long"
+                    | "long long int"
+            ))
+}
+
+/// The compile time check of the size of a capture of a C++ integer type. When a
+/// pointer-width Rust value is captured as a C++ type of another size, the error
+/// tells which C++ types to use, as `primitive_return_type` does for the return type.
+fn integer_size_check(
+    capture: &cpp_common::Capture,
+    size: usize,
+    value: &proc_macro2::TokenStream,
+) -> proc_macro2::TokenStream {
+    let span = capture.name.span();
+    let cpp_types = |rust: &str| {
+        PRIMITIVE_TYPES
+            .iter()
+            .find(|(r, _)| *r == rust)
+            .map_or(String::new(), |(_, c)| c.join(", "))
+    };
+    let pointer_msg = format!(
+        "argument `{}` has the width of a pointer, but is captured as the C++ type `{}` which \
+         does not have the same size. Capture a `usize` as one of: {}; and an `isize` as one of: {}",
+        capture.name,
+        capture.cpp.trim(),
+        cpp_types("usize"),
+        cpp_types("isize")
+    );
+    let sizeof_msg =
+        format!("size_of for argument `{}` does not match between c++ and rust", capture.name);
+    quote_spanned! {span=>
+        struct Check<T>(::core::marker::PhantomData<T>);
+        impl<T> Check<T> {
+            const SIZE_MATCHES: () = {
+                let size = ::core::mem::size_of::<T>();
+                assert!(size == #size || size != ::core::mem::size_of::<usize>(), #pointer_msg);
+                assert!(size == #size, #sizeof_msg);
+            };
+        }
+        fn check<T>(_: &T) {
+            #[allow(clippy::let_unit_value)]
+            let () = Check::<T>::SIZE_MATCHES;
+        }
+        check(&#value);
+    }
+}
+
 /// Find the occurrence of the `stringify!` macro within the macro derive
 fn extract_original_macro(input: &syn::DeriveInput) -> Option<proc_macro2::TokenStream> {
     #[derive(Default)]
@@ -177,6 +341,32 @@ fn extract_original_macro(input: &syn::DeriveInput) -> Option<proc_macro2::Token
     f.0
 }
 
+/// When a closure which captures `self` returns a reference with an elided
+/// lifetime, the reference is tied to a borrow of `*self`, like the return
+/// value of a method. Returns `result` wrapped in a call which ties the lifetimes.
+fn tie_to_self(
+    sig: &cpp_common::ClosureSig,
+    result: proc_macro2::TokenStream,
+) -> proc_macro2::TokenStream {
+    let mutability = match &sig.ret {
+        Some(syn::Type::Reference(r)) if r.lifetime.is_none() => r.mutability,
+        _ => return result,
+    };
+    let capture = match sig.captures.iter().find(|c| c.name == "self") {
+        Some(x) => x,
+        None => return result,
+    };
+    let mac_name = Ident::new("var_self", capture.name.span());
+    quote! {{
+        #[inline(always)]
+        fn __cpp_tie<'a, O: ?Sized, R: ?Sized>(_owner: &'a O, r: &'a #mutability R)
+            -> &'a #mutability R {
+            r
+        }
+        __cpp_tie(&*$#mac_name, #result)
+    }}
+}
+
 #[proc_macro_derive(__cpp_internal_closure)]
 #[allow(clippy::cognitive_complexity)]
 pub fn expand_internal(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
@@ -189,20 +379,23 @@ pub fn expand_internal(input: proc_macro::TokenStream) -> proc_macro::TokenStrea
     // Parse the macro input
     let input = extract_original_macro(&parse_macro_input!(input as syn::DeriveInput)).unwrap();
 
-    let closure = match syn::parse2::<cpp_common::Closure>(input) {
+    let closure = match syn::parse2::<cpp_common::Closure>(input.clone()) {
         Ok(x) => x,
         Err(err) => return err.to_compile_error().into(),
     };
 
     // Get the size data compiled by the build macro
-    let size_data = match METADATA.get(&closure.sig.name_hash()) {
+    let (lib_name, size_data) = match find_metadata(closure.sig.name_hash()) {
         Some(x) => x,
         None => {
             #[cfg(not(feature = "docs-only"))]
-            return quote!(compile_error! {
-r#"This cpp! macro is not found in the library's rust-cpp metadata.
+            return syn::Error::new_spanned(
+                input,
+                r#"This cpp! macro is not found in the library's rust-cpp metadata.
 NOTE: Only cpp! macros found directly in the program source will be parsed -
-NOTE: They cannot be generated by macro expansion."#})
+NOTE: They cannot be generated by macro expansion."#,
+            )
+            .to_compile_error()
             .into();
             #[cfg(feature = "docs-only")]
             {
@@ -216,18 +409,88 @@ NOTE: They cannot be generated by macro expansion."#})
         }
     };
 
+    // The `cpp` crate is passed by the `cpp!` macro as `$crate`
+    let cpp_crate = Ident::new("cpp_crate", Span::call_site());
     let mut extern_params = Vec::new();
     let mut tt_args = Vec::new();
     let mut call_args = Vec::new();
+    let mut const_uses = Vec::new();
+    let mut raw_lets = Vec::new();
     for (i, capture) in closure.sig.captures.iter().enumerate() {
         let written_name = &capture.name;
         let span = written_name.span();
         let mac_name = Ident::new(&format!("var_{}", written_name), span);
         let mac_cty = Ident::new(&format!("cty_{}", written_name), span);
 
+        // The value of a const capture is in the C++ code, it is not passed at runtime
+        if capture.constant {
+            tt_args.push(quote_spanned!(span=> const $#mac_name : ident as $#mac_cty : tt));
+            const_uses.push(quote_spanned!(span=> let _ = $#mac_name;));
+            continue;
+        }
+
+        // The size of a generic parameter is only known after monomorphization
+        if size_data[i + 1].has_flag(flags::IS_GENERIC_CAPTURE) {
+            let error = syn::Error::new(
+                span,
+                format!(
+                    "`{}` has a generic type: the captures of cpp! must have a concrete type, \
+                     known when the build script generates the C++ code (before monomorphization). \
+                     Convert it to a concrete type, or capture a reference or a pointer to it",
+                    written_name
+                ),
+            )
+            .to_compile_error();
+            // Define the macro anyway, so that the error above is the only one reported
+            return quote! {
+                #error
+                macro_rules! __cpp_closure_impl {
+                    ($($x:tt)*) => { unreachable!() }
+                }
+            }
+            .into();
+        }
+
+        // The pointer to a clone of an arc capture, or to the box of a move capture, is put
+        // in a local variable, which is passed
+        let value = if capture.arc {
+            let raw_name = Ident::new(&format!("__cpp_arc_{}", written_name), span);
+            raw_lets.push(quote_spanned!(span=>
+                let #raw_name = ::std::sync::Arc::into_raw(::std::sync::Arc::clone(&$#mac_name));
+            ));
+            quote!(#raw_name)
+        } else if capture.moved {
+            let raw_name = Ident::new(&format!("__cpp_box_{}", written_name), span);
+            raw_lets.push(quote_spanned!(span=>
+                let #raw_name = match $#mac_name {
+                    ::std::option::Option::Some(b) => ::std::boxed::Box::into_raw(b),
+                    ::std::option::Option::None => ::core::ptr::null_mut(),
+                };
+            ));
+            quote!(#raw_name)
+        } else if capture.cpp.trim() == "rustcpp::MutBuf" {
+            // The pointer and the length of the buffer are passed in a __MutBuf
+            let raw_name = Ident::new(&format!("__cpp_buf_{}", written_name), span);
+            raw_lets.push(quote_spanned!(span=>
+                #[allow(unused_mut)]
+                let mut #raw_name = $#cpp_crate::__MutBuf::from(&mut $#mac_name[..]);
+            ));
+            quote!(#raw_name)
+        } else if capture.cpp.trim_start().starts_with("rustcpp::FnMut<") {
+            // The reference to the closure is passed with the function which calls it
+            let raw_name = Ident::new(&format!("__cpp_fn_{}", written_name), span);
+            raw_lets.push(quote_spanned!(span=>
+                #[allow(unused_mut)]
+                let mut #raw_name = $#cpp_crate::__FnMut::from(&mut *$#mac_name);
+            ));
+            quote!(#raw_name)
+        } else {
+            quote!($#mac_name)
+        };
+
         // Generate the assertion to check that the size and align of the types
         // match before calling.
-        let MetaData { size, align, .. } = size_data[i + 1];
+        let MetaData { size, align, flags: capture_flags } = size_data[i + 1];
         let sizeof_msg = format!(
             "size_of for argument `{}` does not match between c++ and \
              rust",
@@ -238,19 +501,52 @@ NOTE: They cannot be generated by macro expansion."#})
              rust",
             &capture.name
         );
-        let assertion = quote_spanned! {span=>
-            // Perform a compile time check that the sizes match. This should be
-            // a no-op.
-            if false {
-                #[allow(clippy::transmute_num_to_bytes)]
-                ::core::mem::transmute::<_, [u8; #size]>(
-                    ::core::ptr::read(&$#mac_name));
+        // The elements of a pointer to wchar_t must have the size of wchar_t, which
+        // depends on the target
+        let wide_char_check = if is_wide_char_pointer(&capture.cpp) {
+            let wchar_size = (capture_flags >> 32) as usize;
+            let wide_char_msg = format!(
+                "argument `{}` is captured as `{}`, but `wchar_t` has {} bytes on this target \
+                 and the Rust elements have {{}}: use `u16` elements (UTF-16) on Windows, and \
+                 `u32` or `char` elements (UTF-32) elsewhere",
+                &capture.name, &capture.cpp, wchar_size
+            );
+            quote_spanned! {span=>
+                let size = $#cpp_crate::__wide_char_size(&#value);
+                assert!(size == #wchar_size, #wide_char_msg, size);
+            }
+        } else {
+            quote!()
+        };
+        // The size of a C++ enum is the size of its underlying type, which must match the
+        // #[repr] of a Rust enum: this check explains the mismatch, unlike the transmute
+        let size_check = if size_data[i + 1].has_flag(flags::IS_ENUM) {
+            quote_spanned! {span=>
+                $#cpp_crate::__check_enum_size::<_, #size>(&#value);
+            }
+        } else if is_integer_type(&capture.cpp) {
+            integer_size_check(capture, size, &value)
+        } else {
+            quote_spanned! {span=>
+                // Perform a compile time check that the sizes match. This should be
+                // a no-op.
+                if false {
+                    #[allow(clippy::transmute_num_to_bytes, clippy::useless_transmute)]
+                    ::core::mem::transmute::<_, [u8; #size]>(
+                        ::core::ptr::read(&#value));
+                }
             }
+        };
+        let assertion = quote_spanned! {span=>
+            #size_check
 
             // NOTE: Both of these calls should be dead code in opt builds.
-            #[allow(clippy::size_of_ref)] { assert!(::core::mem::size_of_val(&$#mac_name) == #size, #sizeof_msg); };
-            assert!(::core::mem::align_of_val(&$#mac_name) == #align,
+            #[allow(clippy::size_of_ref)] { assert!(::core::mem::size_of_val(&#value) == #size, #sizeof_msg); };
+            assert!(::core::mem::align_of_val(&#value) == #align,
                     #alignof_msg);
+            // A tuple, whose layout is unspecified, fails to compile here
+            $#cpp_crate::__NotTuple::__check_not_tuple(&#value);
+            #wide_char_check
         };
 
         let mb_mut = if capture.mutable { quote_spanned!(span=> mut) } else { quote!() };
@@ -264,23 +560,51 @@ NOTE: They cannot be generated by macro expansion."#})
 
         extern_params.push(quote_spanned!(span=> #arg_name : #ptr u8));
 
-        tt_args.push(quote_spanned!(span=> #mb_mut $#mac_name : ident as $#mac_cty : tt));
+        // The pointer of a copy capture is passed itself, instead of its address
+        if capture.by_value {
+            tt_args.push(quote_spanned!(span=> copy $#mac_name : ident as $#mac_cty : tt));
+            call_args.push(quote_spanned!(span=> {
+                #assertion
+                ::core::ptr::read(&#value as *const _ as *const *const u8)
+            }));
+            continue;
+        }
+
+        if capture.arc {
+            tt_args.push(quote_spanned!(span=> arc $#mac_name : ident as $#mac_cty : tt));
+        } else if capture.moved {
+            tt_args.push(quote_spanned!(span=> move $#mac_name : ident as $#mac_cty : tt));
+        } else {
+            tt_args.push(quote_spanned!(span=> #mb_mut $#mac_name : ident as $#mac_cty : tt));
+        }
 
         call_args.push(quote_spanned!(span=> {
             #assertion
-            &#mb_mut $#mac_name as #ptr _ as #ptr u8
+            &#mb_mut #value as #ptr _ as #ptr u8
         }));
     }
 
     let extern_name = closure.sig.extern_name();
     let ret_ty = &closure.sig.ret;
+    // Errors about the return type point at it, or at the whole closure for `void`
+    let ret_span = ret_ty.as_ref().map_or_else(|| closure.body.span(), |t| t.span());
     let MetaData { size: ret_size, align: ret_align, flags } = size_data[0];
     let is_void = closure.sig.cpp == "void";
 
+    let wrapper = closure.sig.return_wrapper();
+
     let decl = if is_void {
         quote! {
             fn #extern_name(#(#extern_params),*);
         }
+    } else if let Some(ReturnWrapper::Optional { rust, .. }) = &wrapper {
+        quote! {
+            fn #extern_name(#(#extern_params,)* _result: *mut #rust) -> bool;
+        }
+    } else if let Some(ReturnWrapper::Expected { rust, rust_error, .. }) = &wrapper {
+        quote! {
+            fn #extern_name(#(#extern_params,)* _result: *mut #rust, _error: *mut #rust_error) -> bool;
+        }
     } else {
         quote! {
             fn #extern_name(#(#extern_params,)* _result: *mut #ret_ty);
@@ -296,25 +620,137 @@ NOTE: They cannot be generated by macro expansion."#})
         }
     } else {
         // static assert that the size and alignement are the same
-        let assert_size = quote! {
+        let assert_primitive = match primitive_return_type(&closure.sig) {
+            Some((rust, cpp_types)) => {
+                let msg = format!(
+                    "`{}` is returned as the C++ type `{}` which does not have the same size. \
+                     Use one of: {}",
+                    rust,
+                    closure.sig.cpp,
+                    cpp_types.join(", ")
+                );
+                quote_spanned!(ret_span=>
+                    const _: () = assert!(::core::mem::size_of::<#ret_ty>() == #ret_size, #msg);
+                )
+            }
+            None => quote!(),
+        };
+        let assert_size = quote_spanned! {ret_span=>
             if false {
+                #assert_primitive
                 const _assert_size: [(); #ret_size] = [(); ::core::mem::size_of::<#ret_ty>()];
                 const _assert_align: [(); #ret_align] = [(); ::core::mem::align_of::<#ret_ty>()];
             }
         };
-        quote!(
-            #assert_size
-            let mut result = ::core::mem::MaybeUninit::<#ret_ty>::uninit();
-            #extern_name(#(#call_args,)* result.as_mut_ptr());
-            result.assume_init()
-        )
+        let assert_wrapped_size = match &wrapper {
+            Some(ReturnWrapper::Optional { rust, .. })
+            | Some(ReturnWrapper::Expected { rust, .. }) => quote_spanned! {ret_span=>
+                if false {
+                    const _assert_size: [(); #ret_size] = [(); ::core::mem::size_of::<#rust>()];
+                    const _assert_align: [(); #ret_align] = [(); ::core::mem::align_of::<#rust>()];
+                }
+            },
+            None => quote!(),
+        };
+        match &wrapper {
+            None if size_data[0].has_flag(flags::IS_CHECKED_ENUM) => quote!(
+                #assert_size
+                let mut result =
+                    ::core::mem::MaybeUninit::<<#ret_ty as $#cpp_crate::CppEnum>::Repr>::uninit();
+                #extern_name(#(#call_args,)* result.as_mut_ptr() as *mut #ret_ty);
+                $#cpp_crate::__checked_enum::<#ret_ty>(result.assume_init())
+            ),
+            // The view is returned as a cpp::StrView, or converted to a slice
+            None if closure.sig.cpp.trim() == "rustcpp::StrView" => {
+                let result = match ret_ty {
+                    Some(syn::Type::Reference(_)) => {
+                        tie_to_self(&closure.sig, quote!(result.assume_init().as_bytes()))
+                    }
+                    _ => quote!(result.assume_init()),
+                };
+                quote!(
+                    if false {
+                        const _assert_size: [(); #ret_size] =
+                            [(); ::core::mem::size_of::<$#cpp_crate::StrView>()];
+                    }
+                    let mut result = ::core::mem::MaybeUninit::<$#cpp_crate::StrView>::uninit();
+                    #extern_name(#(#call_args,)* result.as_mut_ptr() as *mut #ret_ty);
+                    #result
+                )
+            }
+            // The duration is returned as a cpp::Duration or a std::time::Duration
+            None if closure.sig.cpp.trim() == "rustcpp::Duration" => quote!(
+                if false {
+                    const _assert_size: [(); #ret_size] =
+                        [(); ::core::mem::size_of::<$#cpp_crate::Duration>()];
+                }
+                let mut result = ::core::mem::MaybeUninit::<$#cpp_crate::Duration>::uninit();
+                #extern_name(#(#call_args,)* result.as_mut_ptr() as *mut #ret_ty);
+                <#ret_ty as $#cpp_crate::__FromCppDuration>::__from_cpp_duration(
+                    result.assume_init(),
+                )
+            ),
+            None => {
+                let result = tie_to_self(&closure.sig, quote!(result.assume_init()));
+                // A null reference is undefined behavior, so check the pointer before
+                // assume_init in debug builds
+                let null_check = match ret_ty {
+                    Some(syn::Type::Reference(_)) => quote!(
+                        debug_assert!(
+                            !(*(result.as_ptr() as *const *const u8)).is_null(),
+                            "the C++ code returned a null pointer for a reference"
+                        );
+                    ),
+                    _ => quote!(),
+                };
+                quote!(
+                    #assert_size
+                    let mut result = ::core::mem::MaybeUninit::<#ret_ty>::uninit();
+                    #extern_name(#(#call_args,)* result.as_mut_ptr());
+                    #null_check
+                    #result
+                )
+            }
+            Some(ReturnWrapper::Optional { rust, .. }) => quote!(
+                #assert_wrapped_size
+                let mut result = ::core::mem::MaybeUninit::<#rust>::uninit();
+                if #extern_name(#(#call_args,)* result.as_mut_ptr()) {
+                    ::core::option::Option::Some(result.assume_init())
+                } else {
+                    ::core::option::Option::None
+                }
+            ),
+            Some(ReturnWrapper::Expected { rust, rust_error, .. }) => {
+                let MetaData { size: error_size, align: error_align, .. } =
+                    size_data[closure.sig.captures.len() + 1];
+                let assert_error_size = quote_spanned! {ret_span=>
+                    if false {
+                        const _assert_error_size: [(); #error_size] =
+                            [(); ::core::mem::size_of::<#rust_error>()];
+                        const _assert_error_align: [(); #error_align] =
+                            [(); ::core::mem::align_of::<#rust_error>()];
+                    }
+                };
+                quote!(
+                    #assert_wrapped_size
+                    #assert_error_size
+                    let mut result = ::core::mem::MaybeUninit::<#rust>::uninit();
+                    let mut error = ::core::mem::MaybeUninit::<#rust_error>::uninit();
+                    if #extern_name(#(#call_args,)* result.as_mut_ptr(), error.as_mut_ptr()) {
+                        ::core::result::Result::Ok(result.assume_init())
+                    } else {
+                        ::core::result::Result::Err(error.assume_init())
+                    }
+                )
+            }
+        }
     };
 
     let input = proc_macro2::TokenStream::from_iter([closure.body].iter().cloned());
     let rust_invocations = find_all_rust_macro.parse2(input).expect("rust! macro");
     let init_callbacks = if !rust_invocations.is_empty() {
         let rust_cpp_callbacks =
-            Ident::new(&format!("rust_cpp_callbacks{}", *FILE_HASH), Span::call_site());
+            Ident::new(&format!("rust_cpp_callbacks{}", lib_hash(lib_name)), Span::call_site());
         let offset = (flags >> 32) as isize;
         let callbacks: Vec<Ident> = rust_invocations.iter().map(|x| x.id.clone()).collect();
         quote! {
@@ -339,15 +775,41 @@ NOTE: They cannot be generated by macro expansion."#})
         quote!()
     };
 
+    // The C++ code may write an invalid `char` to a mutable capture
+    let char_checks = closure
+        .sig
+        .captures
+        .iter()
+        .filter(|c| c.mutable && rust_primitive_for(&c.cpp) == Some("char"))
+        .map(|c| {
+            let mac_name = Ident::new(&format!("var_{}", c.name), c.name.span());
+            let name = c.name.to_string();
+            quote_spanned! {c.name.span()=>
+                $#cpp_crate::__CppChar32::__validate_char(&mut $#mac_name, #name);
+            }
+        })
+        .collect::<Vec<_>>();
+    let call = if char_checks.is_empty() {
+        call
+    } else {
+        quote! {
+            let result = { #call };
+            #(#char_checks)*
+            result
+        }
+    };
+
     let result = quote! {
         extern "C" {
             #decl
         }
 
         macro_rules! __cpp_closure_impl {
-            (#(#tt_args),*) => {
+            ($#cpp_crate:tt; #(#tt_args),*) => {
                 {
                     #init_callbacks
+                    #(#const_uses)*
+                    #(#raw_lets)*
                     #call
                 }
             }
@@ -356,6 +818,112 @@ NOTE: They cannot be generated by macro expansion."#})
     result.into()
 }
 
+/// Check that the types of the arguments and of the return value of a `rust!`
+/// callback have the same size and alignment as the corresponding C++ types.
+#[proc_macro_derive(__cpp_internal_rust_callback)]
+pub fn expand_rust_callback(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    // Parse the macro input
+    let input = extract_original_macro(&parse_macro_input!(input as syn::DeriveInput)).unwrap();
+
+    let invocation = match ::syn::parse2::<RustInvocation>(input) {
+        Ok(x) => x,
+        Err(err) => return err.to_compile_error().into(),
+    };
+
+    // The metadata may be missing, for instance if the rust! macro is in a cpp! macro which
+    // does not use the metadata either. Then there is nothing to check.
+    let size_data = match find_metadata(invocation.name_hash()) {
+        Some((_, x)) => x,
+        None => return quote!().into(),
+    };
+
+    let ret = invocation
+        .return_type
+        .as_ref()
+        .map(|(rust, cpp)| ("the return type".to_owned(), rust, cpp));
+    let args = invocation
+        .arguments
+        .iter()
+        .map(|(name, rust, cpp)| (format!("argument `{}`", name), rust, cpp));
+    let mut result = quote!();
+    // The entries are in the same order: the return type if any, then the arguments
+    for ((what, rust, cpp), MetaData { size, align, .. }) in
+        ret.into_iter().chain(args).zip(size_data)
+    {
+        let sizeof_msg = format!(
+            "size_of for {} of rust!({}) does not match between c++ (`{}`) and rust",
+            what, invocation.id, cpp
+        );
+        let alignof_msg = format!(
+            "align_of for {} of rust!({}) does not match between c++ (`{}`) and rust",
+            what, invocation.id, cpp
+        );
+        result.extend(quote! {
+            const _: () = assert!(::core::mem::size_of::<#rust>() == #size, #sizeof_msg);
+            const _: () = assert!(::core::mem::align_of::<#rust>() == #align, #alignof_msg);
+        });
+    }
+    result.into()
+}
+
+/// The Rust type which is converted by the constructor taking the C++ arguments
+/// `args` of a `#[rustcpp::from(...)]` attribute, with the parameters of the
+/// extern function and the values passed to it.
+fn from_constructor(
+    args: &[String],
+) -> Option<(proc_macro2::TokenStream, Vec<proc_macro2::TokenStream>, Vec<proc_macro2::TokenStream>)>
+{
+    let args = args.iter().map(|a| a.split_whitespace().collect::<String>()).collect::<Vec<_>>();
+    match &args[..] {
+        [ptr, len] if ptr == "constchar*" && len == "size_t" => Some((
+            quote!(&str),
+            vec![quote!(a0: *const u8), quote!(a1: usize)],
+            vec![quote!(value.as_ptr()), quote!(value.len())],
+        )),
+        [cpp] => {
+            let rust: syn::Type = syn::parse_str(rust_primitive_for(cpp)?).ok()?;
+            Some((quote!(#rust), vec![quote!(a0: #rust)], vec![quote!(value)]))
+        }
+        _ => None,
+    }
+}
+
+/// The accessor methods of the fields of a cpp_class!, which call the functions
+/// generated by cpp_build, see `cpp_common::Class::fields`
+fn field_accessors(class: &cpp_common::Class, hash: u128) -> proc_macro2::TokenStream {
+    if class.fields.is_empty() {
+        return quote!();
+    }
+    let class_name = &class.name;
+    let accessors = class.fields.iter().map(|field| {
+        let cpp_common::ClassField { vis, name, rust, .. } = field;
+        let setter = Ident::new(&format!("set_{}", name), name.span());
+        let get_name = Ident::new(&format!("__cpp_get_{}_{}", hash, name), Span::call_site());
+        let set_name = Ident::new(&format!("__cpp_set_{}_{}", hash, name), Span::call_site());
+        quote! {
+            #vis fn #name(&self) -> #rust {
+                unsafe {
+                    extern "C" { fn #get_name(this: *const #class_name, dest: *mut #rust); }
+                    let mut result = ::core::mem::MaybeUninit::<#rust>::uninit();
+                    #get_name(& *self, result.as_mut_ptr());
+                    result.assume_init()
+                }
+            }
+            #vis fn #setter(&mut self, value: #rust) {
+                unsafe {
+                    extern "C" { fn #set_name(this: *mut #class_name, value: *const #rust); }
+                    #set_name(&mut *self, &value);
+                }
+            }
+        }
+    });
+    quote! {
+        impl #class_name {
+            #(#accessors)*
+        }
+    }
+}
+
 #[proc_macro_derive(__cpp_internal_class)]
 pub fn expand_wrap_class(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     // Parse the macro input
@@ -370,14 +938,17 @@ pub fn expand_wrap_class(input: proc_macro::TokenStream) -> proc_macro::TokenStr
     let class_name = class.name.clone();
 
     // Get the size data compiled by the build macro
-    let size_data = match METADATA.get(&hash) {
-        Some(x) => x,
+    let size_data = match find_metadata(hash) {
+        Some((_, x)) => x,
         None => {
             #[cfg(not(feature = "docs-only"))]
-            return quote!(compile_error! {
-r#"This cpp_class! macro is not found in the library's rust-cpp metadata.
+            return syn::Error::new(
+                class_name.span(),
+                r#"This cpp_class! macro is not found in the library's rust-cpp metadata.
 NOTE: Only cpp_class! macros found directly in the program source will be parsed -
-NOTE: They cannot be generated by macro expansion."#})
+NOTE: They cannot be generated by macro expansion."#,
+            )
+            .to_compile_error()
             .into();
             #[cfg(feature = "docs-only")]
             {
@@ -420,7 +991,8 @@ NOTE: They cannot be generated by macro expansion."#})
                         }
                     };
                 }
-                return result.into();
+                let accessors = field_accessors(&class, hash);
+                return quote!(#result #accessors).into();
             };
         }
     };
@@ -447,7 +1019,7 @@ NOTE: They cannot be generated by macro expansion."#})
             const CPP_TYPE: &'static str = stringify!(#class_name);
         }
     };
-    if !size_data[0].has_flag(flags::IS_TRIVIALLY_DESTRUCTIBLE) {
+    if !size_data[0].has_flag(flags::IS_TRIVIALLY_DESTRUCTIBLE) && !class.no_drop() {
         result = quote! { #result
             impl ::core::ops::Drop for #class_name {
                 fn drop(&mut self) {
@@ -522,6 +1094,39 @@ NOTE: They cannot be generated by macro expansion."#})
             }
         };
     }
+    let from_constructors = match class.from_constructors() {
+        Ok(x) => x,
+        Err(err) => return err.to_compile_error().into(),
+    };
+    for (i, args) in from_constructors.iter().enumerate() {
+        let from_name = Ident::new(&format!("__cpp_from_{}_{}", hash, i), Span::call_site());
+        let (rust, params, values) = match from_constructor(args) {
+            Some(x) => x,
+            None => {
+                let msg = format!(
+                    "unsupported arguments for #[rustcpp::from]: {:?}. Use (\"const char*\", \"size_t\") \
+                     to implement From<&str>, or a single C++ type corresponding to a Rust primitive",
+                    args
+                );
+                return syn::Error::new(class_name.span(), msg).to_compile_error().into();
+            }
+        };
+        result = quote! { #result
+            impl ::core::convert::From<#rust> for #class_name {
+                fn from(value: #rust) -> Self {
+                    unsafe {
+                        extern "C" { fn #from_name(#(#params,)* dst: *mut #class_name); }
+                        let mut result = ::core::mem::MaybeUninit::<Self>::uninit();
+                        #from_name(#(#values,)* result.as_mut_ptr());
+                        result.assume_init()
+                    }
+                }
+            }
+        };
+    }
+    let accessors = field_accessors(&class, hash);
+    result = quote! { #result #accessors };
+
     if class.derives("PartialOrd") {
         let compare_name = Ident::new(&format!("__cpp_compare_{}", hash), Span::call_site());
         let f = |func, cmp| {
@@ -586,3 +1191,90 @@ NOTE: They cannot be generated by macro expansion."#})
 
     result.into()
 }
+
+/// Implement `cpp::CppEnum` for a field-less enum with a `#[repr(C)]` or
+/// `#[repr(<integer>)]` attribute. See the documentation of the `cpp` crate.
+#[proc_macro_derive(CppEnum, attributes(cpp_enum))]
+pub fn derive_cpp_enum(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as syn::DeriveInput);
+    match cpp_enum_impl(&input) {
+        Ok(x) => x.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn cpp_enum_impl(input: &syn::DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    const INTEGERS: &[&str] =
+        &["i8", "i16", "i32", "i64", "isize", "u8", "u16", "u32", "u64", "usize"];
+    let data = match &input.data {
+        syn::Data::Enum(data) => data,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "CppEnum can only be derived for an enum",
+            ))
+        }
+    };
+
+    // An integer type in the repr attribute takes precedence over `C`
+    let mut repr = None;
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("repr")) {
+        let metas = attr.parse_args_with(
+            syn::punctuated::Punctuated::<syn::Meta, Token![,]>::parse_terminated,
+        )?;
+        for meta in metas {
+            match meta.path().get_ident() {
+                Some(i) if INTEGERS.iter().any(|t| i == t) => repr = Some(quote!(#i)),
+                Some(i) if i == "C" && repr.is_none() => repr = Some(quote!(::std::os::raw::c_int)),
+                _ => (),
+            }
+        }
+    }
+    let repr = repr.ok_or_else(|| {
+        syn::Error::new_spanned(
+            &input.ident,
+            "CppEnum requires a #[repr(C)] or #[repr(<integer>)] attribute",
+        )
+    })?;
+
+    let mut fallback = quote!(::core::option::Option::None);
+    let mut has_fallback = false;
+    for variant in &data.variants {
+        if !matches!(variant.fields, syn::Fields::Unit) {
+            return Err(syn::Error::new_spanned(
+                variant,
+                "CppEnum can only be derived for an enum without fields",
+            ));
+        }
+        for attr in variant.attrs.iter().filter(|a| a.path().is_ident("cpp_enum")) {
+            let arg: Ident = attr.parse_args()?;
+            if arg != "fallback" || has_fallback {
+                return Err(syn::Error::new_spanned(
+                    attr,
+                    "expected a single #[cpp_enum(fallback)] variant",
+                ));
+            }
+            let ident = &variant.ident;
+            fallback = quote!(::core::option::Option::Some(Self::#ident));
+            has_fallback = true;
+        }
+    }
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let variants = data.variants.iter().map(|v| &v.ident).collect::<Vec<_>>();
+    Ok(quote! {
+        impl #impl_generics ::cpp::CppEnum for #name #ty_generics #where_clause {
+            type Repr = #repr;
+            const FALLBACK: ::core::option::Option<Self> = #fallback;
+            fn from_repr(repr: #repr) -> ::core::option::Option<Self> {
+                #(
+                    if repr == Self::#variants as #repr {
+                        return ::core::option::Option::Some(Self::#variants);
+                    }
+                )*
+                ::core::option::Option::None
+            }
+        }
+    })
+}
@@ -2,4 +2,84 @@ extern crate cpp_build;
 
 fn main() {
     cpp_build::build("src/lib.rs");
+    let msvc = std::env::var("CARGO_CFG_TARGET_ENV").unwrap() == "msvc";
+    // GCC and Clang look for headers in CPLUS_INCLUDE_PATH
+    let compiler_env_include =
+        std::path::Path::new(&std::env::var_os("CARGO_MANIFEST_DIR").unwrap())
+            .join("include")
+            .join("compiler_env");
+    // Included by second_library.rs, see the `generated_header` test
+    let out_dir = std::path::PathBuf::from(std::env::var_os("OUT_DIR").unwrap());
+    std::fs::write(out_dir.join("rust_cpp_generated_config.h"), "#define GENERATED_VALUE 19\n")
+        .unwrap();
+    let second = cpp_build::Config::new()
+        .lib_name("rust_cpp_second")
+        .define("SECOND_LIBRARY", Some("2"))
+        .define("SECOND_LIBRARY_SCALE(x, y)", Some("((x) * SECOND_LIBRARY + (y))"))
+        .compiler_env("CPLUS_INCLUDE_PATH", &compiler_env_include)
+        .include_from_manifest("include")
+        .include_out_dir()
+        .add_snippet_file("include/snippet_file.cpp")
+        .rerun_if_changed("src")
+        .flag_if_supported("-std=c++17")
+        .hidden_visibility(true)
+        .debug_maps_to_rust(true)
+        .prepend_snippet("#define PREPENDED_SNIPPET 7")
+        .closure_prelude("using namespace second_prelude;")
+        .append_cpp("extern \"C\" int rust_cpp_test_appended(int x) { return second_prelude::prelude_function() + x; }")
+        // Only linked by a #pragma comment(lib) with MSVC, see the `msvc_link_pragma` test
+        .link_lib(if msvc { "version" } else { "m" })
+        .msvc_link_pragma(true)
+        .compile("src/second_library.rs");
+    assert!(second.is_file(), "{} was not built", second.display());
+
+    println!("cargo:rustc-check-cfg=cfg(rust_cpp_span)");
+    if !msvc && supports_cpp_span() {
+        let span = cpp_build::Config::new()
+            .lib_name("rust_cpp_span")
+            .flag("-std=c++20")
+            .compile("src/span_library.rs");
+        assert!(span.is_file(), "{} was not built", span.display());
+        println!("cargo:rustc-cfg=rust_cpp_span");
+    }
+
+    // A crate with only cpp!{{ }} snippets still has metadata, which cpp_macros reads when
+    // expanding the closures of this crate. Its function is called by the
+    // `snippets_only_library` test.
+    let root = out_dir.join("snippets_only.rs");
+    std::fs::write(&root, "cpp! {{ extern \"C\" int rust_cpp_snippets_only() { return 17; } }}\n")
+        .unwrap();
+    let lib = cpp_build::Config::new().lib_name("rust_cpp_snippets_only").compile(&root);
+    assert!(lib.is_file(), "{} was not built", lib.display());
+
+    // Compared with the size given by the compiler in the `probe_type` test
+    let string = cpp_build::probe_type_with_prelude(
+        "#include <string>",
+        "std::string",
+        &cpp_build::Config::new(),
+    );
+    println!(
+        "cargo:rustc-env=RUST_CPP_TEST_PROBED_STRING={},{},{}",
+        string.size,
+        string.align,
+        string.is_copy_constructible()
+    );
+}
+
+/// Whether the C++ compiler has the C++20 `<span>` header
+fn supports_cpp_span() -> bool {
+    use std::io::Write;
+    let compiler = std::env::var("CXX").unwrap_or_else(|_| "c++".to_owned());
+    let child = std::process::Command::new(compiler)
+        .args(["-std=c++20", "-fsyntax-only", "-x", "c++", "-"])
+        .stdin(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::null())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(_) => return false,
+    };
+    let source = b"#include <span>\nstd::span<const int> s;\n";
+    let written = child.stdin.take().unwrap().write_all(source).is_ok();
+    matches!(child.wait(), Ok(status) if status.success()) && written
 }
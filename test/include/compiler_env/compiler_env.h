@@ -0,0 +1,5 @@
+// Only found through the CPLUS_INCLUDE_PATH set with Config::compiler_env in build.rs
+struct CompilerEnvType {
+    char data[3];
+};
+inline int compiler_env_header() { return 23; }
@@ -0,0 +1,2 @@
+// Found through Config::include_from_manifest in build.rs
+inline int second_library_header() { return 11; }
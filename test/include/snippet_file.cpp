@@ -0,0 +1,4 @@
+// Added to the second library with Config::add_snippet_file in build.rs
+static int snippet_file_function() {
+    return 19;
+}
@@ -156,3 +156,115 @@ fn derive_ord() {
     assert!(!(x3 < x3));
     assert!(!(x2 >= x3));
 }
+
+#[test]
+fn array_of_class() {
+    fn live_count() -> i32 {
+        unsafe { cpp!([] -> i32 as "int" { return thread_counter(); }) }
+    }
+
+    let before = live_count();
+    {
+        let array: [A; 3] = [A::new(1, 2), A::new(3, 4), A::new(5, 6)];
+        assert_eq!(live_count(), before + 3);
+
+        let sum = unsafe {
+            cpp!([array as "std::array<A, 3>&"] -> i32 as "int" {
+                int sum = 0;
+                for (const A &a : array)
+                    sum += a.multiply();
+                return sum;
+            })
+        };
+        assert_eq!(sum, 2 + 12 + 30);
+
+        let slice = &array[1..];
+        let ptr = slice.as_ptr();
+        let len = slice.len();
+        let sum = unsafe {
+            cpp!([ptr as "const A*", len as "size_t"] -> i32 as "int" {
+                int sum = 0;
+                for (size_t i = 0; i < len; ++i)
+                    sum += ptr[i].multiply();
+                return sum;
+            })
+        };
+        assert_eq!(sum, 12 + 30);
+
+        // Nothing was copied or destroyed by the C++ code
+        assert_eq!(live_count(), before + 3);
+    }
+    assert_eq!(live_count(), before);
+}
+
+#[test]
+fn no_drop() {
+    cpp_class!(#[rustcpp::no_drop] unsafe struct ManualA as "A");
+    assert!(!std::mem::needs_drop::<ManualA>());
+    assert!(std::mem::needs_drop::<A>());
+
+    fn live_count() -> i32 {
+        unsafe { cpp!([] -> i32 as "int" { return thread_counter(); }) }
+    }
+
+    let before = live_count();
+    let mut a = unsafe { cpp!([] -> ManualA as "A" { return A(1, 2); }) };
+    let mut a2 = a.clone();
+    assert_eq!(live_count(), before + 2);
+    let destroy = |a: &mut ManualA| {
+        let ptr = a as *mut ManualA;
+        unsafe { cpp!([ptr as "A*"] { ptr->~A(); }) };
+    };
+    destroy(&mut a2);
+    assert_eq!(live_count(), before + 1);
+    // The destructor must be called explicitly
+    destroy(&mut a);
+    assert_eq!(live_count(), before);
+}
+
+#[test]
+fn from_constructor() {
+    cpp_class!(
+        #[rustcpp::from("const char*", "size_t")]
+        #[rustcpp::from("size_t")]
+        unsafe struct HeapString as "HeapString"
+    );
+    impl HeapString {
+        fn contents(&self) -> String {
+            let mut len = 0usize;
+            let data = unsafe {
+                cpp!([self as "const HeapString*", mut len as "size_t"] -> *const u8 as "const char*" {
+                    len = self->get().size();
+                    return self->get().data();
+                })
+            };
+            String::from_utf8(unsafe { std::slice::from_raw_parts(data, len) }.to_vec()).unwrap()
+        }
+    }
+
+    let s = HeapString::from("héllo wörld");
+    assert_eq!(s.contents(), "héllo wörld");
+    assert_eq!(s.clone().contents(), "héllo wörld");
+    let s: HeapString = 3usize.into();
+    assert_eq!(s.contents(), "***");
+}
+
+#[test]
+fn field_accessors() {
+    cpp_class!(unsafe struct BFields as "B" { a: i32 as "int", pub b: i32 as "int" });
+    let mut b = BFields::default();
+    assert_eq!((b.a(), b.b()), (0, 0));
+    b.set_a(12);
+    b.set_b(34);
+    assert_eq!((b.a(), b.b()), (12, 34));
+    let b2 = b.clone();
+    assert_eq!(b2.a(), 12);
+
+    cpp_class!(unsafe struct Holder as "MoveOnly" { data: A as "A" });
+    let mut h = Holder::default();
+    assert_eq!(h.data().multiply(), 8 * 9);
+    h.set_data(A::new(3, 4));
+    let data = h.data();
+    assert_eq!(data.multiply(), 3 * 4);
+    assert_eq!(h.data().multiply(), data.multiply());
+}
@@ -25,14 +25,25 @@ inline counter_t &counter() {
     return counter;
 }
 
+// Same as counter, but only for the current thread, so that a test is not
+// affected by the other tests running in parallel
+inline int &thread_counter() {
+#if __cplusplus > 199711L
+    static thread_local int counter;
+#else
+    static int counter;
+#endif
+    return counter;
+}
+
 // class with destructor and copy constructor
 class A {
 public:
   int a;
   int b;
-  A(int a, int b) : a(a), b(b) { counter()++; }
-  A(const A &cpy) : a(cpy.a), b(cpy.b) { counter()++; }
-  ~A() { counter()--; }
+  A(int a, int b) : a(a), b(b) { counter()++; thread_counter()++; }
+  A(const A &cpy) : a(cpy.a), b(cpy.b) { counter()++; thread_counter()++; }
+  ~A() { counter()--; thread_counter()--; }
 #if !defined (_MSC_VER) || (_MSC_VER + 0 >= 1900)
   A &operator=(const A&) = default;
 #endif
@@ -57,5 +68,18 @@ struct MoveOnly {
     A data;
 };
 
+#include <string>
+
+// std::string is not relocatable with libstdc++, so it is kept behind a pointer
+class HeapString {
+    std::string *str;
+public:
+    HeapString(const char *data, size_t len) : str(new std::string(data, len)) {}
+    explicit HeapString(size_t count) : str(new std::string(count, '*')) {}
+    HeapString(const HeapString &other) : str(new std::string(*other.str)) {}
+    HeapString &operator=(const HeapString &other) { *str = *other.str; return *this; }
+    ~HeapString() { delete str; }
+    const std::string &get() const { return *str; }
+};
 
 #endif // defined(header_h__)
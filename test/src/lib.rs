@@ -1,7 +1,14 @@
 #![recursion_limit = "512"]
-#![cfg_attr(not(test), allow(dead_code, unused_imports, clippy::needless_return))]
+#![cfg_attr(not(test), allow(dead_code, unused_imports))]
+// The tests deliberately exercise these patterns
+#![allow(
+    clippy::needless_return,
+    clippy::bool_assert_comparison,
+    clippy::nonminimal_bool,
+    clippy::clone_on_copy
+)]
 
-use cpp::{cpp, cpp_class};
+use cpp::{cpp, cpp_class, cpp_out};
 
 #[cfg(test)]
 mod inner;
@@ -23,6 +30,37 @@ mod non_existent;
 #[cfg(feature = "non_existent")]
 mod invalid_code;
 
+// Neither the C++ code of this inline module nor its rust! callback are compiled
+#[cfg(feature = "non_existent")]
+mod disabled_inline {
+    cpp! {{
+        this is not a valid cpp code!
+    }}
+
+    pub fn callback() -> u32 {
+        unsafe {
+            cpp!([] -> u32 as "uint32_t" {
+                return rust!(Disabled_callback [] -> u32 as "uint32_t" {
+                    does_not_exist()
+                });
+            })
+        }
+    }
+}
+
+// This module is built into a separate library by build.rs. It is included with
+// `include!` so that it is not parsed as part of the main library.
+#[cfg(test)]
+mod second_library {
+    include!("second_library.rs");
+}
+
+// Built with C++20 by build.rs, if the compiler has the <span> header
+#[cfg(all(test, rust_cpp_span))]
+mod span_library {
+    include!("span_library.rs");
+}
+
 fn add_two(x: i32) -> i32 {
     x + 2
 }
@@ -34,10 +72,19 @@ cpp! {{
     #include <math.h>
     #include "src/header.h"
     #include <map>
+    #include <array>
+    #include <chrono>
+    #include <functional>
+    #include <string>
     #include <iostream>
 
+    // Defined in the second library
+    extern "C" rustcpp_import int rust_cpp_test_imported(int);
+
     int global_int;
 
+    struct TuplePair { int32_t a; double b; };
+
     int callRust1(int x)  {
         return rust!(addTwoCallback [x : i32 as "int"] -> i32 as "int" { add_two(x) });
     }
@@ -184,9 +231,390 @@ fn duplicates() {
     // Since both the cpp! inside fn1 and fn2 are made of the same token, the same
     // function is actually generated, meaning they share the same static variable.
     // This might be confusing, I hope nobody relies on this behavior.
+    // (cpp_build::Config::warn_duplicate_closures can be used to get a warning)
     assert_eq!(fn2(1), 11);
 }
 
+const RUST_CONST_BASE: i32 = 3;
+const RUST_CONST_SIZE: usize = (RUST_CONST_BASE as usize + 1) * 4;
+
+#[test]
+fn rust_const() {
+    let len = unsafe {
+        cpp!([] -> usize as "size_t" {
+            int buffer[rust_const!(RUST_CONST_SIZE)];
+            return sizeof(buffer) / sizeof(int);
+        })
+    };
+    assert_eq!(len, RUST_CONST_SIZE);
+    let negative = unsafe { cpp!([] -> i32 as "int" { return -rust_const!(RUST_CONST_BASE); }) };
+    assert_eq!(negative, -RUST_CONST_BASE);
+}
+
+#[test]
+fn const_capture() {
+    let x: i32 = 2;
+    let len = unsafe {
+        cpp!([const RUST_CONST_SIZE as "size_t", x as "int"] -> usize as "size_t" {
+            static_assert(RUST_CONST_SIZE == 16, "known at compile time");
+            int buffer[RUST_CONST_SIZE];
+            return sizeof(buffer) / sizeof(int) + x;
+        })
+    };
+    assert_eq!(len, RUST_CONST_SIZE + 2);
+}
+
+#[test]
+fn imported_symbol() {
+    let r = unsafe { cpp!([] -> i32 as "int" { return rust_cpp_test_imported(40); }) };
+    assert_eq!(r, 42);
+}
+
+extern "C" {
+    // Defined in a library built from cpp!{{ }} snippets only, see build.rs
+    fn rust_cpp_snippets_only() -> i32;
+}
+
+#[test]
+fn snippets_only_library() {
+    assert_eq!(unsafe { rust_cpp_snippets_only() }, 17);
+}
+
+#[test]
+fn probe_type() {
+    let size = unsafe { cpp!([] -> usize as "size_t" { return sizeof(std::string); }) };
+    let align = unsafe { cpp!([] -> usize as "size_t" { return alignof(std::string); }) };
+    assert_eq!(env!("RUST_CPP_TEST_PROBED_STRING"), format!("{},{},true", size, align));
+}
+
+#[test]
+fn pointer_width_integers() {
+    let len: usize = 42;
+    let diff: isize = -4;
+    let r = unsafe {
+        cpp!([len as "size_t", diff as "ptrdiff_t"] -> usize as "uintptr_t" {
+            return len + diff;
+        })
+    };
+    assert_eq!(r, 38);
+}
+
+#[test]
+fn char_capture() {
+    let mut c = 'a';
+    let next = unsafe {
+        cpp!([mut c as "char32_t"] -> char as "char32_t" {
+            c = 0xe9;
+            return c + 1;
+        })
+    };
+    assert_eq!(c, '\u{e9}');
+    assert_eq!(next, '\u{ea}');
+
+    // A surrogate is not a valid char
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| unsafe {
+        cpp!([mut c as "char32_t"] { c = 0xd800; })
+    }));
+    assert!(result.is_err());
+    assert_eq!(c, char::REPLACEMENT_CHARACTER);
+}
+
+#[repr(C)]
+struct TuplePair(i32, f64);
+
+#[test]
+fn tuple_struct() {
+    let t = TuplePair(3, 0.5);
+    let r = unsafe {
+        cpp!([t as "TuplePair"] -> f64 as "double" {
+            return t.a * t.b;
+        })
+    };
+    assert_eq!(r, 1.5);
+}
+
+#[test]
+fn reference_return() {
+    let mut x: i32 = 21;
+    let ptr = &mut x as *mut i32;
+    let r = unsafe { cpp!([ptr as "int*"] -> &mut i32 as "int*" { return ptr; }) };
+    *r *= 2;
+    assert_eq!(x, 42);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "the C++ code returned a null pointer for a reference")]
+fn null_reference_return() {
+    let _r = unsafe { cpp!([] -> &'static i32 as "const int*" { return nullptr; }) };
+}
+
+#[test]
+fn build_info() {
+    let info = cpp::build_info!();
+    assert!(!info.compiler.is_empty());
+    assert!(!info.version.is_empty());
+    let second = cpp::build_info!("rust_cpp_second");
+    assert!(second.flags.contains(&"-DSECOND_LIBRARY=2"), "{:?}", second.flags);
+    assert!(!info.flags.contains(&"-DSECOND_LIBRARY=2"));
+}
+
+#[test]
+fn noexcept_closure() {
+    let x: i32 = 20;
+    let r = unsafe { cpp!([x as "int"] -> i32 as "int" noexcept { return x + 1; }) };
+    assert_eq!(r, 21);
+
+    let mut y: i32 = 0;
+    unsafe { cpp!([mut y as "int"] noexcept { y = 3; }) };
+    assert_eq!(y, 3);
+}
+
+/// Runs the test `name` again in a child process, with the `RUST_CPP_TEST_CHILD`
+/// environment variable set, and checks that the child aborts
+fn assert_child_aborts(name: &str) {
+    let output = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", name, "--test-threads=1"])
+        .env("RUST_CPP_TEST_CHILD", "1")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        assert_eq!(output.status.signal(), Some(6), "not aborted: {:?}", output);
+    }
+}
+
+#[test]
+fn exception_aborts() {
+    if std::env::var_os("RUST_CPP_TEST_CHILD").is_some() {
+        unsafe { cpp!([] { throw 42; }) };
+        return;
+    }
+    assert_child_aborts("exception_aborts");
+}
+
+#[test]
+fn slice_capture() {
+    let mut v = vec![1, 2, 3, 4];
+    let slice = cpp::Slice::from(&mut v[1..]);
+    let sum = unsafe {
+        cpp!([slice as "rustcpp::Slice<int32_t>"] -> i32 as "int32_t" {
+            int32_t sum = 0;
+            for (int32_t &x : slice) {
+                x *= 2;
+                sum += x;
+            }
+            return sum + slice.at(2) * (int32_t)slice.size();
+        })
+    };
+    assert_eq!(sum, 18 + 8 * 3);
+    assert_eq!(v, [1, 4, 6, 8]);
+
+    let slice = cpp::Slice::from(&v[..2]);
+    let last = unsafe {
+        cpp!([slice as "rustcpp::Slice<const int32_t>"] -> i32 as "int32_t" {
+            return slice[slice.size() - 1];
+        })
+    };
+    assert_eq!(last, 4);
+}
+
+#[test]
+fn str_view_return() {
+    let buffer = b"hello world".to_vec();
+    let (p, len) = (buffer.as_ptr(), buffer.len());
+    let word = unsafe {
+        cpp!([p as "const char*", len as "size_t"] -> &[u8] as "rustcpp::StrView" {
+            return rustcpp::StrView(p + 6, len - 6);
+        })
+    };
+    assert_eq!(word, b"world");
+    assert_eq!(word.as_ptr(), buffer[6..].as_ptr());
+
+    let view = unsafe {
+        cpp!([] -> cpp::StrView as "rustcpp::StrView" {
+            static const std::string s("from a string");
+            return s;
+        })
+    };
+    assert_eq!(view.len(), 13);
+    assert_eq!(unsafe { view.as_bytes() }, b"from a string");
+}
+
+#[test]
+fn duration_round_trip() {
+    use std::time::Duration;
+    let timeout = cpp::Duration::from(Duration::new(5, 250_000_999));
+    let doubled = unsafe {
+        cpp!([timeout as "rustcpp::Duration"] -> Duration as "rustcpp::Duration" {
+            std::chrono::milliseconds ms = timeout;
+            return ms * 2;
+        })
+    };
+    assert_eq!(doubled, Duration::from_millis(10_500));
+    let nanos = unsafe {
+        cpp!([] -> cpp::Duration as "rustcpp::Duration" {
+            return std::chrono::nanoseconds(1500000001);
+        })
+    };
+    assert_eq!(nanos.to_duration(), Some(Duration::new(1, 500_000_001)));
+
+    // Periods finer than a nanosecond don't overflow, and saturate when the count doesn't fit
+    let picos = unsafe {
+        cpp!([] -> cpp::Duration as "rustcpp::Duration" {
+            return std::chrono::duration<int64_t, std::pico>(9000000000001001);
+        })
+    };
+    assert_eq!(picos.to_duration(), Some(Duration::new(9000, 1)));
+    let to_picos = |d: Duration| {
+        let d = cpp::Duration::from(d);
+        unsafe {
+            cpp!([d as "rustcpp::Duration"] -> i64 as "int64_t" {
+                return std::chrono::duration<int64_t, std::pico>(d).count();
+            })
+        }
+    };
+    assert_eq!(to_picos(Duration::new(3, 7)), 3_000_000_007_000);
+    assert_eq!(to_picos(Duration::from_secs(200 * 24 * 3600)), i64::MAX);
+
+    let negative = unsafe {
+        cpp!([] -> cpp::Duration as "rustcpp::Duration" { return std::chrono::seconds(-3); })
+    };
+    assert_eq!(negative.to_duration(), None);
+}
+
+/// A bfloat16 type of another crate, with the same representation as cpp::Bf16
+#[repr(transparent)]
+#[derive(Clone, Copy)]
+struct OtherBf16(u16);
+
+#[test]
+fn bf16_capture() {
+    let weights = [cpp::Bf16::from(0.5), cpp::Bf16::from(-1.25)];
+    let scale = OtherBf16(cpp::Bf16::from(3.0).to_bits());
+    let sum = unsafe {
+        cpp!([weights as "std::array<rustcpp::Bf16, 2>", scale as "rustcpp::Bf16"]
+                -> cpp::Bf16 as "rustcpp::Bf16" {
+            return (weights[0] + weights[1]) * scale;
+        })
+    };
+    assert_eq!(f32::from(sum), -2.25);
+
+    // The rounding matches between C++ and Rust, ties to even
+    for f in [1.0 + 1.0 / 256.0, 1.0 + 3.0 / 256.0, 1.0e-40, f32::INFINITY, f32::MAX] {
+        let rounded = unsafe { cpp!([f as "float"] -> cpp::Bf16 as "rustcpp::Bf16" { return f; }) };
+        assert_eq!(rounded, cpp::Bf16::from(f), "{}", f);
+    }
+    assert_eq!(cpp::Bf16::from(1.0 + 1.0 / 256.0).to_f32(), 1.0);
+    assert_eq!(cpp::Bf16::from(1.0 + 3.0 / 256.0).to_f32(), 1.0 + 4.0 / 256.0);
+    let nan = unsafe { cpp!([] -> cpp::Bf16 as "rustcpp::Bf16" { return 0.0f / 0.0f; }) };
+    assert!(nan.to_f32().is_nan());
+
+    // The compiler's own type, where there is one
+    let half = unsafe {
+        cpp!([] -> f32 as "float" {
+        #ifdef __BFLT16_MANT_DIG__
+            __bf16 b = rustcpp::Bf16(0.5f);
+            return rustcpp::Bf16(b);
+        #else
+            return 0.5f;
+        #endif
+        })
+    };
+    assert_eq!(half, 0.5);
+}
+
+#[test]
+fn maybe_uninit_capture() {
+    let mut buffer = std::mem::MaybeUninit::<[u8; 16]>::uninit();
+    let out = &mut buffer;
+    unsafe {
+        cpp!([out as "uint8_t*"] {
+            for (int i = 0; i < 16; ++i)
+                out[i] = i * 2;
+        })
+    };
+    let buffer = unsafe { buffer.assume_init() };
+    assert_eq!(buffer[15], 30);
+    assert_eq!(buffer.iter().map(|&x| u32::from(x)).sum::<u32>(), 240);
+
+    // A MaybeUninit<T> has the layout of T, so it can also be captured as a T
+    let mut value = std::mem::MaybeUninit::<i32>::uninit();
+    unsafe { cpp!([mut value as "int32_t"] { value = 42; }) };
+    assert_eq!(unsafe { value.assume_init() }, 42);
+}
+
+#[test]
+fn element_pointer_capture() {
+    #[repr(C)]
+    struct Data {
+        matrix: [f32; 4],
+    }
+    let mut data = Data { matrix: [1.0, 2.0, 3.0, 4.0] };
+    let element = &mut data.matrix[2];
+    let old = unsafe {
+        cpp!([element as "float*"] -> f32 as "float" {
+            float old = *element;
+            *element = old * 10;
+            return old;
+        })
+    };
+    assert_eq!(old, 3.0);
+    assert_eq!(data.matrix, [1.0, 2.0, 30.0, 4.0]);
+}
+
+static STATIC_DATA: [u8; 4096] = [7; 4096];
+
+#[test]
+fn static_capture() {
+    // A static is passed by reference: C++ sees its memory, not a copy
+    let address = unsafe {
+        cpp!([STATIC_DATA as "std::array<uint8_t, 4096>"] -> usize as "uintptr_t" {
+            return (uintptr_t)STATIC_DATA.data();
+        })
+    };
+    assert_eq!(address, STATIC_DATA.as_ptr() as usize);
+
+    // A &'static reference is captured as a pointer to the static
+    let data: &'static [u8; 4096] = &STATIC_DATA;
+    let (address, last) = unsafe {
+        (
+            cpp!([data as "const uint8_t*"] -> usize as "uintptr_t" { return (uintptr_t)data; }),
+            cpp!([data as "const uint8_t*"] -> u8 as "uint8_t" { return data[4095]; }),
+        )
+    };
+    assert_eq!(address, STATIC_DATA.as_ptr() as usize);
+    assert_eq!(last, 7);
+}
+
+#[test]
+fn slice_out_of_bounds() {
+    if std::env::var_os("RUST_CPP_TEST_CHILD").is_some() {
+        let v = [1, 2, 3];
+        let slice = cpp::Slice::from(&v[..]);
+        unsafe {
+            cpp!([slice as "rustcpp::Slice<const int32_t>"] -> i32 as "int32_t" { return slice.at(3); })
+        };
+        return;
+    }
+    assert_child_aborts("slice_out_of_bounds");
+}
+
+#[test]
+fn closure_include() {
+    let x: f64 = 2.25;
+    let r = unsafe {
+        cpp!(#[cpp_include("<cmath>")] [x as "double"] -> f64 as "double" {
+            return std::sqrt(x);
+        })
+    };
+    assert_eq!(r, 1.5);
+    let r = cpp!(unsafe #[cpp_include("<climits>")] [] -> i32 as "int" { return INT_MAX; });
+    assert_eq!(r, i32::MAX);
+}
+
 #[test]
 fn test_inner() {
     let x = inner::inner();
@@ -319,6 +747,43 @@ fn rust_submacro_trait() {
     assert_eq!(i, 123 + 333);
 }
 
+pub trait Scaled {
+    fn value(&self) -> i32;
+    // cpp! in a default method is expanded once, for all the implementations
+    fn scaled(&self, factor: i32) -> i32 {
+        let value = self.value();
+        unsafe {
+            cpp!([value as "int", factor as "int"] -> i32 as "int" {
+                return value * rust!(ScaledOffset [factor : i32 as "int"] -> i32 as "int" {
+                    factor + 1
+                });
+            })
+        }
+    }
+}
+
+struct ScaledInt(i32);
+impl Scaled for ScaledInt {
+    fn value(&self) -> i32 {
+        self.0
+    }
+}
+
+struct ScaledPair(i32, i32);
+impl Scaled for ScaledPair {
+    fn value(&self) -> i32 {
+        self.0 + self.1
+    }
+}
+
+#[test]
+fn trait_default_method() {
+    assert_eq!(ScaledInt(3).scaled(4), 15);
+    assert_eq!(ScaledPair(2, 5).scaled(1), 14);
+    let dynamic: &dyn Scaled = &ScaledInt(-2);
+    assert_eq!(dynamic.scaled(2), -6);
+}
+
 #[test]
 fn witin_macro() {
     assert_eq!(unsafe { cpp!([] -> u32 as "int" { return 12; }) }, 12);
@@ -352,4 +817,327 @@ fn rust_submacro_closure() {
     assert_eq!(result, 18);
 }
 
+cpp! {{
+    // The reference owned by the C++ code, see arc_capture
+    static const void *stored_arc = nullptr;
+    static uint32_t release_stored_arc() {
+        const void *ptr = stored_arc;
+        stored_arc = nullptr;
+        return rust!(releaseArcCallback [ptr : *const u32 as "const void*"] -> u32 as "uint32_t" {
+            let arc = unsafe { std::sync::Arc::from_raw(ptr) };
+            *arc
+        });
+    }
+}}
+
+#[test]
+fn arc_capture() {
+    let shared = std::sync::Arc::new(42u32);
+    unsafe { cpp!([arc shared as "const void*"] { stored_arc = shared; }) };
+    assert_eq!(std::sync::Arc::strong_count(&shared), 2);
+    assert_eq!(unsafe { cpp!([] -> u32 as "uint32_t" { return release_stored_arc(); }) }, 42);
+    assert_eq!(std::sync::Arc::strong_count(&shared), 1);
+
+    // A variable called `arc` is captured as usual
+    let arc = 5;
+    assert_eq!(unsafe { cpp!([arc as "int"] -> i32 as "int" { return arc; }) }, 5);
+}
+
+cpp! {{
+    static int32_t *stored_box = nullptr;
+}}
+
+#[test]
+fn move_box_capture() {
+    let boxed = Some(Box::new(41i32));
+    unsafe { cpp!([move boxed as "int32_t*"] { stored_box = boxed; *stored_box += 1; }) };
+    let back = unsafe {
+        cpp!([] -> Option<Box<i32>> as "int32_t*" {
+            int32_t *b = stored_box;
+            stored_box = nullptr;
+            return b;
+        })
+    };
+    assert_eq!(back, Some(Box::new(42)));
+
+    // None is a null pointer
+    let none: Option<Box<i32>> = None;
+    assert!(unsafe { cpp!([move none as "int32_t*"] -> bool as "bool" { return !none; }) });
+}
+
+#[test]
+fn closure_capture() {
+    let mut calls = Vec::new();
+    let mut record = |a: i32, b: i32| -> i32 {
+        calls.push((a, b));
+        a * b
+    };
+    let f: &mut dyn FnMut(i32, i32) -> i32 = &mut record;
+    let sum = unsafe {
+        cpp!([f as "rustcpp::FnMut<int32_t(int32_t, int32_t)>"] -> i32 as "int32_t" {
+            int32_t sum = f(2, 3) + f(4, 5);
+            std::function<int32_t(int32_t, int32_t)> function = f;
+            return sum + function(6, 7);
+        })
+    };
+    assert_eq!(sum, 6 + 20 + 42);
+    assert_eq!(calls, [(2, 3), (4, 5), (6, 7)]);
+
+    let mut count = 0;
+    let increment: &mut dyn FnMut() = &mut || count += 1;
+    unsafe { cpp!([increment as "rustcpp::FnMut<void()>"] { increment(); increment(); }) };
+    assert_eq!(count, 2);
+}
+
+#[test]
+fn pointer_capture() {
+    let mut values = [3u32, 4, 5];
+    let ptr: *mut u32 = values.as_mut_ptr();
+
+    // By reference, the C++ parameter is the Rust pointer variable itself
+    let first = unsafe { cpp!([ptr as "uint32_t*"] -> u32 as "uint32_t" { return *ptr; }) };
+    assert_eq!(first, 3);
+    let address = unsafe { cpp!([ptr as "void*"] -> *const u8 as "const void*" { return &ptr; }) };
+    assert_eq!(address, &ptr as *const *mut u32 as *const u8);
+
+    // By value, the C++ parameter is a copy of the pointer, which can be modified
+    let sum = unsafe {
+        cpp!([copy ptr as "uint32_t*"] -> u32 as "uint32_t" {
+            uint32_t sum = *ptr++;
+            sum += *ptr++;
+            return sum + *ptr;
+        })
+    };
+    assert_eq!(sum, 12);
+    assert_eq!(ptr, values.as_mut_ptr());
+
+    // A type-erased pointer
+    let erased = ptr as *mut ();
+    let first = unsafe {
+        cpp!([copy erased as "void*"] -> u32 as "uint32_t" {
+            return *static_cast<uint32_t*>(erased);
+        })
+    };
+    assert_eq!(first, 3);
+
+    // The C++ type gives the type of the pointee, without a cast in the body
+    let a = A::new(6, 7);
+    let erased = &a as *const A as *const ();
+    let product =
+        unsafe { cpp!([copy erased as "const A*"] -> i32 as "int" { return erased->multiply(); }) };
+    assert_eq!(product, 42);
+    let product =
+        unsafe { cpp!([erased as "const A*"] -> i32 as "int" { return erased->multiply(); }) };
+    assert_eq!(product, 42);
+
+    // A variable called `copy` is captured as usual
+    let copy = 7;
+    assert_eq!(unsafe { cpp!([copy as "int"] -> i32 as "int" { return copy; }) }, 7);
+}
+
+/// Used through an out-parameter of a rust! callback, see rust_callback_out_params
+fn divide(n: i32, d: i32, quotient: &mut i32, remainder: &mut i32) -> bool {
+    if d == 0 {
+        return false;
+    }
+    *quotient = n / d;
+    *remainder = n % d;
+    true
+}
+
+#[test]
+fn rust_callback_out_params() {
+    let (mut quotient, mut remainder, mut a) = (0, 0, A::new(0, 0));
+    let ok = unsafe {
+        cpp!([mut quotient as "int", mut remainder as "int", mut a as "A"] -> bool as "bool" {
+            return rust!(divideCallback [quotient : &mut i32 as "int&",
+                                         remainder : &mut i32 as "int&",
+                                         a : &mut A as "A&"] -> bool as "bool" {
+                *a = A::new(6, 7);
+                divide(17, 5, quotient, remainder)
+            });
+        })
+    };
+    assert!(ok);
+    assert_eq!((quotient, remainder, a.multiply()), (3, 2, 42));
+}
+
+#[test]
+fn rust_callback_return_reference() {
+    let third = unsafe {
+        cpp!([] -> i32 as "int" {
+            int values[4] = {1, 2, 3, 4};
+            const int *third = rust!(pickThird [values : &[i32; 4] as "const int*"]
+                                     -> &i32 as "const int*" {
+                &values[2]
+            });
+            int *second = rust!(pickSecond [values : &mut [i32; 4] as "int*"]
+                                -> &mut i32 as "int*" {
+                &mut values[1]
+            });
+            *second = 20;
+            if (third != &values[2] || values[1] != 20)
+                return -1;
+            return *third;
+        })
+    };
+    assert_eq!(third, 3);
+}
+
+cpp! {{
+    #include <cwchar>
+}}
+
+#[test]
+#[cfg(windows)]
+fn utf16_capture() {
+    let s: Vec<u16> = "h\u{e9}llo\0".encode_utf16().collect();
+    let p = s.as_ptr();
+    let len = unsafe { cpp!([p as "const wchar_t*"] -> usize as "size_t" { return wcslen(p); }) };
+    assert_eq!(len, 5);
+}
+
+#[test]
+#[cfg(not(windows))]
+fn utf32_capture() {
+    let s: Vec<char> = "h\u{e9}llo\0".chars().collect();
+    let p = s.as_ptr();
+    let len = unsafe { cpp!([p as "const wchar_t*"] -> usize as "size_t" { return wcslen(p); }) };
+    assert_eq!(len, 5);
+
+    // wchar_t has 32 bits: a UTF-16 string is rejected before the call
+    let s: Vec<u16> = "h\u{e9}llo\0".encode_utf16().collect();
+    let p16 = s.as_ptr();
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| unsafe {
+        cpp!([p16 as "const wchar_t*"] -> usize as "size_t" { return wcslen(p16); })
+    }));
+    assert!(result.is_err());
+}
+
+cpp! {{
+    enum class CheckedColor : uint8_t { Red = 1, Green = 2, Blue = 4 };
+}}
+
+#[derive(Debug, PartialEq, cpp::CppEnum)]
+#[repr(u8)]
+enum CheckedColor {
+    Red = 1,
+    Green = 2,
+    Blue = 4,
+}
+
+#[derive(Debug, PartialEq, cpp::CppEnum)]
+#[repr(C)]
+enum CheckedLevel {
+    Low,
+    High,
+    #[cpp_enum(fallback)]
+    Unknown = 99,
+}
+
+#[test]
+fn checked_enum_return() {
+    let color =
+        unsafe { cpp!([] -> CheckedColor as "CheckedColor" { return CheckedColor::Blue; }) };
+    assert_eq!(color, CheckedColor::Blue);
+    let level = unsafe { cpp!([] -> CheckedLevel as "int" { return 1; }) };
+    assert_eq!(level, CheckedLevel::High);
+
+    // An unknown value gives the fallback variant, or panics if there is none
+    let level = unsafe { cpp!([] -> CheckedLevel as "int" { return 7; }) };
+    assert_eq!(level, CheckedLevel::Unknown);
+    let result = std::panic::catch_unwind(|| unsafe {
+        cpp!([] -> CheckedColor as "CheckedColor" { return static_cast<CheckedColor>(3); })
+    });
+    assert!(result.is_err());
+    let _ = (CheckedColor::Red, CheckedColor::Green, CheckedLevel::Low);
+}
+
+#[test]
+fn out_captures() {
+    let data = [7i32, -2, 11, 4];
+    let values = cpp::Slice::from(&data[..]);
+    let (min, max) = unsafe {
+        cpp_out!(min: i32, max: i32 => cpp!([values as "rustcpp::Slice<const int32_t>",
+                                             mut min as "int32_t", mut max as "int32_t"] {
+            min = max = values[0];
+            for (int32_t v : values) {
+                if (v < min) min = v;
+                if (v > max) max = v;
+            }
+        }))
+    };
+    assert_eq!((min, max), (-2, 11));
+}
+
+#[test]
+fn vec_push() {
+    let mut values = vec![1i32];
+    {
+        let mut vec = cpp::VecMut::from(&mut values);
+        unsafe {
+            cpp!([mut vec as "rustcpp::Vec<int32_t>"] {
+                vec.reserve(2);
+                for (int32_t i = 2; i <= 100; ++i)
+                    vec.push_back(i);
+                vec[0] = -1;
+            })
+        };
+    }
+    let mut expected = (1..=100).collect::<Vec<i32>>();
+    expected[0] = -1;
+    assert_eq!(values, expected);
+}
+
+#[test]
+fn mut_buf() {
+    let mut buf = [0xffu8; 8];
+    let buf_len = &mut buf[..5];
+    let written = unsafe {
+        cpp!([buf_len as "rustcpp::MutBuf"] -> usize as "size_t" {
+            const char message[] = "hello world";
+            return buf_len.copy_from(message, sizeof(message) - 1);
+        })
+    };
+    assert_eq!(written, 5);
+    assert_eq!(&buf, b"hello\xff\xff\xff");
+
+    let mut bytes = vec![0u8; 4];
+    let len = unsafe {
+        cpp!([bytes as "rustcpp::MutBuf"] -> usize as "size_t" {
+            for (uint8_t &b : bytes)
+                b = 7;
+            return bytes.len;
+        })
+    };
+    assert_eq!(len, 4);
+    assert_eq!(bytes, [7; 4]);
+}
+
+#[test]
+fn enum_capture() {
+    // The underlying type of the C++ enum has the size of the #[repr] of the Rust enum
+    let color = CheckedColor::Green;
+    let bits = unsafe {
+        cpp!([color as "CheckedColor"] -> u8 as "uint8_t" { return static_cast<uint8_t>(color); })
+    };
+    assert_eq!(bits, 2);
+}
+
+// Only run on MSVC, which needs Config::exception_model, but compiled everywhere by cpp_build
+#[test]
+#[cfg(target_env = "msvc")]
+fn exception_in_closure() {
+    let caught = unsafe {
+        cpp!([] -> i32 as "int" {
+            try {
+                throw 42;
+            } catch (int e) {
+                return e;
+            }
+        })
+    };
+    assert_eq!(caught, 42);
+}
+
 pub mod cpp_class;
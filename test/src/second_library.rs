@@ -0,0 +1,119 @@
+// This file is built into its own library by build.rs, with different flags.
+use cpp::cpp;
+
+cpp! {{
+    #include <optional>
+    #include "second_library.h"
+    #include "rust_cpp_generated_config.h"
+    #ifndef _MSC_VER
+    #include <compiler_env.h>
+    #endif
+
+    // Visible unqualified in the closures, see the `closure_prelude` test
+    namespace second_prelude {
+        inline int prelude_function() { return 13; }
+    }
+
+    #ifdef _MSC_VER
+    // From version.lib, see Config::link_lib in build.rs
+    extern "C" __declspec(dllimport) unsigned long __stdcall
+    GetFileVersionInfoSizeW(const wchar_t *filename, unsigned long *handle);
+    #endif
+    inline uint32_t kernel32_version_info_size() {
+    #ifdef _MSC_VER
+        return GetFileVersionInfoSizeW(L"kernel32.dll", nullptr);
+    #else
+        return 0;
+    #endif
+    }
+
+    // Called from the main library, see the `imported_symbol` test
+    extern "C" int rust_cpp_test_imported(int x) {
+        return x + SECOND_LIBRARY;
+    }
+}}
+
+#[test]
+fn second_library() {
+    let x: i32 = 5;
+    let r = unsafe {
+        cpp!([x as "int"] -> i32 as "int" {
+            return rust!(secondLibraryCallback [x : i32 as "int"] -> i32 as "int" { x * 3 })
+                + SECOND_LIBRARY;
+        })
+    };
+    assert_eq!(r, 5 * 3 + 2);
+}
+
+#[test]
+fn function_like_define() {
+    let r = unsafe { cpp!([] -> i32 as "int" { return SECOND_LIBRARY_SCALE(4, 1); }) };
+    assert_eq!(r, 4 * 2 + 1);
+}
+
+#[test]
+#[cfg(not(target_env = "msvc"))]
+fn compiler_env() {
+    let r = unsafe { cpp!([] -> i32 as "int" { return compiler_env_header(); }) };
+    assert_eq!(r, 23);
+}
+
+#[test]
+fn include_from_manifest() {
+    let r = unsafe { cpp!([] -> i32 as "int" { return second_library_header(); }) };
+    assert_eq!(r, 11);
+}
+
+#[test]
+fn snippet_file() {
+    let r = unsafe { cpp!([] -> i32 as "int" { return snippet_file_function(); }) };
+    assert_eq!(r, 19);
+}
+
+#[test]
+fn prepended_snippet() {
+    let r = unsafe { cpp!([] -> i32 as "int" { return PREPENDED_SNIPPET; }) };
+    assert_eq!(r, 7);
+}
+
+extern "C" {
+    // Defined by the code appended with Config::append_cpp, see build.rs
+    fn rust_cpp_test_appended(x: i32) -> i32;
+}
+
+#[test]
+fn appended_cpp() {
+    assert_eq!(unsafe { rust_cpp_test_appended(2) }, 15);
+}
+
+#[test]
+fn closure_prelude() {
+    let r = unsafe { cpp!([] -> i32 as "int" { return prelude_function(); }) };
+    assert_eq!(r, 13);
+}
+
+#[test]
+fn optional_return() {
+    let find = |x: i32| unsafe {
+        cpp!([x as "int"] -> Option<i32> as "std::optional<int>" {
+            if (x < 0)
+                return std::nullopt;
+            return x * 2;
+        })
+    };
+    assert_eq!(find(21), Some(42));
+    assert_eq!(find(-1), None);
+}
+
+#[cfg(target_env = "msvc")]
+#[test]
+fn msvc_link_pragma() {
+    let size = unsafe { cpp!([] -> u32 as "uint32_t" { return kernel32_version_info_size(); }) };
+    assert!(size > 0);
+}
+
+#[test]
+fn generated_header() {
+    let r = unsafe { cpp!([] -> i32 as "int" { return GENERATED_VALUE; }) };
+    assert_eq!(r, 19);
+}
@@ -0,0 +1,32 @@
+// This file is built into its own library by build.rs, with C++20.
+use cpp::cpp;
+
+cpp! {{
+    #include <span>
+    #include <stdint.h>
+}}
+
+#[test]
+fn span_capture() {
+    let data = [1u8, 2, 3, 4, 250];
+    let slice = cpp::Slice::from(&data[..]);
+    let sum = unsafe {
+        cpp!([slice as "std::span<const uint8_t>"] -> u32 as "uint32_t" {
+            uint32_t sum = 0;
+            for (uint8_t x : slice)
+                sum += x;
+            return sum + slice.size();
+        })
+    };
+    assert_eq!(sum, 265);
+
+    let mut values = [1i32, 2, 3];
+    let slice = cpp::Slice::from(&mut values[1..]);
+    unsafe {
+        cpp!([slice as "std::span<int32_t>"] {
+            for (int32_t &x : slice)
+                x *= 10;
+        })
+    };
+    assert_eq!(values, [1, 20, 30]);
+}